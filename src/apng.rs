@@ -0,0 +1,505 @@
+use color::Color;
+use error::PngError;
+use helpers;
+use png::PngFile;
+
+pub type AnimationControlParseResult = Result<AnimationControl, String>;
+pub type FrameControlParseResult = Result<FrameControl, String>;
+
+/// The number of frames and plays of an animated PNG, from an
+/// acTL chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationControl {
+    pub num_frames: u32,
+    /// The number of times to loop the animation, where 0 loops forever.
+    pub num_plays: u32
+}
+
+/// What happens to a frame's region of the canvas once the frame
+/// has been shown.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisposeOp {
+    /// The region is left as it is.
+    None,
+    /// The region is cleared to transparent black.
+    Background,
+    /// The region is reverted to what it was before the frame.
+    Previous
+}
+
+/// How a frame's pixels are combined with the canvas.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlendOp {
+    /// The frame's pixels replace the canvas.
+    Source,
+    /// The frame's pixels are alpha composited over the canvas.
+    Over
+}
+
+/// The region, timing and compositing of a single frame, from an
+/// fcTL chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub width: usize,
+    pub height: usize,
+    pub x_offset: usize,
+    pub y_offset: usize,
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp
+}
+
+impl DisposeOp {
+    pub fn to_byte(&self) -> u8 {
+        match *self {
+            DisposeOp::None => 0,
+            DisposeOp::Background => 1,
+            DisposeOp::Previous => 2
+        }
+    }
+}
+
+impl BlendOp {
+    pub fn to_byte(&self) -> u8 {
+        match *self {
+            BlendOp::Source => 0,
+            BlendOp::Over => 1
+        }
+    }
+}
+
+/// A fully composited frame of an animated PNG.
+pub struct Frame {
+    pub pixels: Vec<Color>,
+    pub delay_num: u16,
+    pub delay_den: u16
+}
+
+impl Frame {
+    /// How long the frame is shown for, in seconds.
+    pub fn delay(&self) -> f64 {
+        delay(self.delay_num, self.delay_den)
+    }
+}
+
+/// An image to be written as a frame of an animated PNG, and how
+/// long it is shown for.
+pub struct AnimationFrame {
+    pub image: PngFile,
+    pub delay_num: u16,
+    pub delay_den: u16
+}
+
+impl AnimationFrame {
+    pub fn new(image: PngFile, delay_num: u16, delay_den: u16) -> Self {
+        AnimationFrame {
+            image: image,
+            delay_num: delay_num,
+            delay_den: delay_den
+        }
+    }
+}
+
+fn delay(num: u16, den: u16) -> f64 {
+    // A denominator of 0 means hundredths of a second
+    let den = iif!(den == 0, 100, den);
+    num as f64 / den as f64
+}
+
+/// The frame control and compressed image data of a frame,
+/// as collected while reading chunks.
+pub struct FrameData {
+    pub control: FrameControl,
+    pub chunks: Vec<Vec<u8>>
+}
+
+/// Draws a decoded frame region onto the canvas using the frame's
+/// blend op.
+pub fn blend(canvas: &mut [Color], canvas_width: usize, region: &[Color], control: &FrameControl) {
+    for y in 0..control.height {
+        let row = (control.y_offset + y) * canvas_width + control.x_offset;
+        for x in 0..control.width {
+            let source = &region[y * control.width + x];
+            let target = &mut canvas[row + x];
+
+            *target = match control.blend_op {
+                BlendOp::Source => source.clone(),
+                BlendOp::Over => over(source, target)
+            };
+        }
+    }
+}
+
+/// Composites a straight alpha color over another.
+fn over(source: &Color, target: &Color) -> Color {
+    if source.a == 255 || target.a == 0 {
+        return source.clone();
+    }
+
+    if source.a == 0 {
+        return target.clone();
+    }
+
+    // All values are scaled by 255 * 255 to stay in integers
+    let sa = source.a as u32;
+    let ta = target.a as u32 * (255 - sa);
+    let a = sa * 255 + ta;
+    let mix = |s: u8, t: u8| ((s as u32 * sa * 255 + t as u32 * ta + a / 2) / a) as u8;
+
+    Color::new(
+        mix(source.r, target.r),
+        mix(source.g, target.g),
+        mix(source.b, target.b),
+        ((a + 127) / 255) as u8
+    )
+}
+
+/// Copies the frame's region out of the canvas into `region`, so
+/// that it can be restored by `APNG_DISPOSE_OP_PREVIOUS`.
+pub fn save_region(canvas: &[Color], canvas_width: usize, control: &FrameControl, region: &mut Vec<Color>) {
+    region.clear();
+    for y in 0..control.height {
+        let row = (control.y_offset + y) * canvas_width + control.x_offset;
+        region.extend_from_slice(&canvas[row..row + control.width]);
+    }
+}
+
+/// Disposes of the frame's region of the canvas once it has been
+/// shown. `previous` is the region saved before the frame was drawn.
+pub fn dispose(canvas: &mut [Color], canvas_width: usize, control: &FrameControl, previous: Option<&[Color]>) {
+    for y in 0..control.height {
+        let row = (control.y_offset + y) * canvas_width + control.x_offset;
+        for x in 0..control.width {
+            match (&control.dispose_op, previous) {
+                (&DisposeOp::None, _) => (),
+                (&DisposeOp::Previous, Some(previous)) => {
+                    canvas[row + x] = previous[y * control.width + x].clone();
+                },
+                _ => canvas[row + x] = Color::new(0, 0, 0, 0)
+            }
+        }
+    }
+}
+
+impl AnimationControl {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(8);
+        helpers::write_unsigned_int(&mut result, self.num_frames);
+        helpers::write_unsigned_int(&mut result, self.num_plays);
+
+        result
+    }
+}
+
+impl FrameControl {
+    /// How long the frame is shown for, in seconds.
+    pub fn delay(&self) -> f64 {
+        delay(self.delay_num, self.delay_den)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(26);
+        helpers::write_unsigned_int(&mut result, self.sequence_number);
+        helpers::write_unsigned_int(&mut result, self.width as u32);
+        helpers::write_unsigned_int(&mut result, self.height as u32);
+        helpers::write_unsigned_int(&mut result, self.x_offset as u32);
+        helpers::write_unsigned_int(&mut result, self.y_offset as u32);
+        result.extend_from_slice(&[
+            (self.delay_num >> 8) as u8,
+            self.delay_num as u8,
+            (self.delay_den >> 8) as u8,
+            self.delay_den as u8,
+            self.dispose_op.to_byte(),
+            self.blend_op.to_byte()
+        ]);
+
+        result
+    }
+}
+
+pub fn parse_actl(data: &[u8]) -> AnimationControlParseResult {
+    if data.len() != 8 {
+        return Err("Invalid acTL length".to_string());
+    }
+
+    let control = AnimationControl {
+        num_frames: helpers::read_unsigned_int(&data[0x00..]),
+        num_plays: helpers::read_unsigned_int(&data[0x04..])
+    };
+
+    if control.num_frames == 0 {
+        return Err("acTL must have at least one frame".to_string());
+    }
+
+    Ok(control)
+}
+
+pub fn parse_fctl(data: &[u8], canvas_width: usize, canvas_height: usize) -> FrameControlParseResult {
+    if data.len() != 26 {
+        return Err("Invalid fcTL length".to_string());
+    }
+
+    let control = FrameControl {
+        sequence_number: helpers::read_unsigned_int(&data[0x00..]),
+        width: helpers::read_unsigned_int(&data[0x04..]) as usize,
+        height: helpers::read_unsigned_int(&data[0x08..]) as usize,
+        x_offset: helpers::read_unsigned_int(&data[0x0C..]) as usize,
+        y_offset: helpers::read_unsigned_int(&data[0x10..]) as usize,
+        delay_num: helpers::read_unsigned_short(&data[0x14..]),
+        delay_den: helpers::read_unsigned_short(&data[0x16..]),
+        dispose_op: match data[0x18] {
+            0 => DisposeOp::None,
+            1 => DisposeOp::Background,
+            2 => DisposeOp::Previous,
+            n => return Err(format!("Invalid fcTL dispose op: {}", n))
+        },
+        blend_op: match data[0x19] {
+            0 => BlendOp::Source,
+            1 => BlendOp::Over,
+            n => return Err(format!("Invalid fcTL blend op: {}", n))
+        }
+    };
+
+    if control.width == 0 || control.height == 0 {
+        return Err("fcTL frame must not be empty".to_string());
+    }
+
+    if control.x_offset + control.width > canvas_width ||
+       control.y_offset + control.height > canvas_height {
+        return Err("fcTL frame lies outside the image".to_string());
+    }
+
+    Ok(control)
+}
+
+/// A composited frame that borrows the canvas of a `Frames` iterator.
+pub struct FrameRef<'a> {
+    pub pixels: &'a [Color],
+    pub control: &'a FrameControl
+}
+
+impl<'a> FrameRef<'a> {
+    /// How long the frame is shown for, in seconds.
+    pub fn delay(&self) -> f64 {
+        self.control.delay()
+    }
+}
+
+/// Decodes the frames of an animated PNG one at a time, compositing
+/// each onto a single canvas that is reused for every frame. Only the
+/// image data of the current frame is decompressed, and the only other
+/// buffer kept is the region saved for `APNG_DISPOSE_OP_PREVIOUS`.
+///
+/// Frames borrow the canvas, so this can't be a standard `Iterator`.
+/// Loop over it with `while let Some(frame) = frames.next()`.
+pub struct Frames<'a> {
+    png: PngFile,
+    data: &'a [u8],
+    idx: usize,
+    index: usize,
+    canvas: Vec<Color>,
+    previous: Vec<Color>,
+    restore: bool,
+    control: Option<FrameControl>
+}
+
+impl<'a> Frames<'a> {
+    /// Creates an iterator over the chunks in `data`, which follow the
+    /// PNG signature, using the header already read into `png`.
+    pub fn new(png: PngFile, data: &'a [u8]) -> Self {
+        let canvas = vec![Color::new(0, 0, 0, 0); png.w * png.h];
+        Frames {
+            png: png,
+            data: data,
+            idx: 0,
+            index: 0,
+            canvas: canvas,
+            previous: Vec::new(),
+            restore: false,
+            control: None
+        }
+    }
+
+    /// The image, with its header and metadata but no pixels.
+    pub fn png(&self) -> &PngFile {
+        &self.png
+    }
+
+    /// Decodes the next frame, or returns None once every frame has
+    /// been decoded.
+    pub fn next(&mut self) -> Option<Result<FrameRef<'_>, PngError>> {
+        // Dispose of the last frame now that it is no longer borrowed
+        if let Some(control) = self.control.take() {
+            let previous = iif!(self.restore, Some(&self.previous[..]), None);
+            dispose(&mut self.canvas, self.png.w, &control, previous);
+        }
+
+        let (control, compressed_data) = match self.next_frame_data() {
+            Some(Ok(frame)) => frame,
+            Some(Err(error)) => return Some(Err(PngError::InvalidFormat(error))),
+            None => return None
+        };
+
+        let region = match self.png.decode_region(&compressed_data, control.width, control.height) {
+            Ok(region) => region,
+            Err(error) => return Some(Err(PngError::InvalidFormat(error)))
+        };
+
+        // A PREVIOUS dispose on the first frame is treated as BACKGROUND
+        self.restore = control.dispose_op == DisposeOp::Previous && self.index > 0;
+        if self.restore {
+            save_region(&self.canvas, self.png.w, &control, &mut self.previous);
+        }
+
+        blend(&mut self.canvas, self.png.w, &region, &control);
+        self.index += 1;
+        self.control = Some(control);
+
+        Some(Ok(FrameRef {
+            pixels: &self.canvas[..],
+            control: self.control.as_ref().unwrap()
+        }))
+    }
+
+    /// Finds the next fcTL chunk and gathers the IDAT or fdAT data
+    /// that follows it.
+    fn next_frame_data(&mut self) -> Option<Result<(FrameControl, Vec<u8>), String>> {
+        let mut control = None;
+        let mut compressed_data = Vec::new();
+
+        while self.idx + 0x08 <= self.data.len() {
+            let chunk_length = helpers::read_unsigned_int(&self.data[self.idx..]) as usize;
+            let chunk_start = self.idx + 0x08;
+            if chunk_start + chunk_length > self.data.len() {
+                return Some(Err("Truncated chunk".to_string()));
+            }
+
+            let chunk_type = &self.data[self.idx + 0x04..chunk_start];
+            let chunk_data = &self.data[chunk_start..chunk_start + chunk_length];
+
+            match (chunk_type, control.is_some()) {
+                (b"fcTL", false) => {
+                    match parse_fctl(chunk_data, self.png.w, self.png.h) {
+                        Ok(frame_control) => control = Some(frame_control),
+                        Err(error) => return Some(Err(error))
+                    }
+                },
+                (b"fcTL", true) | (b"IEND", _) => break,
+                (b"IDAT", true) => compressed_data.extend_from_slice(chunk_data),
+                (b"fdAT", true) => compressed_data.extend_from_slice(&chunk_data[4..]),
+                _ => ()
+            }
+
+            self.idx = chunk_start + chunk_length + 0x04;
+        }
+
+        control.map(|control| Ok((control, compressed_data)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helpers;
+    use options::DecodeOptions;
+
+    /// Builds a 2x1 RGBA animation from frame controls and the colors of
+    /// each frame's region. The first frame is the default image, and
+    /// the sequence numbers are taken from the controls.
+    fn two_pixel_animation(frames: &[(FrameControl, Vec<Color>)]) -> Vec<u8> {
+        let mut chunks = vec![(b"acTL", AnimationControl { num_frames: frames.len() as u32, num_plays: 0 }.to_bytes())];
+        for (i, &(ref control, ref pixels)) in frames.iter().enumerate() {
+            let mut raw = vec![0];
+            for p in pixels {
+                raw.extend_from_slice(&[p.r, p.g, p.b, p.a]);
+            }
+            let compressed = helpers::image_data(&raw);
+
+            chunks.push((b"fcTL", control.to_bytes()));
+            if i == 0 {
+                chunks.push((b"IDAT", compressed));
+            } else {
+                let mut fdat = Vec::new();
+                helpers::write_unsigned_int(&mut fdat, control.sequence_number + 1);
+                fdat.extend(compressed);
+                chunks.push((b"fdAT", fdat));
+            }
+        }
+
+        helpers::png_file(2, 1, 8, 6, &chunks)
+    }
+
+    fn frame_control(sequence_number: u32, width: usize, x_offset: usize, dispose_op: DisposeOp, blend_op: BlendOp) -> FrameControl {
+        FrameControl {
+            sequence_number: sequence_number,
+            width: width,
+            height: 1,
+            x_offset: x_offset,
+            y_offset: 0,
+            delay_num: 1,
+            delay_den: 10,
+            dispose_op: dispose_op,
+            blend_op: blend_op
+        }
+    }
+
+    #[test]
+    fn disposes_to_previous() {
+        let red = Color::new(255, 0, 0, 255);
+        let green = Color::new(0, 255, 0, 255);
+        let blue = Color::new(0, 0, 255, 255);
+        let clear = Color::new(0, 0, 0, 0);
+
+        // The green pixel is drawn over the red one and then put back,
+        // while the blue one is blended over what was restored. A
+        // PREVIOUS dispose on the first frame clears it instead.
+        let data = two_pixel_animation(&[
+            (frame_control(0, 2, 0, DisposeOp::Previous, BlendOp::Source), vec![red.clone(), red.clone()]),
+            (frame_control(1, 2, 0, DisposeOp::None, BlendOp::Over), vec![clear.clone(), red.clone()]),
+            (frame_control(3, 1, 1, DisposeOp::Previous, BlendOp::Source), vec![green.clone()]),
+            (frame_control(5, 1, 0, DisposeOp::None, BlendOp::Over), vec![blue.clone()])
+        ]);
+
+        let expected = vec![
+            vec![red.clone(), red.clone()],
+            vec![clear.clone(), red.clone()],
+            vec![clear.clone(), green.clone()],
+            vec![blue.clone(), red.clone()]
+        ];
+
+        let mut options = DecodeOptions::new();
+        options.animation = true;
+        let decoded = PngFile::from_data_with_options(&data, &options).unwrap();
+        assert_eq!(decoded.frames.len(), expected.len());
+        for (frame, pixels) in decoded.frames.iter().zip(expected.iter()) {
+            assert!(&frame.pixels == pixels);
+        }
+
+        let mut frames = PngFile::frames(&data).unwrap();
+        for pixels in &expected {
+            assert!(frames.next().unwrap().unwrap().pixels == &pixels[..]);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_order_sequence_numbers() {
+        let red = Color::new(255, 0, 0, 255);
+        let frames = |second: u32| vec![
+            (frame_control(0, 2, 0, DisposeOp::None, BlendOp::Source), vec![red.clone(), red.clone()]),
+            (frame_control(second, 1, 1, DisposeOp::None, BlendOp::Source), vec![red.clone()])
+        ];
+
+        let mut options = DecodeOptions::new();
+        options.animation = true;
+        assert!(PngFile::from_data_with_options(&two_pixel_animation(&frames(1)), &options).is_ok());
+
+        // Skipping sequence numbers or repeating one fails
+        for &second in &[0, 2, 3] {
+            let data = two_pixel_animation(&frames(second));
+            assert!(PngFile::from_data_with_options(&data, &options).is_err());
+            assert!(PngFile::frames(&data).is_err());
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Command line tools built on rpng
+ */
+
+extern crate rpng;
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+const USAGE: &'static str = "Usage: rpng-tool optimize [--extreme] [--strip] <input> <output>
+       rpng-tool quantize [--colors N] [--quality Q] [--no-dither] <input> <output>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "optimize" {
+        optimize(&args[2..]);
+    } else if args.len() > 1 && args[1] == "quantize" {
+        quantize(&args[2..]);
+    } else {
+        usage();
+    }
+}
+
+/// Losslessly shrinks a PNG:
+///
+///     rpng-tool optimize [--extreme] [--strip] <input> <output>
+fn optimize(args: &[String]) {
+    let mut options = rpng::OptimizeOptions::new();
+    let mut paths = Vec::new();
+    for arg in args {
+        match &arg[..] {
+            "--extreme" => options.extreme = true,
+            "--strip" => options.strip = true,
+            _ => paths.push(arg)
+        }
+    }
+
+    if paths.len() != 2 {
+        usage();
+    }
+
+    let mut data = Vec::new();
+    if let Err(error) = File::open(paths[0]).and_then(|mut file| file.read_to_end(&mut data)) {
+        eprintln!("Error reading {}: {}", paths[0], error);
+        std::process::exit(1);
+    }
+
+    match rpng::optimize(&data, options) {
+        Err(error) => {
+            eprintln!("Error optimising PNG: {:?}", error);
+            std::process::exit(1);
+        },
+        Ok(optimized) => {
+            if let Err(error) = File::create(paths[1]).and_then(|mut file| file.write_all(&optimized)) {
+                eprintln!("Error writing {}: {}", paths[1], error);
+                std::process::exit(1);
+            }
+
+            println!("{} bytes -> {} bytes", data.len(), optimized.len());
+        }
+    }
+}
+
+/// Reduces a PNG to a palette:
+///
+///     rpng-tool quantize [--colors N] [--quality Q] [--no-dither] <input> <output>
+fn quantize(args: &[String]) {
+    let mut options = rpng::QuantizeOptions::new();
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--colors" => options.colors = args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            "--quality" => options.quality = args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            "--no-dither" => options.dither = false,
+            _ => paths.push(arg)
+        }
+    }
+
+    if paths.len() != 2 {
+        usage();
+    }
+
+    let png = match rpng::PngFile::from_path(paths[0]) {
+        Ok(png) => png,
+        Err(error) => {
+            eprintln!("Error loading PNG: {:?}", error);
+            std::process::exit(1);
+        }
+    };
+
+    match png.to_quantized_data(&options) {
+        Err(error) => {
+            eprintln!("Error quantizing PNG: {:?}", error);
+            std::process::exit(1);
+        },
+        Ok(quantized) => {
+            if let Err(error) = File::create(paths[1]).and_then(|mut file| file.write_all(&quantized)) {
+                eprintln!("Error writing {}: {}", paths[1], error);
+                std::process::exit(1);
+            }
+
+            println!("Wrote {} bytes", quantized.len());
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
@@ -0,0 +1,59 @@
+use color_type::ColorType;
+use helpers;
+
+pub type BackgroundParseResult = Result<Background, String>;
+
+/// The default background color from a bKGD chunk, stored the
+/// same way as the image's own samples.
+pub enum Background {
+    /// An index into the palette.
+    PaletteIndex(u8),
+    /// A greyscale sample at the image's bit depth.
+    Greyscale(u16),
+    /// A red, green and blue sample at the image's bit depth.
+    TrueColor(u16, u16, u16)
+}
+
+pub fn parse(data: &[u8], color_type: &ColorType, bit_depth: u8, palette_len: usize) -> BackgroundParseResult {
+    let max = ((1u32 << bit_depth) - 1) as u16;
+
+    match *color_type {
+        ColorType::IndexedColor => {
+            if data.len() != 1 {
+                return Err("Invalid bKGD length".to_string());
+            }
+
+            if data[0] as usize >= palette_len {
+                return Err("bKGD palette index out of range".to_string());
+            }
+
+            Ok(Background::PaletteIndex(data[0]))
+        },
+        ColorType::Greyscale | ColorType::GreyscaleWithAlpha => {
+            if data.len() != 2 {
+                return Err("Invalid bKGD length".to_string());
+            }
+
+            let grey = helpers::read_unsigned_short(data);
+            if grey > max {
+                return Err("bKGD sample out of range for the bit depth".to_string());
+            }
+
+            Ok(Background::Greyscale(grey))
+        },
+        _ => {
+            if data.len() != 6 {
+                return Err("Invalid bKGD length".to_string());
+            }
+
+            let r = helpers::read_unsigned_short(&data[0x00..]);
+            let g = helpers::read_unsigned_short(&data[0x02..]);
+            let b = helpers::read_unsigned_short(&data[0x04..]);
+            if r > max || g > max || b > max {
+                return Err("bKGD sample out of range for the bit depth".to_string());
+            }
+
+            Ok(Background::TrueColor(r, g, b))
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use helpers;
+
+pub type ChromaticitiesParseResult = Result<Chromaticities, String>;
+
+pub type Matrix = [[f64; 3]; 3];
+
+/// The white point and red, green and blue primaries of an image,
+/// as CIE 1931 x and y chromaticity coordinates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chromaticities {
+    pub white: (f64, f64),
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64)
+}
+
+impl Chromaticities {
+    /// The sRGB (ITU-R BT.709) primaries with a D65 white point.
+    pub fn srgb() -> Self {
+        Chromaticities {
+            white: (0.3127, 0.3290),
+            red: (0.64, 0.33),
+            green: (0.30, 0.60),
+            blue: (0.15, 0.06)
+        }
+    }
+
+    /// The Display P3 primaries with a D65 white point.
+    pub fn display_p3() -> Self {
+        Chromaticities {
+            white: (0.3127, 0.3290),
+            red: (0.680, 0.320),
+            green: (0.265, 0.690),
+            blue: (0.150, 0.060)
+        }
+    }
+
+    /// The ITU-R BT.2020 primaries with a D65 white point.
+    pub fn bt2020() -> Self {
+        Chromaticities {
+            white: (0.3127, 0.3290),
+            red: (0.708, 0.292),
+            green: (0.170, 0.797),
+            blue: (0.131, 0.046)
+        }
+    }
+
+    /// Builds the matrix converting linear RGB values with these
+    /// primaries into CIE XYZ, scaled so that white has a Y of 1.
+    pub fn to_xyz(&self) -> Matrix {
+        let primaries = [
+            [xyz(self.red)[0], xyz(self.green)[0], xyz(self.blue)[0]],
+            [xyz(self.red)[1], xyz(self.green)[1], xyz(self.blue)[1]],
+            [xyz(self.red)[2], xyz(self.green)[2], xyz(self.blue)[2]]
+        ];
+
+        // Scale each primary so that full red, green and blue
+        // together add up to the white point
+        let scale = transform(&invert(&primaries), xyz(self.white));
+
+        let mut result = primaries;
+        for row in result.iter_mut() {
+            for (c, s) in row.iter_mut().zip(scale.iter()) {
+                *c *= *s;
+            }
+        }
+
+        result
+    }
+
+    /// Builds the matrix converting CIE XYZ into linear RGB values
+    /// with these primaries.
+    pub fn from_xyz(&self) -> Matrix {
+        invert(&self.to_xyz())
+    }
+}
+
+/// Builds the matrix converting linear RGB values with one set of
+/// primaries into linear RGB values with another. When the white
+/// points differ, the colors are adapted with the Bradford transform.
+pub fn conversion_matrix(from: &Chromaticities, to: &Chromaticities) -> Matrix {
+    let adapt = if from.white == to.white {
+        [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+    } else {
+        bradford(xyz(from.white), xyz(to.white))
+    };
+
+    multiply(&to.from_xyz(), &multiply(&adapt, &from.to_xyz()))
+}
+
+/// Applies a matrix to a vector.
+pub fn transform(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2]
+    ]
+}
+
+/// Converts x and y chromaticity coordinates into XYZ with a Y of 1.
+fn xyz(xy: (f64, f64)) -> [f64; 3] {
+    let (x, y) = xy;
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// Builds the Bradford chromatic adaptation matrix between two white points.
+fn bradford(from: [f64; 3], to: [f64; 3]) -> Matrix {
+    let cone: Matrix = [
+        [ 0.8951,  0.2664, -0.1614],
+        [-0.7502,  1.7135,  0.0367],
+        [ 0.0389, -0.0685,  1.0296]
+    ];
+
+    let from = transform(&cone, from);
+    let to = transform(&cone, to);
+    let scale = [
+        [to[0] / from[0], 0.0, 0.0],
+        [0.0, to[1] / from[1], 0.0],
+        [0.0, 0.0, to[2] / from[2]]
+    ];
+
+    multiply(&invert(&cone), &multiply(&scale, &cone))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            result[i][j] = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+    }
+
+    result
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+              m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+              m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+
+    [
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) / det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) / det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) / det
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) / det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) / det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) / det
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) / det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) / det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) / det
+        ]
+    ]
+}
+
+pub fn parse(data: &[u8]) -> ChromaticitiesParseResult {
+    if data.len() != 32 {
+        return Err("Invalid cHRM length".to_string());
+    }
+
+    let point = |offset: usize| -> (f64, f64) {
+        (
+            helpers::read_unsigned_int(&data[offset..]) as f64 / 100000.0,
+            helpers::read_unsigned_int(&data[offset + 0x04..]) as f64 / 100000.0
+        )
+    };
+
+    let chromaticities = Chromaticities {
+        white: point(0x00),
+        red: point(0x08),
+        green: point(0x10),
+        blue: point(0x18)
+    };
+
+    for &(x, y) in &[chromaticities.white, chromaticities.red, chromaticities.green, chromaticities.blue] {
+        if y == 0.0 || x + y > 1.0 {
+            return Err("Invalid cHRM chromaticity".to_string());
+        }
+    }
+
+    Ok(chromaticities)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn srgb_primaries_give_the_standard_matrix() {
+        // The RGB to XYZ matrix from IEC 61966-2-1
+        let expected = [
+            [0.4124, 0.3576, 0.1805],
+            [0.2126, 0.7152, 0.0722],
+            [0.0193, 0.1192, 0.9505]
+        ];
+
+        let srgb = Chromaticities::srgb();
+        let matrix = srgb.to_xyz();
+        for (row, expected_row) in matrix.iter().zip(expected.iter()) {
+            for (c, e) in row.iter().zip(expected_row.iter()) {
+                assert!((c - e).abs() < 1e-4);
+            }
+        }
+
+        // Converting to XYZ and back changes nothing
+        let back = transform(&srgb.from_xyz(), transform(&matrix, [0.25, 0.5, 1.0]));
+        for (c, e) in back.iter().zip([0.25, 0.5, 1.0].iter()) {
+            assert!((c - e).abs() < 1e-9);
+        }
+    }
+}
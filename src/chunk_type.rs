@@ -19,5 +19,65 @@ pub enum PngChunkType {
     LastModifiedTime,
     InternationalTextualData,
     TextualData,
-    CompressedTextualData
+    CompressedTextualData,
+    Exif,
+
+    // HDR
+    CodingIndependentCodePoints,
+    MasteringDisplayColorVolume,
+    ContentLightLevel,
+
+    // Animation
+    AnimationControl,
+    FrameControl,
+    FrameData,
+
+    // Extensions
+    ImageOffset,
+    PixelCalibration,
+    PhysicalScale,
+    StereoLayout,
+    GifGraphicControl,
+    GifApplication,
+
+    Unknown
+}
+
+impl<'a> From<&'a [u8]> for PngChunkType {
+    fn from(chunk_type: &'a [u8]) -> Self {
+        match chunk_type {
+            b"IHDR" => PngChunkType::ImageHeader,
+            b"PLTE" => PngChunkType::Palette,
+            b"IDAT" => PngChunkType::ImageData,
+            b"IEND" => PngChunkType::End,
+            b"cHRM" => PngChunkType::Chromaticity,
+            b"gAMA" => PngChunkType::Gamma,
+            b"iCCP" => PngChunkType::ICCProfile,
+            b"sBIT" => PngChunkType::SignificantBits,
+            b"sRGB" => PngChunkType::RGBColorSpace,
+            b"bKGD" => PngChunkType::BackgroundColor,
+            b"hIST" => PngChunkType::Histogram,
+            b"tRNS" => PngChunkType::Transparency,
+            b"pHYs" => PngChunkType::PhysicalPixelDimensions,
+            b"sPLT" => PngChunkType::SuggestedPalette,
+            b"tIME" => PngChunkType::LastModifiedTime,
+            b"iTXt" => PngChunkType::InternationalTextualData,
+            b"tEXt" => PngChunkType::TextualData,
+            b"zTXt" => PngChunkType::CompressedTextualData,
+            b"eXIf" => PngChunkType::Exif,
+            b"cICP" => PngChunkType::CodingIndependentCodePoints,
+            b"mDCV" => PngChunkType::MasteringDisplayColorVolume,
+            b"cLLI" => PngChunkType::ContentLightLevel,
+            b"acTL" => PngChunkType::AnimationControl,
+            b"fcTL" => PngChunkType::FrameControl,
+            b"fdAT" => PngChunkType::FrameData,
+            b"oFFs" => PngChunkType::ImageOffset,
+            b"pCAL" => PngChunkType::PixelCalibration,
+            b"sCAL" => PngChunkType::PhysicalScale,
+            b"sTER" => PngChunkType::StereoLayout,
+            b"gIFg" => PngChunkType::GifGraphicControl,
+            b"gIFx" => PngChunkType::GifApplication,
+            _ => PngChunkType::Unknown
+        }
+    }
 }
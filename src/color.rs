@@ -1,5 +1,6 @@
 use std::fmt;
 
+#[derive(PartialEq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -16,6 +17,30 @@ impl Color {
             a: a
         }
     }
+
+    /// Multiplies the color by its alpha.
+    pub fn premultiply(&self) -> Self {
+        let a = self.a as u16;
+        let mul = |c: u8| ((c as u16 * a + 127) / 255) as u8;
+
+        Color::new(mul(self.r), mul(self.g), mul(self.b), self.a)
+    }
+
+    /// Divides a premultiplied color by its alpha. Fully transparent
+    /// colors become transparent black.
+    pub fn unpremultiply(&self) -> Self {
+        if self.a == 0 {
+            return Color::new(0, 0, 0, 0);
+        }
+
+        let a = self.a as u16;
+        let div = |c: u8| {
+            let c = (c as u16 * 255 + a / 2) / a;
+            iif!(c > 255, 255, c) as u8
+        };
+
+        Color::new(div(self.r), div(self.g), div(self.b), self.a)
+    }
 }
 
 impl Clone for Color {
@@ -43,6 +68,7 @@ impl fmt::Debug for Color {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Color16 {
     pub r: u16,
     pub g: u16,
@@ -59,4 +85,48 @@ impl Color16 {
             a: a
         }
     }
+
+    /// Multiplies the color by its alpha.
+    pub fn premultiply(&self) -> Self {
+        let a = self.a as u32;
+        let mul = |c: u16| ((c as u32 * a + 32767) / 65535) as u16;
+
+        Color16::new(mul(self.r), mul(self.g), mul(self.b), self.a)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn premultiply_round_trips() {
+        for a in 0..256u16 {
+            for c in 0..256u16 {
+                let color = Color::new(c as u8, 0, 255, a as u8);
+                let back = color.premultiply().unpremultiply();
+                if a == 0 {
+                    assert_eq!(back, Color::new(0, 0, 0, 0));
+                    continue;
+                }
+
+                // Premultiplying loses precision at low alpha, but never
+                // more than half a step at that alpha
+                let error = (back.r as i32 - c as i32).abs();
+                assert!(error * a as i32 * 2 <= 255 + a as i32);
+                assert_eq!((back.g, back.b, back.a), (0, 255, a as u8));
+
+                // Premultiplied colors survive the round trip the other way
+                if c <= a {
+                    let premultiplied = Color::new(c as u8, 0, a as u8, a as u8);
+                    assert_eq!(premultiplied.unpremultiply().premultiply(), premultiplied);
+                }
+            }
+        }
+
+        let color = Color16::new(65535, 32768, 1, 65535);
+        assert_eq!(color.premultiply(), color);
+        assert_eq!(Color16::new(65535, 32768, 1, 32768).premultiply(), Color16::new(32768, 16384, 1, 32768));
+        assert_eq!(Color16::new(65535, 32768, 1, 0).premultiply(), Color16::new(0, 0, 0, 0));
+    }
 }
@@ -11,6 +11,18 @@ pub enum ColorType {
 }
 
 impl ColorType {
+    /// The value stored for this ColorType in an IHDR chunk.
+    pub fn to_byte(&self) -> u8 {
+        match *self {
+            ColorType::Greyscale => 0,
+            ColorType::TrueColor => 2,
+            ColorType::IndexedColor => 3,
+            ColorType::GreyscaleWithAlpha => 4,
+            ColorType::TrueColorWithAlpha => 6,
+            ColorType::Unknown => 0xff
+        }
+    }
+
     /// Asserts that the ColorType and Bit Depth combination is
     /// valid according to the PNG spec.
     pub fn validate(&self, bit_depth: u8) -> Result<(), String> {
@@ -1,27 +1,85 @@
 
-use color::Color;
+use color::{Color, Color16};
 use helpers;
 use png::PngFile;
 
 pub trait PixelDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color>;
+
+    /// Decodes pixels as their raw samples, at the image's bit depth
+    /// rather than scaled to 8 bits. Images without an alpha channel
+    /// are given a fully opaque alpha sample.
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16>;
+
     fn step(&self) -> usize;
 }
 
+/// Unpacks the palette indices stored in a single byte of
+/// indexed-color image data.
+pub trait IndexDecoder {
+    fn indices(&self, val: u8) -> Vec<u8>;
+}
+
+/// Expands palette indices into their palette colors.
+fn lookup_palette(indices: Vec<u8>, png: &PngFile) -> Vec<Color> {
+    indices.iter().map(|&i| png.palette[i as usize].clone()).collect()
+}
+
+/// Expands palette indices into the samples of their palette colors.
+fn lookup_palette_samples(indices: Vec<u8>, png: &PngFile) -> Vec<Color16> {
+    indices.iter().map(|&i| {
+        let c = &png.palette[i as usize];
+        Color16::new(c.r as u16, c.g as u16, c.b as u16, c.a as u16)
+    }).collect()
+}
+
+/// Unpacks greyscale samples packed into a single byte and gives
+/// them an opaque alpha sample.
+fn unpack_greyscale(val: u8, bit_depth: u8) -> Vec<Color16> {
+    let mask = (1u16 << bit_depth) - 1;
+    let mut result = Vec::with_capacity(8 / bit_depth as usize);
+    let mut shift = 8;
+    while shift > 0 {
+        shift -= bit_depth;
+        let v = (val >> shift) as u16 & mask;
+        result.push(Color16::new(v, v, v, mask));
+    }
+
+    result
+}
+
+/// Scales unpacked greyscale samples up to 8 bits.
+fn scale_greyscale(samples: Vec<Color16>, bit_depth: u8) -> Vec<Color> {
+    samples.iter().map(|s| {
+        let v = helpers::rescale(s.r, bit_depth, 8) as u8;
+        Color::new(v, v, v, 255)
+    }).collect()
+}
+
 pub struct OneBitIndexedColorDecoder;
-impl PixelDecoder for OneBitIndexedColorDecoder {
-    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+impl IndexDecoder for OneBitIndexedColorDecoder {
+    fn indices(&self, val: u8) -> Vec<u8> {
         vec![
-            png.palette[(val >> 7) as usize].clone(),
-            png.palette[(val >> 6) as usize & 0x01].clone(),
-            png.palette[(val >> 5) as usize & 0x01].clone(),
-            png.palette[(val >> 4) as usize & 0x01].clone(),
-            png.palette[(val >> 3) as usize & 0x01].clone(),
-            png.palette[(val >> 2) as usize & 0x01].clone(),
-            png.palette[(val >> 1) as usize & 0x01].clone(),
-            png.palette[val as usize & 0x01].clone()
+            val >> 7,
+            (val >> 6) & 0x01,
+            (val >> 5) & 0x01,
+            (val >> 4) & 0x01,
+            (val >> 3) & 0x01,
+            (val >> 2) & 0x01,
+            (val >> 1) & 0x01,
+            val & 0x01
         ]
     }
+}
+
+impl PixelDecoder for OneBitIndexedColorDecoder {
+    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+        lookup_palette(self.indices(val), png)
+    }
+
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        lookup_palette_samples(self.indices(val), png)
+    }
 
     fn step(&self) -> usize {
         0x01
@@ -29,15 +87,25 @@ impl PixelDecoder for OneBitIndexedColorDecoder {
 }
 
 pub struct TwoBitIndexedColorDecoder;
-impl PixelDecoder for TwoBitIndexedColorDecoder {
-    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+impl IndexDecoder for TwoBitIndexedColorDecoder {
+    fn indices(&self, val: u8) -> Vec<u8> {
         vec![
-            png.palette[(val >> 6) as usize].clone(),
-            png.palette[(val >> 4) as usize & 0x03].clone(),
-            png.palette[(val >> 2) as usize & 0x03].clone(),
-            png.palette[val as usize & 0x03].clone()
+            val >> 6,
+            (val >> 4) & 0x03,
+            (val >> 2) & 0x03,
+            val & 0x03
         ]
     }
+}
+
+impl PixelDecoder for TwoBitIndexedColorDecoder {
+    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+        lookup_palette(self.indices(val), png)
+    }
+
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        lookup_palette_samples(self.indices(val), png)
+    }
 
     fn step(&self) -> usize {
         0x01
@@ -45,13 +113,23 @@ impl PixelDecoder for TwoBitIndexedColorDecoder {
 }
 
 pub struct FourBitIndexedColorDecoder;
-impl PixelDecoder for FourBitIndexedColorDecoder {
-    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+impl IndexDecoder for FourBitIndexedColorDecoder {
+    fn indices(&self, val: u8) -> Vec<u8> {
         vec![
-            png.palette[(val >> 4) as usize].clone(),
-            png.palette[val as usize & 0x0f].clone()
+            val >> 4,
+            val & 0x0f
         ]
     }
+}
+
+impl PixelDecoder for FourBitIndexedColorDecoder {
+    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+        lookup_palette(self.indices(val), png)
+    }
+
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        lookup_palette_samples(self.indices(val), png)
+    }
 
     fn step(&self) -> usize {
         0x01
@@ -59,9 +137,19 @@ impl PixelDecoder for FourBitIndexedColorDecoder {
 }
 
 pub struct EightBitIndexedColorDecoder;
+impl IndexDecoder for EightBitIndexedColorDecoder {
+    fn indices(&self, val: u8) -> Vec<u8> {
+        vec![val]
+    }
+}
+
 impl PixelDecoder for EightBitIndexedColorDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        vec![png.palette[val as usize].clone()]
+        lookup_palette(self.indices(val), png)
+    }
+
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        lookup_palette_samples(self.indices(val), png)
     }
 
     fn step(&self) -> usize {
@@ -72,25 +160,11 @@ impl PixelDecoder for EightBitIndexedColorDecoder {
 pub struct OneBitGreyscaleDecoder;
 impl PixelDecoder for OneBitGreyscaleDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        let one     = iif!(data[x] >> 7 & 0x01 == 0, 0, 255);
-        let two     = iif!(data[x] >> 6 & 0x01 == 0, 0, 255);
-        let three   = iif!(data[x] >> 5 & 0x01 == 0, 0, 255);
-        let four    = iif!(data[x] >> 4 & 0x01 == 0, 0, 255);
-        let five    = iif!(data[x] >> 3 & 0x01 == 0, 0, 255);
-        let six     = iif!(data[x] >> 2 & 0x01 == 0, 0, 255);
-        let seven   = iif!(data[x] >> 1 & 0x01 == 0, 0, 255);
-        let eight   = iif!(data[x] & 0x01 == 0, 0, 255);
+        scale_greyscale(self.samples(data, x, val, png), 1)
+    }
 
-        vec![
-            Color::new(one, one, one, 255),
-            Color::new(two, two, two, 255),
-            Color::new(three, three, three, 255),
-            Color::new(four, four, four, 255),
-            Color::new(five, five, five, 255),
-            Color::new(six, six, six, 255),
-            Color::new(seven, seven, seven, 255),
-            Color::new(eight, eight, eight, 255),
-        ]
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        unpack_greyscale(data[x], 1)
     }
 
     fn step(&self) -> usize {
@@ -101,24 +175,11 @@ impl PixelDecoder for OneBitGreyscaleDecoder {
 pub struct TwoBitGreyscaleDecoder;
 impl PixelDecoder for TwoBitGreyscaleDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        let lookup = vec![
-            Color::new(0, 0, 0, 255),
-            Color::new(85, 85, 85, 255),
-            Color::new(170, 170, 170, 255),
-            Color::new(255, 255, 255, 255)
-        ];
-
-        let one     = (data[x] >> 6 & 0x03) as usize;
-        let two     = (data[x] >> 4 & 0x03) as usize;
-        let three   = (data[x] >> 2 & 0x03) as usize;
-        let four    = (data[x] & 0x03) as usize;
+        scale_greyscale(self.samples(data, x, val, png), 2)
+    }
 
-        vec![
-            lookup[one].clone(),
-            lookup[two].clone(),
-            lookup[three].clone(),
-            lookup[four].clone()
-        ]
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        unpack_greyscale(data[x], 2)
     }
 
     fn step(&self) -> usize {
@@ -129,32 +190,11 @@ impl PixelDecoder for TwoBitGreyscaleDecoder {
 pub struct FourBitGreyscaleDecoder;
 impl PixelDecoder for FourBitGreyscaleDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        let lookup = vec![
-            Color::new(0, 0, 0, 255),
-            Color::new(17, 17, 17, 255),
-            Color::new(34, 34, 34, 255),
-            Color::new(51, 51, 51, 255),
-            Color::new(68, 68, 68, 255),
-            Color::new(85, 85, 85, 255),
-            Color::new(102, 102, 102, 255),
-            Color::new(119, 119, 119, 255),
-            Color::new(136, 136, 136, 255),
-            Color::new(153, 153, 153, 255),
-            Color::new(170, 170, 170, 255),
-            Color::new(187, 187, 187, 255),
-            Color::new(204, 204, 204, 255),
-            Color::new(221, 221, 221, 255),
-            Color::new(238, 238, 238, 255),
-            Color::new(255, 255, 255, 255),
-        ];
-
-        let one     = (data[x] >> 4 & 0x0f) as usize;
-        let two     = (data[x] & 0x0f) as usize;
+        scale_greyscale(self.samples(data, x, val, png), 4)
+    }
 
-        vec![
-            lookup[one].clone(),
-            lookup[two].clone()
-        ]
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        unpack_greyscale(data[x], 4)
     }
 
     fn step(&self) -> usize {
@@ -175,6 +215,11 @@ impl PixelDecoder for EightBitGreyscaleDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        let v = data[x] as u16;
+        vec![Color16::new(v, v, v, 0xff)]
+    }
+
     fn step(&self) -> usize {
         0x01
     }
@@ -183,10 +228,8 @@ impl PixelDecoder for EightBitGreyscaleDecoder {
 pub struct SixteenBitGreyscaleDecoder;
 impl PixelDecoder for SixteenBitGreyscaleDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        let combined = ((data[x] as u16) << 8) | data[x + 0x01] as u16;
-
-        let percentage: f64 = combined as f64 / u16::max_value() as f64;
-        let result = (percentage * u8::max_value() as f64) as u8;
+        let combined = helpers::read_unsigned_short(&data[x..]);
+        let result = helpers::sixteen_to_eight(combined);
 
         vec![
             Color::new(
@@ -198,6 +241,11 @@ impl PixelDecoder for SixteenBitGreyscaleDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        let v = helpers::read_unsigned_short(&data[x..]);
+        vec![Color16::new(v, v, v, 0xffff)]
+    }
+
     fn step(&self) -> usize {
         0x02
     }
@@ -216,11 +264,42 @@ impl PixelDecoder for EightBitGreyscaleWithAlphaDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        let v = data[x] as u16;
+        vec![Color16::new(v, v, v, data[x + 0x01] as u16)]
+    }
+
     fn step(&self) -> usize {
         0x02
     }
 }
 
+pub struct SixteenBitGreyscaleWithAlphaDecoder;
+impl PixelDecoder for SixteenBitGreyscaleWithAlphaDecoder {
+    fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
+        let v = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x..]));
+        let a = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x + 0x02..]));
+
+        vec![
+            Color::new(
+                v,
+                v,
+                v,
+                a
+            )
+        ]
+    }
+
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        let v = helpers::read_unsigned_short(&data[x..]);
+        vec![Color16::new(v, v, v, helpers::read_unsigned_short(&data[x + 0x02..]))]
+    }
+
+    fn step(&self) -> usize {
+        0x04
+    }
+}
+
 pub struct EightBitTrueColorDecoder;
 impl PixelDecoder for EightBitTrueColorDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
@@ -234,6 +313,17 @@ impl PixelDecoder for EightBitTrueColorDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        vec![
+            Color16::new(
+                data[x] as u16,
+                data[x + 0x01] as u16,
+                data[x + 0x02] as u16,
+                0xff
+            )
+        ]
+    }
+
     fn step(&self) -> usize {
         0x03
     }
@@ -242,17 +332,9 @@ impl PixelDecoder for EightBitTrueColorDecoder {
 pub struct SixteenBitTrueColorDecoder;
 impl PixelDecoder for SixteenBitTrueColorDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        let ru16 = ((data[x] as u16) << 8) | data[x + 0x01] as u16;
-        let gu16 = ((data[x + 0x02] as u16) << 8) | data[x + 0x03] as u16;
-        let bu16 = ((data[x + 0x04] as u16) << 8) | data[x + 0x05] as u16;
-
-        let rp: f64 = ru16 as f64 / u16::max_value() as f64;
-        let gp: f64 = gu16 as f64 / u16::max_value() as f64;
-        let bp: f64 = bu16 as f64 / u16::max_value() as f64;
-
-        let r = (rp * u8::max_value() as f64) as u8;
-        let g = (gp * u8::max_value() as f64) as u8;
-        let b = (bp * u8::max_value() as f64) as u8;
+        let r = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x..]));
+        let g = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x + 0x02..]));
+        let b = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x + 0x04..]));
 
         vec![
             Color::new(
@@ -264,6 +346,17 @@ impl PixelDecoder for SixteenBitTrueColorDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        vec![
+            Color16::new(
+                helpers::read_unsigned_short(&data[x..]),
+                helpers::read_unsigned_short(&data[x + 0x02..]),
+                helpers::read_unsigned_short(&data[x + 0x04..]),
+                0xffff
+            )
+        ]
+    }
+
     fn step(&self) -> usize {
         0x06
     }
@@ -282,6 +375,17 @@ impl PixelDecoder for EightBitTrueColorWithAlphaDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        vec![
+            Color16::new(
+                data[x] as u16,
+                data[x + 0x01] as u16,
+                data[x + 0x02] as u16,
+                data[x + 0x03] as u16
+            )
+        ]
+    }
+
     fn step(&self) -> usize {
         0x04
     }
@@ -290,20 +394,10 @@ impl PixelDecoder for EightBitTrueColorWithAlphaDecoder {
 pub struct SixteenBitTrueColorWithAlphaDecoder;
 impl PixelDecoder for SixteenBitTrueColorWithAlphaDecoder {
     fn decode(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color> {
-        let ru16 = ((data[x] as u16) << 8) | data[x + 0x01] as u16;
-        let gu16 = ((data[x + 0x02] as u16) << 8) | data[x + 0x03] as u16;
-        let bu16 = ((data[x + 0x04] as u16) << 8) | data[x + 0x05] as u16;
-        let au16 = ((data[x + 0x06] as u16) << 8) | data[x + 0x07] as u16;
-
-        let rp: f64 = ru16 as f64 / u16::max_value() as f64;
-        let gp: f64 = gu16 as f64 / u16::max_value() as f64;
-        let bp: f64 = bu16 as f64 / u16::max_value() as f64;
-        let ap: f64 = au16 as f64 / u16::max_value() as f64;
-
-        let r = (rp * u8::max_value() as f64) as u8;
-        let g = (gp * u8::max_value() as f64) as u8;
-        let b = (bp * u8::max_value() as f64) as u8;
-        let a = (ap * u8::max_value() as f64) as u8;
+        let r = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x..]));
+        let g = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x + 0x02..]));
+        let b = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x + 0x04..]));
+        let a = helpers::sixteen_to_eight(helpers::read_unsigned_short(&data[x + 0x06..]));
 
         vec![
             Color::new(
@@ -315,6 +409,17 @@ impl PixelDecoder for SixteenBitTrueColorWithAlphaDecoder {
         ]
     }
 
+    fn samples(&self, data: &[u8], x: usize, val: u8, png: &PngFile) -> Vec<Color16> {
+        vec![
+            Color16::new(
+                helpers::read_unsigned_short(&data[x..]),
+                helpers::read_unsigned_short(&data[x + 0x02..]),
+                helpers::read_unsigned_short(&data[x + 0x04..]),
+                helpers::read_unsigned_short(&data[x + 0x06..])
+            )
+        ]
+    }
+
     fn step(&self) -> usize {
         0x08
     }
@@ -1,16 +1,31 @@
 extern crate flate2;
 
 use std::io::Read;
+use std::thread;
 
-use self::flate2::read::ZlibDecoder;
+use self::flate2::read::{DeflateDecoder, ZlibDecoder};
+
+use helpers;
+use options::Compression;
+use zopfli;
 
 pub fn decode<F>(data: &[u8], get_size: F) -> Result<Vec<u8>, String> 
     where F: Fn() -> usize {
 
-    let predict = get_size();
+    inflate(ZlibDecoder::new(&data[..]), get_size())
+}
+
+/// Decodes raw deflate data without a zlib header or checksum, as
+/// found in Apple's CgBI PNGs.
+pub fn decode_raw<F>(data: &[u8], get_size: F) -> Result<Vec<u8>, String>
+    where F: Fn() -> usize {
+
+    inflate(DeflateDecoder::new(&data[..]), get_size())
+}
+
+fn inflate<R: Read>(mut decompressor: R, predict: usize) -> Result<Vec<u8>, String> {
     let mut decompressed_data = Vec::new();
     let mut buf = Vec::with_capacity(predict);
-    let mut decompressor = ZlibDecoder::new(&data[..]);
     match decompressor.read_to_end(&mut buf) {
         Ok(n) => {
             if n != 0 {
@@ -22,3 +37,1057 @@ pub fn decode<F>(data: &[u8], get_size: F) -> Result<Vec<u8>, String>
 
     Ok(decompressed_data)
 }
+
+/// Compresses data into a zlib stream at the default level.
+pub fn encode(data: &[u8]) -> Result<Vec<u8>, String> {
+    encode_with(data, &Compression::new())
+}
+
+/// Compresses data into a zlib stream at the given level and window
+/// size. The output only depends on the input and the options.
+pub fn encode_with(data: &[u8], compression: &Compression) -> Result<Vec<u8>, String> {
+    try!(check_compression(compression));
+
+    let mut writer = BitWriter::new();
+    write_zlib_header(&mut writer, compression);
+    deflate_from(&mut writer, data, 0, compression, true);
+
+    let mut result = writer.finish();
+    helpers::write_unsigned_int(&mut result, adler32(data));
+
+    Ok(result)
+}
+
+fn check_compression(compression: &Compression) -> Result<(), String> {
+    if compression.level > 9 {
+        return Err(format!("Invalid compression level: {}", compression.level));
+    }
+
+    if compression.window_bits < 8 || compression.window_bits > 15 {
+        return Err(format!("Invalid window size: {} bits", compression.window_bits));
+    }
+
+    Ok(())
+}
+
+/// Compresses `data[start..]` as deflate blocks, finding matches in the
+/// bytes before `start` as well. Only the last block of the last part
+/// of a stream is marked as final.
+fn deflate_from(writer: &mut BitWriter, data: &[u8], start: usize, compression: &Compression, last: bool) {
+    if compression.extreme {
+        zopfli::compress(writer, data, start, compression, last);
+    } else if compression.level == 0 {
+        write_stored(writer, &data[start..], last);
+    } else {
+        let tokens = lz77(data, start, compression);
+        write_blocks(writer, &data[start..], &tokens, last);
+    }
+}
+
+/// Compresses data into a zlib stream by splitting it into bands of
+/// `band_size` bytes and compressing the bands on separate threads.
+/// Each band can match the window of data before it, and ends with a
+/// sync flush so that the bands join up into a single stream. The
+/// output only depends on the band size, not on the number of threads.
+pub fn encode_parallel(data: &[u8], band_size: usize, threads: usize, compression: &Compression) -> Result<Vec<u8>, String> {
+    try!(check_compression(compression));
+
+    if band_size == 0 {
+        return Err("The band size must not be 0".to_string());
+    }
+
+    let bands: Vec<(usize, usize)> = if data.is_empty() {
+        vec![(0, 0)]
+    } else {
+        (0..(data.len() + band_size - 1) / band_size).map(|i| {
+            (i * band_size, ::std::cmp::min((i + 1) * band_size, data.len()))
+        }).collect()
+    };
+
+    let window_size = 1 << compression.window_bits;
+    let compress_band = |index: usize| {
+        let (start, end) = bands[index];
+        let history = start - ::std::cmp::min(start, window_size);
+        let last = index == bands.len() - 1;
+
+        let mut writer = BitWriter::new();
+        deflate_from(&mut writer, &data[history..end], start - history, compression, last);
+        if !last {
+            // An empty stored block aligns the band to a byte boundary
+            write_stored(&mut writer, &[], false);
+        }
+
+        (writer.finish(), adler32(&data[start..end]))
+    };
+
+    let band_count = bands.len();
+    let threads = ::std::cmp::max(1, ::std::cmp::min(threads, band_count));
+    let mut results: Vec<Option<(Vec<u8>, u32)>> = (0..bands.len()).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|thread| {
+            let compress_band = &compress_band;
+            scope.spawn(move || {
+                (thread..band_count).step_by(threads).map(|i| (i, compress_band(i))).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        for worker in workers {
+            for (i, result) in worker.join().unwrap() {
+                results[i] = Some(result);
+            }
+        }
+    });
+
+    let mut writer = BitWriter::new();
+    write_zlib_header(&mut writer, compression);
+    let mut result = writer.finish();
+
+    let mut checksum = 1;
+    for (i, band) in results.into_iter().enumerate() {
+        let (compressed_data, band_checksum) = band.unwrap();
+        result.extend(compressed_data);
+        checksum = adler32_combine(checksum, band_checksum, bands[i].1 - bands[i].0);
+    }
+
+    helpers::write_unsigned_int(&mut result, checksum);
+
+    Ok(result)
+}
+
+/// Combines the Adler-32 checksums of two pieces of data into the
+/// checksum of both, given the length of the second piece.
+pub fn adler32_combine(first: u32, second: u32, second_length: usize) -> u32 {
+    const BASE: u64 = 65521;
+    let remainder = second_length as u64 % BASE;
+
+    let mut a = (first & 0xFFFF) as u64;
+    let mut b = (remainder * a) % BASE;
+    a += (second & 0xFFFF) as u64 + BASE - 1;
+    b += (first >> 16) as u64 + (second >> 16) as u64 + BASE - remainder;
+
+    a %= BASE;
+    b %= BASE;
+
+    ((b << 16) | a) as u32
+}
+
+pub fn write_zlib_header(writer: &mut BitWriter, compression: &Compression) {
+    let cmf = ((compression.window_bits as u16 - 8) << 4) | 8;
+    let level: u16 = match iif!(compression.extreme, 9, compression.level) {
+        0 | 1 => 0,
+        2 | 3 | 4 | 5 => 1,
+        6 => 2,
+        _ => 3
+    };
+
+    // The check bits make the header a multiple of 31
+    let mut flg = level << 6;
+    flg += 31 - ((cmf << 8) + flg) % 31;
+
+    writer.write_bits(cmf as u32, 8);
+    writer.write_bits(flg as u32, 8);
+}
+
+/// The Adler-32 checksum that ends a zlib stream.
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // 5552 bytes is the most that can be summed before b overflows
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+/// Writes bits least significant first, as deflate expects.
+pub struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter {
+            out: Vec::new(),
+            bits: 0,
+            count: 0
+        }
+    }
+
+    pub fn write_bits(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which is stored most significant bit first.
+    pub fn write_code(&mut self, code: u16, length: u8) {
+        let mut reversed = 0;
+        for i in 0..length {
+            reversed |= ((code >> i) & 1) << (length - 1 - i);
+        }
+        self.write_bits(reversed as u32, length as u32);
+    }
+
+    pub fn align(&mut self) {
+        if self.count > 0 {
+            let count = 8 - self.count;
+            self.write_bits(0, count);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.out
+    }
+}
+
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+
+/// The number of symbols in a block before it is ended and a new
+/// one started with its own Huffman codes.
+const BLOCK_SYMBOLS: usize = 16384;
+
+/// The first length and distance of each code, and how many extra
+/// bits follow it.
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+pub const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+pub const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// The order code length code lengths are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// How hard each compression level searches for matches: matches this
+/// long only get a quarter of the search, the longest match that is
+/// still checked for a better one at the next byte, the length that
+/// ends a search early, and how many earlier positions are searched.
+const LEVELS: [(usize, usize, usize, usize); 10] = [
+    (0, 0, 0, 0),
+    (4, 0, 8, 4),
+    (4, 0, 16, 8),
+    (4, 0, 32, 32),
+    (4, 4, 16, 16),
+    (8, 16, 32, 32),
+    (8, 16, 128, 128),
+    (8, 32, 128, 256),
+    (32, 128, 258, 1024),
+    (32, 258, 258, 4096)
+];
+
+/// A literal byte, or a match of `length` bytes `distance` bytes back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Token {
+    Literal(u8),
+    Match(u16, u16)
+}
+
+pub fn length_code(length: usize) -> usize {
+    let mut code = 28;
+    while LENGTH_BASE[code] as usize > length {
+        code -= 1;
+    }
+    code
+}
+
+pub fn distance_code(distance: usize) -> usize {
+    let mut code = 29;
+    while DISTANCE_BASE[code] as usize > distance {
+        code -= 1;
+    }
+    code
+}
+
+/// Finds repeated strings with hash chains over a sliding window.
+pub struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    window_size: usize,
+    inserted: usize
+}
+
+const NIL: usize = ::std::usize::MAX;
+
+impl<'a> Matcher<'a> {
+    pub fn new(data: &'a [u8], window_bits: u8) -> Self {
+        let window_size = 1 << window_bits;
+        Matcher {
+            data: data,
+            head: vec![NIL; 1 << HASH_BITS],
+            prev: vec![NIL; window_size],
+            window_size: window_size,
+            inserted: 0
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let d = self.data;
+        (((d[pos] as usize) << 10) ^ ((d[pos + 1] as usize) << 5) ^ d[pos + 2] as usize) & ((1 << HASH_BITS) - 1)
+    }
+
+    /// Adds every position before `end` to the hash chains.
+    fn insert_up_to(&mut self, end: usize) {
+        while self.inserted < end {
+            let pos = self.inserted;
+            if pos + MIN_MATCH <= self.data.len() {
+                let hash = self.hash(pos);
+                self.prev[pos & (self.window_size - 1)] = self.head[hash];
+                self.head[hash] = pos;
+            }
+            self.inserted += 1;
+        }
+    }
+
+    /// Finds the longest match for the bytes at `pos` that is longer than
+    /// `min_length`, searching at most `chain` earlier positions.
+    pub fn find(&mut self, pos: usize, min_length: usize, chain: usize, nice: usize) -> Option<(usize, usize)> {
+        self.search(pos, min_length, chain, nice, None)
+    }
+
+    /// Finds every match at `pos` that is longer than all the matches at
+    /// smaller distances, as `(length, distance)` pairs in order of
+    /// increasing length. The closest match of any length is the first
+    /// pair at least that long.
+    pub fn find_all(&mut self, pos: usize, chain: usize, matches: &mut Vec<(u16, u16)>) {
+        self.search(pos, 0, chain, MAX_MATCH, Some(matches));
+    }
+
+    fn search(&mut self, pos: usize, min_length: usize, chain: usize, nice: usize, mut matches: Option<&mut Vec<(u16, u16)>>) -> Option<(usize, usize)> {
+        self.insert_up_to(pos);
+
+        let data = self.data;
+        let max_length = ::std::cmp::min(MAX_MATCH, data.len() - pos);
+        if max_length < MIN_MATCH {
+            return None;
+        }
+
+        let mut best_length = ::std::cmp::max(min_length, MIN_MATCH - 1);
+        if best_length >= max_length {
+            return None;
+        }
+
+        let mut best = None;
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = chain;
+
+        while candidate != NIL && chain > 0 {
+            let distance = pos - candidate;
+            if distance > self.window_size || distance > 32768 {
+                break;
+            }
+
+            if data[candidate + best_length] == data[pos + best_length] && data[candidate] == data[pos] {
+                let mut length = 0;
+                while length < max_length && data[candidate + length] == data[pos + length] {
+                    length += 1;
+                }
+
+                if length > best_length {
+                    best_length = length;
+                    best = Some((length, distance));
+                    if let Some(ref mut matches) = matches {
+                        matches.push((length as u16, distance as u16));
+                    }
+
+                    if length >= nice || length == max_length {
+                        break;
+                    }
+                }
+            }
+
+            // Chain entries are overwritten once they leave the window
+            let next = self.prev[candidate & (self.window_size - 1)];
+            if next == NIL || next >= candidate {
+                break;
+            }
+
+            candidate = next;
+            chain -= 1;
+        }
+
+        best
+    }
+}
+
+/// Splits `data[start..]` into literals and matches. Levels 4 and up use lazy
+/// matching, where a match is only taken if the next byte doesn't
+/// start a longer one.
+pub fn lz77(data: &[u8], start: usize, compression: &Compression) -> Vec<Token> {
+    let (good, lazy, nice, chain) = LEVELS[compression.level as usize];
+    let mut matcher = Matcher::new(data, compression.window_bits);
+    let mut tokens = Vec::with_capacity((data.len() - start) / 2);
+
+    let mut pos = start;
+    let mut pending: Option<(usize, usize)> = None;
+    while pos < data.len() {
+        let found = match pending.take() {
+            Some(found) => Some(found),
+            None => matcher.find(pos, 0, chain, nice)
+        };
+
+        let (length, distance) = match found {
+            Some(found) => found,
+            None => {
+                tokens.push(Token::Literal(data[pos]));
+                pos += 1;
+                continue;
+            }
+        };
+
+        if length < lazy && pos + 1 < data.len() {
+            let chain = iif!(length >= good, chain / 4, chain);
+            if let Some(next) = matcher.find(pos + 1, length, chain, nice) {
+                // The next byte starts a longer match, so emit this one
+                // as a literal and take that one instead
+                tokens.push(Token::Literal(data[pos]));
+                pending = Some(next);
+                pos += 1;
+                continue;
+            }
+        }
+
+        tokens.push(Token::Match(length as u16, distance as u16));
+        pos += length;
+    }
+
+    tokens
+}
+
+/// Writes tokens as a series of blocks, each using whichever of fixed
+/// codes, dynamic codes or no compression is smallest.
+fn write_blocks(writer: &mut BitWriter, data: &[u8], tokens: &[Token], last: bool) {
+    let mut start = 0;
+    let blocks: Vec<&[Token]> = tokens.chunks(BLOCK_SYMBOLS).collect();
+    if blocks.is_empty() {
+        write_block(writer, &[], &[], last, false);
+    }
+
+    for (i, block) in blocks.iter().enumerate() {
+        let length: usize = block.iter().map(|t| match *t {
+            Token::Literal(_) => 1,
+            Token::Match(length, _) => length as usize
+        }).sum();
+
+        write_block(writer, &data[start..start + length], block, last && i == blocks.len() - 1, false);
+        start += length;
+    }
+}
+
+/// The symbol frequencies of a block of tokens, including its end of
+/// block symbol.
+pub fn frequencies(tokens: &[Token]) -> ([usize; 286], [usize; 30]) {
+    let mut literal_frequencies = [0usize; 286];
+    let mut distance_frequencies = [0usize; 30];
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_frequencies[byte as usize] += 1,
+            Token::Match(length, distance) => {
+                literal_frequencies[257 + length_code(length as usize)] += 1;
+                distance_frequencies[distance_code(distance as usize)] += 1;
+            }
+        }
+    }
+    literal_frequencies[256] = 1;
+
+    (literal_frequencies, distance_frequencies)
+}
+
+/// The Huffman codes and encoded sizes of a block with each kind of
+/// block type.
+struct BlockPlan {
+    literal_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    header: DynamicHeader,
+    fixed_size: usize,
+    dynamic_size: usize,
+    stored_size: usize
+}
+
+impl BlockPlan {
+    fn new(data_length: usize, tokens: &[Token], thorough: bool) -> Self {
+        let (literal_frequencies, distance_frequencies) = frequencies(tokens);
+        BlockPlan::with_frequencies(data_length, &literal_frequencies, &distance_frequencies, thorough)
+    }
+
+    /// Plans a block from its symbol frequencies. A thorough plan also
+    /// tries codes built from smoothed frequencies, which can cost more
+    /// bits for the data but fewer for the run length encoded header.
+    fn with_frequencies(data_length: usize, literal_frequencies: &[usize], distance_frequencies: &[usize], thorough: bool) -> Self {
+        let mut literal_lengths = huffman_lengths(literal_frequencies, 15);
+        let mut distance_lengths = huffman_lengths(distance_frequencies, 15);
+        let mut header = dynamic_header(&literal_lengths, &distance_lengths, thorough);
+        let mut dynamic_size = 3 + header.size + data_size(literal_frequencies, distance_frequencies, &literal_lengths, &distance_lengths);
+
+        if thorough {
+            let smooth_literals = huffman_lengths(&smooth_for_rle(literal_frequencies), 15);
+            let smooth_distances = huffman_lengths(&smooth_for_rle(distance_frequencies), 15);
+            let smooth_header = dynamic_header(&smooth_literals, &smooth_distances, true);
+            let smooth_size = 3 + smooth_header.size + data_size(literal_frequencies, distance_frequencies, &smooth_literals, &smooth_distances);
+            if smooth_size < dynamic_size {
+                literal_lengths = smooth_literals;
+                distance_lengths = smooth_distances;
+                header = smooth_header;
+                dynamic_size = smooth_size;
+            }
+        }
+
+        let (fixed_literals, fixed_distances) = fixed_lengths();
+        let fixed_size = 3 + data_size(literal_frequencies, distance_frequencies, &fixed_literals, &fixed_distances);
+
+        BlockPlan {
+            literal_lengths: literal_lengths,
+            distance_lengths: distance_lengths,
+            header: header,
+            fixed_size: fixed_size,
+            dynamic_size: dynamic_size,
+            stored_size: stored_size(data_length)
+        }
+    }
+
+    fn size(&self) -> usize {
+        ::std::cmp::min(self.stored_size, ::std::cmp::min(self.fixed_size, self.dynamic_size))
+    }
+}
+
+/// The size in bits of a block of tokens, which decode to `data_length`
+/// bytes, with its smallest block type. A thorough search spends
+/// longer looking for Huffman codes that are cheaper to store.
+pub fn block_size(data_length: usize, tokens: &[Token], thorough: bool) -> usize {
+    BlockPlan::new(data_length, tokens, thorough).size()
+}
+
+/// The size in bits of a block with the given symbol frequencies, as
+/// counted by `frequencies`, which decodes to `data_length` bytes.
+pub fn block_size_with(data_length: usize, literal_frequencies: &[usize], distance_frequencies: &[usize], thorough: bool) -> usize {
+    BlockPlan::with_frequencies(data_length, literal_frequencies, distance_frequencies, thorough).size()
+}
+
+/// Writes a single block of tokens, which decode to `data`.
+pub fn write_block(writer: &mut BitWriter, data: &[u8], tokens: &[Token], last: bool, thorough: bool) {
+    let plan = BlockPlan::new(data.len(), tokens, thorough);
+
+    if plan.stored_size < plan.fixed_size && plan.stored_size < plan.dynamic_size {
+        write_stored(writer, data, last);
+    } else if plan.fixed_size <= plan.dynamic_size {
+        let (fixed_literals, fixed_distances) = fixed_lengths();
+        writer.write_bits(last as u32, 1);
+        writer.write_bits(1, 2);
+        write_tokens(writer, tokens, &fixed_literals, &fixed_distances);
+    } else {
+        writer.write_bits(last as u32, 1);
+        writer.write_bits(2, 2);
+        plan.header.write(writer);
+        write_tokens(writer, tokens, &plan.literal_lengths, &plan.distance_lengths);
+    }
+}
+
+/// The size in bits of stored blocks holding `length` bytes, assuming
+/// the worst case padding.
+fn stored_size(length: usize) -> usize {
+    let blocks = iif!(length == 0, 1, (length + 65534) / 65535);
+    blocks * (3 + 7 + 32) + length * 8
+}
+
+fn write_stored(writer: &mut BitWriter, data: &[u8], last: bool) {
+    let blocks: Vec<&[u8]> = iif!(data.is_empty(), vec![data], data.chunks(65535).collect());
+    for (i, block) in blocks.iter().enumerate() {
+        writer.write_bits((last && i == blocks.len() - 1) as u32, 1);
+        writer.write_bits(0, 2);
+        writer.align();
+
+        let length = block.len() as u32;
+        writer.write_bits(length, 16);
+        writer.write_bits(!length & 0xFFFF, 16);
+        for &byte in block.iter() {
+            writer.write_bits(byte as u32, 8);
+        }
+    }
+}
+
+/// The code lengths of the fixed Huffman codes.
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut literals = vec![8; 288];
+    for length in &mut literals[144..256] {
+        *length = 9;
+    }
+    for length in &mut literals[256..280] {
+        *length = 7;
+    }
+
+    (literals, vec![5; 30])
+}
+
+/// The size in bits of the tokens, including extra bits.
+fn data_size(literal_frequencies: &[usize], distance_frequencies: &[usize], literal_lengths: &[u8], distance_lengths: &[u8]) -> usize {
+    let mut size = 0;
+    for (symbol, &frequency) in literal_frequencies.iter().enumerate() {
+        let extra = iif!(symbol > 256, LENGTH_EXTRA[symbol - 257] as usize, 0);
+        size += frequency * (literal_lengths[symbol] as usize + extra);
+    }
+
+    for (symbol, &frequency) in distance_frequencies.iter().enumerate() {
+        size += frequency * (distance_lengths[symbol] as usize + DISTANCE_EXTRA[symbol] as usize);
+    }
+
+    size
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8]) {
+    let literal_codes = canonical_codes(literal_lengths);
+    let distance_codes = canonical_codes(distance_lengths);
+
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => writer.write_code(literal_codes[byte as usize], literal_lengths[byte as usize]),
+            Token::Match(length, distance) => {
+                let (length, distance) = (length as usize, distance as usize);
+                let code = length_code(length);
+                writer.write_code(literal_codes[257 + code], literal_lengths[257 + code]);
+                writer.write_bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+                let code = distance_code(distance);
+                writer.write_code(distance_codes[code], distance_lengths[code]);
+                writer.write_bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+            }
+        }
+    }
+
+    writer.write_code(literal_codes[256], literal_lengths[256]);
+}
+
+/// Builds the lengths of a Huffman code for the given symbol frequencies,
+/// no longer than `max_length` bits.
+fn huffman_lengths(frequencies: &[usize], max_length: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; frequencies.len()];
+
+    // Symbols sorted from least to most frequent
+    let mut symbols: Vec<usize> = (0..frequencies.len()).filter(|&s| frequencies[s] > 0).collect();
+    symbols.sort_by_key(|&s| frequencies[s]);
+
+    // A single code is given a partner so that the code is complete
+    if symbols.len() < 2 {
+        let used = iif!(symbols.is_empty(), NIL, symbols[0]);
+        for symbol in (0..frequencies.len()).filter(|&s| s != used).take(2 - symbols.len()) {
+            lengths[symbol] = 1;
+        }
+        if used != NIL {
+            lengths[used] = 1;
+        }
+        return lengths;
+    }
+
+    // Merge the two lightest trees until one is left, with leaves and
+    // merged trees in two queues that each stay sorted
+    let leaves = symbols.len();
+    let mut weights: Vec<usize> = symbols.iter().map(|&s| frequencies[s]).collect();
+    let mut parents = vec![0usize; leaves * 2 - 1];
+    let (mut next_leaf, mut next_node) = (0, leaves);
+    for node in leaves..leaves * 2 - 1 {
+        let mut children = [0; 2];
+        for child in children.iter_mut() {
+            let take_leaf = next_leaf < leaves && (next_node >= node || weights[next_leaf] <= weights[next_node]);
+            *child = iif!(take_leaf, next_leaf, next_node);
+            if take_leaf {
+                next_leaf += 1;
+            } else {
+                next_node += 1;
+            }
+        }
+
+        weights.push(weights[children[0]] + weights[children[1]]);
+        parents[children[0]] = node;
+        parents[children[1]] = node;
+    }
+
+    // Count how many leaves sit at each depth
+    let root = leaves * 2 - 2;
+    let mut depths = vec![0usize; leaves * 2 - 1];
+    let mut counts = vec![0usize; leaves + 1];
+    for node in (0..root).rev() {
+        depths[node] = depths[parents[node]] + 1;
+        if node < leaves {
+            counts[depths[node]] += 1;
+        }
+    }
+
+    // Move leaves deeper than the limit up to it, then lengthen
+    // shorter codes until the code is complete again
+    if counts.len() > max_length + 1 {
+        let overflow: usize = counts[max_length + 1..].iter().sum();
+        counts.truncate(max_length + 1);
+        counts[max_length] += overflow;
+
+        let mut total: usize = (1..max_length + 1).map(|i| counts[i] << (max_length - i)).sum();
+        while total > 1 << max_length {
+            counts[max_length] -= 1;
+            for i in (1..max_length).rev() {
+                if counts[i] > 0 {
+                    counts[i] -= 1;
+                    counts[i + 1] += 2;
+                    break;
+                }
+            }
+            total -= 1;
+        }
+    }
+
+    // The least frequent symbols get the longest codes
+    let mut index = 0;
+    for length in (1..counts.len()).rev() {
+        for _ in 0..counts[length] {
+            lengths[symbols[index]] = length as u8;
+            index += 1;
+        }
+    }
+
+    lengths
+}
+
+/// Assigns canonical Huffman codes to the given code lengths.
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &length in lengths {
+        counts[length as usize] += 1;
+    }
+    counts[0] = 0;
+
+    let mut next = [0u16; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+
+    lengths.iter().map(|&length| {
+        if length == 0 {
+            0
+        } else {
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code
+        }
+    }).collect()
+}
+
+/// The code lengths of a dynamic block, run length encoded and
+/// compressed with their own Huffman code.
+struct DynamicHeader {
+    literal_count: usize,
+    distance_count: usize,
+    code_length_count: usize,
+    symbols: Vec<(u8, u8)>,
+    code_length_lengths: Vec<u8>,
+    size: usize
+}
+
+impl DynamicHeader {
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits((self.literal_count - 257) as u32, 5);
+        writer.write_bits((self.distance_count - 1) as u32, 5);
+        writer.write_bits((self.code_length_count - 4) as u32, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..self.code_length_count] {
+            writer.write_bits(self.code_length_lengths[symbol] as u32, 3);
+        }
+
+        let codes = canonical_codes(&self.code_length_lengths);
+        for &(symbol, extra) in &self.symbols {
+            let symbol = symbol as usize;
+            writer.write_code(codes[symbol], self.code_length_lengths[symbol]);
+            match symbol {
+                16 => writer.write_bits(extra as u32, 2),
+                17 => writer.write_bits(extra as u32, 3),
+                18 => writer.write_bits(extra as u32, 7),
+                _ => ()
+            }
+        }
+    }
+}
+
+/// Evens out runs of similar frequencies, so that the code lengths
+/// built from them run length encode better. Runs that are already
+/// long enough to encode well are left alone, and other runs of counts
+/// close to their average are replaced by it.
+fn smooth_for_rle(frequencies: &[usize]) -> Vec<usize> {
+    let mut counts = frequencies.to_vec();
+    let length = counts.len() - counts.iter().rev().take_while(|&&c| c == 0).count();
+    if length == 0 {
+        return counts;
+    }
+
+    let mut good = vec![false; length];
+    let (mut symbol, mut stride) = (counts[0], 0);
+    for i in 0..length + 1 {
+        if i == length || counts[i] != symbol {
+            if (symbol == 0 && stride >= 5) || (symbol != 0 && stride >= 7) {
+                for k in 0..stride {
+                    good[i - k - 1] = true;
+                }
+            }
+            stride = 1;
+            if i != length {
+                symbol = counts[i];
+            }
+        } else {
+            stride += 1;
+        }
+    }
+
+    let (mut stride, mut sum) = (0, 0);
+    let mut limit = counts[0];
+    for i in 0..length + 1 {
+        let differs = i < length && (counts[i] as isize - limit as isize).abs() >= 4;
+        if i == length || good[i] || differs {
+            if stride >= 4 || (stride >= 3 && sum == 0) {
+                let count = iif!(sum == 0, 0, ::std::cmp::max(1, (sum + stride / 2) / stride));
+                for k in 0..stride {
+                    counts[i - k - 1] = count;
+                }
+            }
+            stride = 0;
+            sum = 0;
+            limit = if i + 3 < length {
+                (counts[i] + counts[i + 1] + counts[i + 2] + counts[i + 3] + 2) / 4
+            } else if i < length {
+                counts[i]
+            } else {
+                0
+            };
+        }
+        stride += 1;
+        if i != length {
+            sum += counts[i];
+        }
+    }
+
+    counts
+}
+
+/// Run length encodes the code lengths of a dynamic block. A thorough
+/// search tries leaving out each combination of the repeat codes 16, 17
+/// and 18, as leaving one out can shorten the code length code.
+fn dynamic_header(literal_lengths: &[u8], distance_lengths: &[u8], thorough: bool) -> DynamicHeader {
+    let mut best = encode_lengths(literal_lengths, distance_lengths, [true; 3]);
+    if thorough {
+        for repeats in 0..7 {
+            let header = encode_lengths(literal_lengths, distance_lengths, [repeats & 1 != 0, repeats & 2 != 0, repeats & 4 != 0]);
+            if header.size < best.size {
+                best = header;
+            }
+        }
+    }
+
+    best
+}
+
+/// Run length encodes the code lengths, using the repeat codes 16, 17
+/// and 18 where `repeats` allows.
+fn encode_lengths(literal_lengths: &[u8], distance_lengths: &[u8], repeats: [bool; 3]) -> DynamicHeader {
+    let literal_count = ::std::cmp::max(257, 286 - literal_lengths.iter().rev().take_while(|&&l| l == 0).count());
+    let distance_count = ::std::cmp::max(1, 30 - distance_lengths.iter().rev().take_while(|&&l| l == 0).count());
+
+    let mut lengths = literal_lengths[..literal_count].to_vec();
+    lengths.extend_from_slice(&distance_lengths[..distance_count]);
+
+    // Run length encode the lengths, with 16 repeating the previous
+    // length and 17 and 18 repeating zeros
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == length {
+            run += 1;
+        }
+
+        let mut remaining = run;
+        if length == 0 && repeats[2] {
+            while remaining >= 11 {
+                let count = ::std::cmp::min(remaining, 138);
+                symbols.push((18, (count - 11) as u8));
+                remaining -= count;
+            }
+        }
+        if length == 0 && repeats[1] {
+            while remaining >= 3 {
+                let count = ::std::cmp::min(remaining, 10);
+                symbols.push((17, (count - 3) as u8));
+                remaining -= count;
+            }
+        }
+        if repeats[0] && remaining >= 4 {
+            symbols.push((length, 0));
+            remaining -= 1;
+            while remaining >= 3 {
+                let count = ::std::cmp::min(remaining, 6);
+                symbols.push((16, (count - 3) as u8));
+                remaining -= count;
+            }
+        }
+
+        for _ in 0..remaining {
+            symbols.push((length, 0));
+        }
+
+        i += run;
+    }
+
+    let mut frequencies = [0usize; 19];
+    for &(symbol, _) in &symbols {
+        frequencies[symbol as usize] += 1;
+    }
+
+    let code_length_lengths = huffman_lengths(&frequencies, 7);
+    let code_length_count = ::std::cmp::max(4, 19 - CODE_LENGTH_ORDER.iter().rev().take_while(|&&s| code_length_lengths[s] == 0).count());
+
+    let mut size = 5 + 5 + 4 + code_length_count * 3;
+    for &(symbol, _) in &symbols {
+        size += code_length_lengths[symbol as usize] as usize + match symbol {
+            16 => 2,
+            17 => 3,
+            18 => 7,
+            _ => 0
+        };
+    }
+
+    DynamicHeader {
+        literal_count: literal_count,
+        distance_count: distance_count,
+        code_length_count: code_length_count,
+        symbols: symbols,
+        code_length_lengths: code_length_lengths,
+        size: size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use options::Compression;
+
+    /// Deterministic data that mixes noise with repeated runs.
+    fn sample_data(length: usize) -> Vec<u8> {
+        let mut state = 12345u32;
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let byte = (state >> 16) as u8;
+            if byte < 64 && data.len() > 300 {
+                let start = data.len() - 1 - (state >> 8) as usize % 300;
+                for i in 0..(byte as usize % 40) {
+                    let repeated = data[start + i];
+                    data.push(repeated);
+                }
+            } else {
+                data.push(byte % 16);
+            }
+        }
+        data.truncate(length);
+        data
+    }
+
+    fn round_trip(data: &[u8], level: u8, window_bits: u8) -> Vec<u8> {
+        let mut compression = Compression::new();
+        compression.level = level;
+        compression.window_bits = window_bits;
+
+        let compressed = encode_with(data, &compression).unwrap();
+        assert_eq!(decode(&compressed, || data.len()).unwrap(), data);
+        compressed
+    }
+
+    #[test]
+    fn every_level_round_trips() {
+        let data = sample_data(100000);
+        for level in 0..10 {
+            round_trip(&data, level, 15);
+        }
+    }
+
+    #[test]
+    fn small_windows_round_trip() {
+        let data = sample_data(20000);
+        for window_bits in 8..16 {
+            round_trip(&data, 6, window_bits);
+        }
+    }
+
+    #[test]
+    fn edge_cases_round_trip() {
+        round_trip(&[], 6, 15);
+        round_trip(&[], 0, 15);
+        round_trip(&[7], 9, 15);
+        round_trip(&vec![0; 70000], 0, 15);
+        round_trip(&vec![0; 70000], 9, 15);
+        round_trip(&(0..=255).collect::<Vec<u8>>(), 1, 15);
+    }
+
+    #[test]
+    fn huffman_lengths_are_limited() {
+        // Fibonacci frequencies give the deepest possible tree
+        let mut frequencies = vec![1usize, 1];
+        while frequencies.len() < 30 {
+            let next = frequencies[frequencies.len() - 1] + frequencies[frequencies.len() - 2];
+            frequencies.push(next);
+        }
+
+        let lengths = huffman_lengths(&frequencies, 15);
+        let kraft: f64 = lengths.iter().map(|&l| 0.5f64.powi(l as i32)).sum();
+        assert!(lengths.iter().all(|&l| l >= 1 && l <= 15));
+        assert_eq!(kraft, 1.0);
+    }
+
+    #[test]
+    fn extreme_round_trips() {
+        let data = sample_data(30000);
+        let mut compression = Compression::new();
+        compression.extreme = true;
+
+        let compressed = encode_with(&data, &compression).unwrap();
+        assert_eq!(decode(&compressed, || data.len()).unwrap(), data);
+        assert!(compressed.len() <= round_trip(&data, 9, 15).len());
+        assert_eq!(decode(&encode_with(&[], &compression).unwrap(), || 0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn parallel_output_matches_for_any_thread_count() {
+        let data = sample_data(200000);
+        let compression = Compression::new();
+
+        let single = encode_parallel(&data, 30000, 1, &compression).unwrap();
+        assert_eq!(decode(&single, || data.len()).unwrap(), data);
+        for threads in 2..5 {
+            assert_eq!(encode_parallel(&data, 30000, threads, &compression).unwrap(), single);
+        }
+
+        let empty = encode_parallel(&[], 30000, 4, &compression).unwrap();
+        assert_eq!(decode(&empty, || 0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn adler32_combines() {
+        let data = sample_data(100000);
+        let (first, second) = data.split_at(70001);
+        assert_eq!(adler32_combine(adler32(first), adler32(second), second.len()), adler32(&data));
+    }
+
+    #[test]
+    fn output_is_deterministic() {
+        let data = sample_data(50000);
+        assert_eq!(round_trip(&data, 9, 15), round_trip(&data, 9, 15));
+        assert!(round_trip(&data, 9, 15).len() < round_trip(&data, 1, 15).len());
+    }
+}
@@ -0,0 +1,97 @@
+use color::{Color, Color16};
+use helpers;
+
+/// A 4x4 Bayer matrix used to pick the threshold for ordered dithering.
+const BAYER: [[u32; 4]; 4] = [
+    [ 0,  8,  2, 10],
+    [12,  4, 14,  6],
+    [ 3, 11,  1,  9],
+    [15,  7, 13,  5]
+];
+
+/// Reduces 16-bit samples to 8 bits with ordered dithering, rounding
+/// each sample up or down depending on its position in the Bayer matrix.
+pub fn ordered(samples: &[Color16], width: usize) -> Vec<Color> {
+    samples.iter().enumerate().map(|(i, s)| {
+        let (x, y) = (i % width, i / width);
+        let threshold = (BAYER[y & 3][x & 3] * 2 + 1) * 65535 / 32;
+        let reduce = |v: u16| -> u8 {
+            let scaled = v as u32 * 255;
+            let (base, fraction) = (scaled / 65535, scaled % 65535);
+            iif!(fraction > threshold, base + 1, base) as u8
+        };
+
+        Color::new(reduce(s.r), reduce(s.g), reduce(s.b), reduce(s.a))
+    }).collect()
+}
+
+/// Reduces 16-bit samples to 8 bits with Floyd-Steinberg dithering,
+/// spreading the rounding error of each sample over its neighbours.
+pub fn floyd_steinberg(samples: &[Color16], width: usize) -> Vec<Color> {
+    let mut result = Vec::with_capacity(samples.len());
+
+    diffuse(samples.len(), width, |i, carried| {
+        let s = &samples[i];
+        let mut out = [0u8; 4];
+        let mut error = [0f32; 4];
+        for (c, &v) in [s.r, s.g, s.b, s.a].iter().enumerate() {
+            let wanted = v as f32 + carried[c];
+            let clamped = iif!(wanted < 0.0, 0.0, iif!(wanted > 65535.0, 65535.0, wanted));
+            out[c] = helpers::sixteen_to_eight(clamped.round() as u16);
+            error[c] = clamped - out[c] as f32 * 257.0;
+        }
+
+        result.push(Color::new(out[0], out[1], out[2], out[3]));
+        error
+    });
+
+    result
+}
+
+/// Visits the pixels of an image in order, spreading the error left by
+/// each one over its neighbours with the Floyd-Steinberg weights. `pick`
+/// is given the index of a pixel and the error carried into it, per
+/// channel, and returns the error that it leaves.
+pub fn diffuse<F: FnMut(usize, [f32; 4]) -> [f32; 4]>(count: usize, width: usize, mut pick: F) {
+    // The error carried into the current and next rows, per channel
+    let mut current = vec![[0f32; 4]; width + 2];
+    let mut next = vec![[0f32; 4]; width + 2];
+
+    for i in 0..count {
+        let x = i % width;
+        if x == 0 && i > 0 {
+            current = next;
+            next = vec![[0f32; 4]; width + 2];
+        }
+
+        let error = pick(i, current[x + 1]);
+        for c in 0..4 {
+            let sixteenth = error[c] / 16.0;
+            current[x + 2][c] += sixteenth * 7.0;
+            next[x][c] += sixteenth * 3.0;
+            next[x + 1][c] += sixteenth * 5.0;
+            next[x + 2][c] += sixteenth;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flat_fields_keep_their_mean() {
+        let width = 64;
+        for &v in &[1000u16, 20000, 32896, 40000, 65000] {
+            let samples = vec![Color16::new(v, v, v, 65535); width * width];
+            let exact = v as f64 * 255.0 / 65535.0;
+
+            for pixels in &[ordered(&samples, width), floyd_steinberg(&samples, width)] {
+                let mean = pixels.iter().map(|p| p.r as f64).sum::<f64>() / pixels.len() as f64;
+                assert!((mean - exact).abs() < 0.05);
+                assert_eq!(mean.round() as u8, helpers::sixteen_to_eight(v));
+                assert!(pixels.iter().all(|p| p.a == 255));
+            }
+        }
+    }
+}
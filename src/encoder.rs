@@ -0,0 +1,533 @@
+extern crate flate2;
+
+use self::flate2::Crc;
+
+use std::iter;
+
+use apng::{AnimationControl, AnimationFrame, BlendOp, DisposeOp, FrameControl};
+use color::{Color, Color16};
+use deflate;
+use helpers;
+use ihdr::IHDR;
+use filters;
+use linear;
+use options::{AnimationOptions, EncodeOptions, FilterStrategy, QuantizeOptions};
+use png::{PngFile, PNG_HEADER};
+use quantize;
+use reduce::{self, PixelFormat};
+
+pub type PngEncodeResult = Result<Vec<u8>, String>;
+
+/// The most image data that is written in a single IDAT chunk.
+const MAX_IDAT_SIZE: usize = 0x100000;
+
+/// Appends a chunk with its length, type, data and CRC.
+pub fn write_chunk(out: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+    helpers::write_unsigned_int(out, data.len() as u32);
+
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    helpers::write_unsigned_int(out, crc.sum());
+}
+
+/// Encodes the pixels of a PNG in the format given by the options, or
+/// the smallest one that stores them exactly, along with its metadata.
+pub fn encode(png: &PngFile, options: &EncodeOptions) -> PngEncodeResult {
+    try!(check_dimensions(png));
+
+    let format = pixel_format(&png.pixels, options);
+    encode_pixels(png, &png.pixels, &format, options)
+}
+
+/// Reduces the pixels of a PNG to a palette and encodes them as an
+/// indexed color image, along with the PNG's metadata.
+pub fn encode_quantized(png: &PngFile, options: &QuantizeOptions) -> PngEncodeResult {
+    try!(check_dimensions(png));
+
+    let quantized = try!(quantize::quantize(&png.pixels, png.w, options));
+    encode_pixels(png, &quantized.pixels, &quantized.pixel_format(), &options.encoding)
+}
+
+/// Encodes the given pixels in the given format, along with the
+/// metadata of the PNG.
+fn encode_pixels(png: &PngFile, pixels: &[Color], format: &PixelFormat, options: &EncodeOptions) -> PngEncodeResult {
+    try!(check_strategy(&options.filter_strategy));
+    try!(check_format(format));
+
+    let mut out = PNG_HEADER.to_vec();
+    try!(write_header(&mut out, png, format));
+
+    write_image_data(&mut out, &try!(compress(pixels, png.w, format, options)));
+    write_chunk(&mut out, b"IEND", &[]);
+
+    Ok(out)
+}
+
+/// Encodes linear light pixels as an sRGB image with 8 or 16-bit
+/// samples, in the format given by the options or the smallest one at
+/// that depth that stores them exactly. Alpha is straight coverage.
+pub fn encode_linear(pixels: &[[f32; 4]], w: usize, h: usize, depth: u8, options: &EncodeOptions) -> PngEncodeResult {
+    if w == 0 || h == 0 {
+        return Err("The image must be at least one pixel wide and high".to_string());
+    }
+
+    if pixels.len() != w * h {
+        return Err("Pixel count does not match the image dimensions".to_string());
+    }
+
+    try!(check_strategy(&options.filter_strategy));
+
+    let (format, rows) = match depth {
+        8 => {
+            let pixels = linear::linear_to_srgb8(pixels);
+            let format = pixel_format(&pixels, options);
+            try!(check_format(&format));
+            let rows = try!(reduce::pack(&pixels, w, &format));
+            (format, rows)
+        },
+        16 => {
+            let pixels = linear::linear_to_srgb16(pixels);
+            let format = match options.pixel_format {
+                Some(ref format) => format.clone(),
+                None => sixteen_bit_format(&pixels)
+            };
+            try!(check_format(&format));
+            let rows = try!(reduce::pack16(&pixels, w, &format));
+            (format, rows)
+        },
+        _ => return Err(format!("Invalid bit depth: {}", depth))
+    };
+
+    let mut out = PNG_HEADER.to_vec();
+    write_ihdr(&mut out, w, h, &format);
+    write_palette(&mut out, &format);
+    write_chunk(&mut out, b"sRGB", &[0]);
+
+    write_image_data(&mut out, &try!(compress_rows(&rows, format.bits_per_pixel(), options)));
+    write_chunk(&mut out, b"IEND", &[]);
+
+    Ok(out)
+}
+
+/// The smallest 16-bit format that stores every pixel exactly.
+fn sixteen_bit_format(pixels: &[Color16]) -> PixelFormat {
+    let grey = pixels.iter().all(|p| p.r == p.g && p.g == p.b);
+    let opaque = pixels.iter().all(|p| p.a == 0xffff);
+    match (grey, opaque) {
+        (true, true) => PixelFormat::Greyscale(16),
+        (true, false) => PixelFormat::GreyscaleWithAlpha(16),
+        (false, true) => PixelFormat::TrueColor(16),
+        (false, false) => PixelFormat::TrueColorWithAlpha(16)
+    }
+}
+
+/// The format the options ask for, or the smallest exact one.
+fn pixel_format(pixels: &[Color], options: &EncodeOptions) -> PixelFormat {
+    match options.pixel_format {
+        Some(ref format) => format.clone(),
+        None => reduce::smallest_format(pixels)
+    }
+}
+
+/// Writes the IHDR chunk and palette for the given format, followed by
+/// the PNG's metadata.
+fn write_header(out: &mut Vec<u8>, png: &PngFile, format: &PixelFormat) -> Result<(), String> {
+    write_ihdr(out, png.w, png.h, format);
+    write_palette(out, format);
+
+    if let Some(dimensions) = png.physical_dimensions() {
+        write_chunk(out, b"pHYs", &dimensions.to_bytes());
+    }
+
+    if let Some(timestamp) = png.last_modified() {
+        write_chunk(out, b"tIME", &timestamp.to_bytes());
+    }
+
+    for text in png.international_text() {
+        write_chunk(out, b"iTXt", &try!(text.to_bytes()));
+    }
+
+    Ok(())
+}
+
+pub fn write_ihdr(out: &mut Vec<u8>, w: usize, h: usize, format: &PixelFormat) {
+    let mut ihdr = IHDR::new();
+    ihdr.width = w;
+    ihdr.height = h;
+    ihdr.bit_depth = format.bit_depth();
+    ihdr.color_type = format.color_type();
+
+    write_chunk(out, b"IHDR", &ihdr.to_bytes());
+}
+
+/// Writes the PLTE chunk of an indexed format, and a tRNS chunk that
+/// stops at the last translucent palette entry.
+pub fn write_palette(out: &mut Vec<u8>, format: &PixelFormat) {
+    let palette = match format.palette() {
+        Some(palette) => palette,
+        None => return
+    };
+
+    let mut colors = Vec::with_capacity(palette.len() * 3);
+    for c in palette {
+        colors.extend_from_slice(&[c.r, c.g, c.b]);
+    }
+    write_chunk(out, b"PLTE", &colors);
+
+    let alpha: Vec<u8> = palette.iter().map(|c| c.a).collect();
+    if let Some(last) = alpha.iter().rposition(|&a| a != 255) {
+        write_chunk(out, b"tRNS", &alpha[..last + 1]);
+    }
+}
+
+/// Packs pixels into the given format, then filters and compresses them.
+pub fn compress(pixels: &[Color], w: usize, format: &PixelFormat, options: &EncodeOptions) -> PngEncodeResult {
+    let rows = try!(reduce::pack(pixels, w, format));
+    compress_rows(&rows, format.bits_per_pixel(), options)
+}
+
+/// Filters and compresses unfiltered scanlines of packed samples.
+pub fn compress_rows(rows: &[Vec<u8>], bits_per_pixel: usize, options: &EncodeOptions) -> PngEncodeResult {
+    let bytes_per_pixel = ::std::cmp::max(1, bits_per_pixel / 8);
+    let row_size = rows.first().map(|row| row.len()).unwrap_or(0) + 1;
+
+    let mut filtered = Vec::with_capacity(row_size * rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        let previous = iif!(i > 0, Some(&rows[i - 1][..]), None);
+        filters::filter_row_with(&options.filter_strategy, row, previous, bytes_per_pixel, &mut filtered);
+    }
+
+    if options.threads > 1 || options.band_rows.is_some() {
+        let threads = ::std::cmp::max(options.threads, 1);
+        let band_rows = options.band_rows.unwrap_or((rows.len() + threads - 1) / threads);
+        if band_rows == 0 {
+            return Err("A band must have at least one row".to_string());
+        }
+
+        deflate::encode_parallel(&filtered, band_rows * row_size, threads, &options.compression)
+    } else {
+        deflate::encode_with(&filtered, &options.compression)
+    }
+}
+
+/// Writes compressed image data as one or more IDAT chunks.
+pub fn write_image_data(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_IDAT_SIZE) {
+        write_chunk(out, b"IDAT", chunk);
+    }
+}
+
+/// Checks that an image has pixels, and as many as its dimensions say.
+fn check_dimensions(png: &PngFile) -> Result<(), String> {
+    if png.w == 0 || png.h == 0 {
+        return Err("The image must be at least one pixel wide and high".to_string());
+    }
+
+    if png.pixels.len() != png.w * png.h {
+        return Err("Pixel count does not match the image dimensions".to_string());
+    }
+
+    Ok(())
+}
+
+/// Checks that a fixed filter type is one PNG defines.
+pub fn check_strategy(strategy: &FilterStrategy) -> Result<(), String> {
+    match *strategy {
+        FilterStrategy::Fixed(filter_type) if filter_type > 4 => Err(format!("Invalid filter type: {}", filter_type)),
+        _ => Ok(())
+    }
+}
+
+/// Checks that a format has a bit depth PNG allows for its color type,
+/// and that an indexed format's palette fits that bit depth.
+fn check_format(format: &PixelFormat) -> Result<(), String> {
+    let depth = format.bit_depth();
+    let valid = match *format {
+        PixelFormat::Greyscale(_) => depth == 1 || depth == 2 || depth == 4 || depth == 8 || depth == 16,
+        PixelFormat::Indexed(_, _) => depth == 1 || depth == 2 || depth == 4 || depth == 8,
+        _ => depth == 8 || depth == 16
+    };
+    if !valid {
+        return Err(format!("Invalid bit depth: {}", depth));
+    }
+
+    match format.palette() {
+        Some(palette) if palette.is_empty() => Err("The palette has no colors".to_string()),
+        Some(palette) if palette.len() > 1 << depth => Err("The palette has too many colors for the bit depth".to_string()),
+        _ => Ok(())
+    }
+}
+
+/// Compresses the pixels of a PNG, in the smallest format that stores
+/// them exactly, with every filter strategy, returning
+/// the size in bytes of the compressed image data for each.
+pub fn compressed_sizes(png: &PngFile) -> Result<Vec<(FilterStrategy, usize)>, String> {
+    try!(check_dimensions(png));
+
+    let mut strategies: Vec<FilterStrategy> = (0..5).map(FilterStrategy::Fixed).collect();
+    strategies.push(FilterStrategy::MinSumAbs);
+    strategies.push(FilterStrategy::Entropy);
+    strategies.push(FilterStrategy::BruteForce);
+
+    let format = reduce::smallest_format(&png.pixels);
+    let mut result = Vec::with_capacity(strategies.len());
+    for strategy in strategies {
+        let mut options = EncodeOptions::new();
+        options.filter_strategy = strategy.clone();
+
+        let size = try!(compress(&png.pixels, png.w, &format, &options)).len();
+        result.push((strategy, size));
+    }
+
+    Ok(result)
+}
+
+/// A frame that has been cropped and compressed, waiting for the
+/// dispose op of its frame control to be decided by the next frame.
+struct EncodedFrame {
+    control: FrameControl,
+    data: Vec<u8>
+}
+
+/// Encodes a sequence of images as an animated PNG. Every frame is
+/// cropped to the region that changed since the previous frame, with
+/// the dispose and blend ops that compress smallest. Every frame is
+/// stored in the same format, which holds the pixels of all of them.
+/// The metadata of the first image is written to the file.
+pub fn encode_animation(frames: &[AnimationFrame], options: &AnimationOptions) -> PngEncodeResult {
+    let first = match frames.first() {
+        Some(frame) => &frame.image,
+        None => return Err("An animation needs at least one frame".to_string())
+    };
+
+    let (w, h) = (first.w, first.h);
+    for frame in frames {
+        if frame.image.w != w || frame.image.h != h {
+            return Err("Every frame must have the same dimensions".to_string());
+        }
+
+        try!(check_dimensions(&frame.image));
+    }
+
+    try!(check_strategy(&options.encoding.filter_strategy));
+
+    let animated = iif!(options.include_default_image, frames, &frames[1..]);
+    if animated.is_empty() {
+        return Err("An animation needs at least one frame besides the default image".to_string());
+    }
+
+    // Every frame shares the header, so the format has to hold the
+    // pixels of all of them
+    let formats = match options.encoding.pixel_format {
+        Some(ref format) => {
+            try!(check_format(format));
+            vec![format.clone()]
+        },
+        None => {
+            let pixels = || frames.iter().flat_map(|frame| frame.image.pixels.iter());
+            let format = reduce::analyze(pixels());
+
+            // Over frames fill the pixels that don't change with clear
+            // pixels, which the smallest format may not hold, so also
+            // try a format that does
+            let clear = Color::new(0, 0, 0, 0);
+            let with_clear = reduce::analyze(pixels().chain(iter::once(&clear)));
+            iif!(animated.len() > 1 && with_clear != format, vec![format, with_clear], vec![format])
+        }
+    };
+
+    let mut best: Option<Vec<u8>> = None;
+    for format in formats {
+        let data = try!(encode_frames(first, animated, &format, options));
+        if best.as_ref().map_or(true, |b| data.len() < b.len()) {
+            best = Some(data);
+        }
+    }
+
+    Ok(best.unwrap())
+}
+
+/// Encodes the frames of an animation in the given format.
+fn encode_frames(first: &PngFile, animated: &[AnimationFrame], format: &PixelFormat, options: &AnimationOptions) -> PngEncodeResult {
+    let (w, h) = (first.w, first.h);
+    let mut encoded: Vec<EncodedFrame> = Vec::with_capacity(animated.len());
+
+    // The canvas as it was before the previous frame was drawn, for
+    // APNG_DISPOSE_OP_PREVIOUS
+    let mut before_previous = vec![Color::new(0, 0, 0, 0); w * h];
+
+    for (i, frame) in animated.iter().enumerate() {
+        if i == 0 {
+            // The first frame covers the whole canvas
+            encoded.push(EncodedFrame {
+                control: frame_control(w, h, 0, 0, frame, BlendOp::Source),
+                data: try!(compress(&frame.image.pixels, w, format, &options.encoding))
+            });
+            continue;
+        }
+
+        let previous = &animated[i - 1].image.pixels;
+        let previous_control = encoded[i - 1].control.clone();
+
+        // Try every way of disposing of the previous frame, and keep
+        // whichever leaves the smallest frame to encode
+        let mut disposals = vec![DisposeOp::None, DisposeOp::Background];
+        if i > 1 {
+            disposals.push(DisposeOp::Previous);
+        }
+
+        let mut best: Option<(DisposeOp, EncodedFrame, Vec<Color>)> = None;
+        for dispose_op in disposals {
+            let canvas = match dispose_op {
+                DisposeOp::None => previous.clone(),
+                DisposeOp::Background => {
+                    let mut canvas = previous.clone();
+                    clear_region(&mut canvas, w, &previous_control);
+                    canvas
+                },
+                DisposeOp::Previous => before_previous.clone()
+            };
+
+            let candidate = try!(encode_difference(&canvas, &frame.image.pixels, w, h, frame, format, &options.encoding));
+            let smaller = match best {
+                Some((_, ref current, _)) => candidate.data.len() < current.data.len(),
+                None => true
+            };
+
+            if smaller {
+                best = Some((dispose_op, candidate, canvas));
+            }
+        }
+
+        let (dispose_op, candidate, canvas) = best.unwrap();
+        encoded[i - 1].control.dispose_op = dispose_op;
+        encoded.push(candidate);
+        before_previous = canvas;
+    }
+
+    let mut out = PNG_HEADER.to_vec();
+    try!(write_header(&mut out, first, format));
+
+    let control = AnimationControl {
+        num_frames: encoded.len() as u32,
+        num_plays: options.num_plays
+    };
+    write_chunk(&mut out, b"acTL", &control.to_bytes());
+
+    // fcTL and fdAT chunks share a single sequence
+    let mut sequence_number = 0;
+    if !options.include_default_image {
+        write_image_data(&mut out, &try!(compress(&first.pixels, w, format, &options.encoding)));
+    }
+
+    for (i, frame) in encoded.iter_mut().enumerate() {
+        frame.control.sequence_number = sequence_number;
+        write_chunk(&mut out, b"fcTL", &frame.control.to_bytes());
+        sequence_number += 1;
+
+        if i == 0 && options.include_default_image {
+            write_image_data(&mut out, &frame.data);
+        } else {
+            let mut data = Vec::with_capacity(frame.data.len() + 4);
+            helpers::write_unsigned_int(&mut data, sequence_number);
+            data.extend_from_slice(&frame.data);
+            write_chunk(&mut out, b"fdAT", &data);
+            sequence_number += 1;
+        }
+    }
+
+    write_chunk(&mut out, b"IEND", &[]);
+
+    Ok(out)
+}
+
+fn frame_control(w: usize, h: usize, x: usize, y: usize, frame: &AnimationFrame, blend_op: BlendOp) -> FrameControl {
+    FrameControl {
+        sequence_number: 0,
+        width: w,
+        height: h,
+        x_offset: x,
+        y_offset: y,
+        delay_num: frame.delay_num,
+        delay_den: frame.delay_den,
+        dispose_op: DisposeOp::None,
+        blend_op: blend_op
+    }
+}
+
+fn clear_region(canvas: &mut [Color], canvas_width: usize, control: &FrameControl) {
+    for y in control.y_offset..control.y_offset + control.height {
+        for x in control.x_offset..control.x_offset + control.width {
+            canvas[y * canvas_width + x] = Color::new(0, 0, 0, 0);
+        }
+    }
+}
+
+/// Crops a frame to the bounding box of the pixels that differ from
+/// the canvas, and compresses it with whichever blend op is smaller.
+fn encode_difference(canvas: &[Color], pixels: &[Color], w: usize, h: usize, frame: &AnimationFrame, format: &PixelFormat, options: &EncodeOptions) -> Result<EncodedFrame, String> {
+    let (mut left, mut top, mut right, mut bottom) = (w, h, 0, 0);
+    for y in 0..h {
+        for x in 0..w {
+            if canvas[y * w + x] != pixels[y * w + x] {
+                left = iif!(x < left, x, left);
+                top = iif!(y < top, y, top);
+                right = iif!(x + 1 > right, x + 1, right);
+                bottom = iif!(y + 1 > bottom, y + 1, bottom);
+            }
+        }
+    }
+
+    // Frames can't be empty, so an unchanged frame redraws one pixel
+    if right == 0 {
+        left = 0;
+        top = 0;
+        right = 1;
+        bottom = 1;
+    }
+
+    let (width, height) = (right - left, bottom - top);
+    let mut source = Vec::with_capacity(width * height);
+    let mut over = Vec::with_capacity(width * height);
+    let mut can_blend_over = true;
+
+    for y in top..bottom {
+        for x in left..right {
+            let old = &canvas[y * w + x];
+            let new = &pixels[y * w + x];
+            source.push(new.clone());
+
+            // Compositing over the canvas only reproduces the frame
+            // when the new pixel is opaque or the old one is clear
+            if old == new {
+                over.push(iif!(old.a == 0, old.clone(), Color::new(0, 0, 0, 0)));
+            } else if new.a == 255 || old.a == 0 {
+                over.push(new.clone());
+            } else {
+                can_blend_over = false;
+            }
+        }
+    }
+
+    let mut result = EncodedFrame {
+        control: frame_control(width, height, left, top, frame, BlendOp::Source),
+        data: try!(compress(&source, width, format, options))
+    };
+
+    // The clear pixels of an Over frame may not fit the format, in
+    // which case the frame is only drawn with Source
+    if can_blend_over {
+        if let Ok(data) = compress(&over, width, format, options) {
+            if data.len() < result.data.len() {
+                result.control.blend_op = BlendOp::Over;
+                result.data = data;
+            }
+        }
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,319 @@
+pub type ExifParseResult = Result<Exif, String>;
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+
+/// How an image must be transformed to be shown upright, from the
+/// EXIF Orientation tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Orientation {
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270
+}
+
+impl Orientation {
+    pub fn from_tag(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None
+        }
+    }
+
+    /// Whether the transform swaps the width and height of the image.
+    pub fn swaps_dimensions(&self) -> bool {
+        match *self {
+            Orientation::Transpose | Orientation::Rotate90 |
+            Orientation::Transverse | Orientation::Rotate270 => true,
+            _ => false
+        }
+    }
+
+    /// Transforms the row-major pixels of a w by h image so that it
+    /// is shown upright, returning the pixels with the new width
+    /// and height.
+    pub fn apply<T: Clone>(&self, pixels: &[T], w: usize, h: usize) -> (Vec<T>, usize, usize) {
+        let (new_w, new_h) = iif!(self.swaps_dimensions(), (h, w), (w, h));
+        let mut result = pixels.to_vec();
+
+        for y in 0..h {
+            for x in 0..w {
+                let (dx, dy) = match *self {
+                    Orientation::Normal => (x, y),
+                    Orientation::FlipHorizontal => (w - 1 - x, y),
+                    Orientation::Rotate180 => (w - 1 - x, h - 1 - y),
+                    Orientation::FlipVertical => (x, h - 1 - y),
+                    Orientation::Transpose => (y, x),
+                    Orientation::Rotate90 => (h - 1 - y, x),
+                    Orientation::Transverse => (h - 1 - y, w - 1 - x),
+                    Orientation::Rotate270 => (y, w - 1 - x)
+                };
+
+                result[dy * new_w + dx] = pixels[y * w + x].clone();
+            }
+        }
+
+        (result, new_w, new_h)
+    }
+}
+
+/// A single entry of an image file directory.
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+
+    // The offset of the entry's value, or the value itself when
+    // it fits within four bytes
+    value_offset: usize
+}
+
+/// EXIF metadata from an eXIf chunk. The chunk holds a TIFF
+/// structure, of which the tags in the first image file directory
+/// (IFD0) are read.
+pub struct Exif {
+    data: Vec<u8>,
+    little_endian: bool,
+    entries: Vec<Entry>
+}
+
+impl Exif {
+    /// The raw EXIF data.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.short(TAG_ORIENTATION).and_then(Orientation::from_tag)
+    }
+
+    pub fn date_time(&self) -> Option<String> {
+        self.ascii(TAG_DATE_TIME)
+    }
+
+    pub fn make(&self) -> Option<String> {
+        self.ascii(TAG_MAKE)
+    }
+
+    pub fn model(&self) -> Option<String> {
+        self.ascii(TAG_MODEL)
+    }
+
+    fn entry(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+
+    fn short(&self, tag: u16) -> Option<u16> {
+        match self.entry(tag) {
+            Some(entry) if entry.field_type == TYPE_SHORT && entry.count >= 1 => {
+                Some(read_u16(&self.data[entry.value_offset..], self.little_endian))
+            },
+            _ => None
+        }
+    }
+
+    fn ascii(&self, tag: u16) -> Option<String> {
+        match self.entry(tag) {
+            Some(entry) if entry.field_type == TYPE_ASCII => {
+                let bytes = &self.data[entry.value_offset..entry.value_offset + entry.count as usize];
+                let text = bytes.split(|&b| b == 0).next().unwrap_or(&[]);
+                Some(String::from_utf8_lossy(text).into_owned())
+            },
+            _ => None
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], little_endian: bool) -> u16 {
+    if little_endian {
+        buf[0] as u16 | (buf[1] as u16) << 8
+    } else {
+        (buf[0] as u16) << 8 | buf[1] as u16
+    }
+}
+
+fn read_u32(buf: &[u8], little_endian: bool) -> u32 {
+    if little_endian {
+        read_u16(buf, true) as u32 | (read_u16(&buf[2..], true) as u32) << 16
+    } else {
+        (read_u16(buf, false) as u32) << 16 | read_u16(&buf[2..], false) as u32
+    }
+}
+
+/// The size in bytes of a single value of a TIFF field type.
+fn type_size(field_type: u16) -> usize {
+    match field_type {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0
+    }
+}
+
+pub fn parse(data: &[u8]) -> ExifParseResult {
+    if data.len() < 8 {
+        return Err("eXIf chunk is too short".to_string());
+    }
+
+    let little_endian = match &data[0..4] {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return Err("eXIf chunk does not start with a TIFF header".to_string())
+    };
+
+    let ifd = read_u32(&data[0x04..], little_endian) as usize;
+    if ifd < 8 || ifd + 2 > data.len() {
+        return Err("eXIf IFD0 offset out of range".to_string());
+    }
+
+    let count = read_u16(&data[ifd..], little_endian) as usize;
+    if ifd + 2 + count * 12 > data.len() {
+        return Err("eXIf IFD0 entries out of range".to_string());
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let start = ifd + 2 + i * 12;
+        let field_type = read_u16(&data[start + 0x02..], little_endian);
+        let count = read_u32(&data[start + 0x04..], little_endian);
+
+        // Values of four bytes or less are stored in the entry
+        // itself rather than at an offset
+        let size = type_size(field_type) * count as usize;
+        let value_offset = if size <= 4 {
+            start + 0x08
+        } else {
+            read_u32(&data[start + 0x08..], little_endian) as usize
+        };
+
+        if value_offset + size > data.len() {
+            return Err("eXIf tag value out of range".to_string());
+        }
+
+        entries.push(Entry {
+            tag: read_u16(&data[start..], little_endian),
+            field_type: field_type,
+            count: count,
+            value_offset: value_offset
+        });
+    }
+
+    Ok(Exif {
+        data: data.to_vec(),
+        little_endian: little_endian,
+        entries: entries
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helpers;
+    use options::DecodeOptions;
+    use png::PngFile;
+
+    /// Builds a TIFF structure with an Orientation tag and a Make tag,
+    /// whose value is stored at an offset past IFD0.
+    fn exif_fixture(orientation: u16, little_endian: bool) -> Vec<u8> {
+        let u16_bytes = |v: u16| iif!(little_endian, [v as u8, (v >> 8) as u8], [(v >> 8) as u8, v as u8]);
+        let u32_bytes = |v: u32| {
+            let (high, low) = (u16_bytes((v >> 16) as u16), u16_bytes(v as u16));
+            iif!(little_endian, [low[0], low[1], high[0], high[1]], [high[0], high[1], low[0], low[1]])
+        };
+
+        let mut data = iif!(little_endian, b"II*\0".to_vec(), b"MM\0*".to_vec());
+        data.extend_from_slice(&u32_bytes(8));
+        data.extend_from_slice(&u16_bytes(2));
+
+        data.extend_from_slice(&u16_bytes(0x010F));
+        data.extend_from_slice(&u16_bytes(2));
+        data.extend_from_slice(&u32_bytes(6));
+        data.extend_from_slice(&u32_bytes(8 + 2 + 2 * 12 + 4));
+
+        data.extend_from_slice(&u16_bytes(0x0112));
+        data.extend_from_slice(&u16_bytes(3));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u16_bytes(orientation));
+        data.extend_from_slice(&[0, 0]);
+
+        data.extend_from_slice(&u32_bytes(0));
+        data.extend_from_slice(b"rpng!\0");
+        data
+    }
+
+    #[test]
+    fn orientation_is_applied() {
+        // Labels 0-5 laid out as 3x2, and how each orientation shows them
+        let expected: [(usize, [u8; 6]); 8] = [
+            (3, [0, 1, 2, 3, 4, 5]),
+            (3, [2, 1, 0, 5, 4, 3]),
+            (3, [5, 4, 3, 2, 1, 0]),
+            (3, [3, 4, 5, 0, 1, 2]),
+            (2, [0, 3, 1, 4, 2, 5]),
+            (2, [3, 0, 4, 1, 5, 2]),
+            (2, [5, 2, 4, 1, 3, 0]),
+            (2, [2, 5, 1, 4, 0, 3])
+        ];
+
+        let raw = [0, 0, 1, 2, 0, 3, 4, 5];
+
+        let mut options = DecodeOptions::new();
+        options.apply_orientation = true;
+
+        for (i, &(w, ref labels)) in expected.iter().enumerate() {
+            let orientation = i as u16 + 1;
+            let data = helpers::png_file(3, 2, 8, 0, &[
+                (b"eXIf", exif_fixture(orientation, i % 2 == 0)),
+                (b"IDAT", helpers::image_data(&raw))
+            ]);
+
+            let decoded = PngFile::from_data_with_options(&data, &options).unwrap();
+            let exif = decoded.exif().unwrap();
+            assert_eq!(exif.orientation(), Orientation::from_tag(orientation));
+            assert_eq!(exif.make(), Some("rpng!".to_string()));
+
+            assert_eq!((decoded.w, decoded.h), (w, 6 / w));
+            let shown: Vec<u8> = decoded.pixels.iter().map(|p| p.r).collect();
+            assert_eq!(&shown[..], &labels[..]);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_offsets() {
+        let data = exif_fixture(6, true);
+        assert!(parse(&data).is_ok());
+
+        // Cut into the entries, the Make value and the header
+        for &length in &[8 + 2 + 12, data.len() - 1, 9, 4] {
+            assert!(parse(&data[..length]).is_err());
+        }
+
+        // An IFD0 offset past the end of the data
+        let mut moved = data.clone();
+        moved[4] = 200;
+        assert!(parse(&moved).is_err());
+
+        // An entry count larger than the entries that follow
+        let mut counted = data.clone();
+        counted[8] = 40;
+        assert!(parse(&counted).is_err());
+    }
+}
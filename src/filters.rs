@@ -1,8 +1,15 @@
 
-use png::PngFile;
+use deflate;
+use options::FilterStrategy;
 
+/// A scanline filter. `a`, `b` and `c` are the bytes to the left,
+/// above, and above and to the left of `x`.
 pub trait Filter {
+    /// Reconstructs a filtered byte.
     fn apply(&self, x: u16, a: u16, b: u16, c: u16) -> u8;
+
+    /// Filters a raw byte.
+    fn filter(&self, x: u16, a: u16, b: u16, c: u16) -> u8;
 }
 
 pub struct NoFilter;
@@ -11,6 +18,10 @@ impl Filter for NoFilter {
         // No op
         x as u8
     }
+
+    fn filter(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
+        x as u8
+    }
 }
 
 pub struct Sub;
@@ -18,6 +29,10 @@ impl Filter for Sub {
     fn apply(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
         (x + a) as u8
     }
+
+    fn filter(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
+        x.wrapping_sub(a) as u8
+    }
 }
 
 pub struct Up;
@@ -25,6 +40,10 @@ impl Filter for Up {
     fn apply(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
         (x + b) as u8
     }
+
+    fn filter(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
+        x.wrapping_sub(b) as u8
+    }
 }
 
 pub struct Average;
@@ -32,24 +51,144 @@ impl Filter for Average {
     fn apply(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
         (x + ((a + b) / 2)) as u8
     }
+
+    fn filter(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
+        x.wrapping_sub((a + b) / 2) as u8
+    }
 }
 
 pub struct Paeth;
 impl Filter for Paeth {
     fn apply(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
-        let (a, b, c) = (a as i32, b as i32, c as i32);
-        let p: i32 = a + b - c;
-        let pa = (p - a).abs();
-        let pb = (p - b).abs();
-        let pc = (p - c).abs();
-        if pa <= pb && pa <= pc {
-            ((x as i32 + a as i32) % 256) as u8
-        } else if pb <= pc {
-            ((x as i32 + b as i32) % 256) as u8
-        } else {
-            ((x as i32 + c as i32) % 256) as u8
-        }
+        (x + paeth_predictor(a, b, c)) as u8
+    }
 
+    fn filter(&self, x: u16, a: u16, b: u16, c: u16) -> u8 {
+        x.wrapping_sub(paeth_predictor(a, b, c)) as u8
     }
 }
 
+/// Picks whichever of the left, above or upper left bytes is closest
+/// to `a + b - c`.
+fn paeth_predictor(a: u16, b: u16, c: u16) -> u16 {
+    let (a, b, c) = (a as i32, b as i32, c as i32);
+    let p: i32 = a + b - c;
+    let pa = (p - a).abs();
+    let pb = (p - b).abs();
+    let pc = (p - c).abs();
+    if pa <= pb && pa <= pc {
+        a as u16
+    } else if pb <= pc {
+        b as u16
+    } else {
+        c as u16
+    }
+}
+
+/// Picks the filter for the filter type byte at the start of
+/// a scanline.
+pub fn from_type(filter_type: u8) -> Option<Box<Filter>> {
+    match filter_type {
+        0 => Some(Box::new(NoFilter)),
+        1 => Some(Box::new(Sub)),
+        2 => Some(Box::new(Up)),
+        3 => Some(Box::new(Average)),
+        4 => Some(Box::new(Paeth)),
+        _ => None
+    }
+}
+
+/// Reverses the filtering of a single scanline in place. `row` holds the
+/// scanline without its filter type byte, and `previous` the already
+/// unfiltered scanline above it, if there is one.
+pub fn unfilter_row(filter_type: u8, row: &mut [u8], previous: Option<&[u8]>, bytes_per_pixel: usize) -> Result<(), String> {
+    let filter = match from_type(filter_type) {
+        Some(filter) => filter,
+        None => return Err(format!("Invalid filter type: {}", filter_type))
+    };
+
+    for i in 0..row.len() {
+        let x_okay = i >= bytes_per_pixel;
+        let a = iif!(x_okay, row[i - bytes_per_pixel] as u16, 0);
+        let (b, c) = match previous {
+            Some(previous) => (previous[i] as u16, iif!(x_okay, previous[i - bytes_per_pixel] as u16, 0)),
+            None => (0, 0)
+        };
+
+        row[i] = filter.apply(row[i] as u16, a, b, c);
+    }
+
+    Ok(())
+}
+
+/// Filters a single scanline with the given filter type, appending the
+/// filter type byte and the filtered bytes to `out`. `previous` is the
+/// raw scanline above, if there is one.
+pub fn filter_row(filter_type: u8, row: &[u8], previous: Option<&[u8]>, bytes_per_pixel: usize, out: &mut Vec<u8>) {
+    let filter = from_type(filter_type).expect("Invalid filter type");
+
+    out.push(filter_type);
+    for i in 0..row.len() {
+        let x_okay = i >= bytes_per_pixel;
+        let a = iif!(x_okay, row[i - bytes_per_pixel] as u16, 0);
+        let (b, c) = match previous {
+            Some(previous) => (previous[i] as u16, iif!(x_okay, previous[i - bytes_per_pixel] as u16, 0)),
+            None => (0, 0)
+        };
+
+        out.push(filter.filter(row[i] as u16, a, b, c));
+    }
+}
+
+/// Filters a single scanline with the filter that the strategy picks,
+/// appending the filter type byte and the filtered bytes to `out`.
+pub fn filter_row_with(strategy: &FilterStrategy, row: &[u8], previous: Option<&[u8]>, bytes_per_pixel: usize, out: &mut Vec<u8>) {
+    if let FilterStrategy::Fixed(filter_type) = *strategy {
+        return filter_row(filter_type, row, previous, bytes_per_pixel, out);
+    }
+
+    let mut best: Option<(u64, Vec<u8>)> = None;
+    for filter_type in 0..5 {
+        let mut candidate = Vec::with_capacity(row.len() + 1);
+        filter_row(filter_type, row, previous, bytes_per_pixel, &mut candidate);
+
+        let cost = match *strategy {
+            FilterStrategy::MinSumAbs => sum_abs(&candidate[1..]),
+            FilterStrategy::Entropy => entropy(&candidate[1..]),
+            _ => deflate::encode(&candidate).map(|c| c.len() as u64).unwrap_or(u64::max_value())
+        };
+
+        let better = match best {
+            Some((best_cost, _)) => cost < best_cost,
+            None => true
+        };
+
+        if better {
+            best = Some((cost, candidate));
+        }
+    }
+
+    out.extend(best.unwrap().1);
+}
+
+/// The sum of the filtered bytes taken as signed differences.
+fn sum_abs(row: &[u8]) -> u64 {
+    row.iter().map(|&v| (v as i8 as i32).abs() as u64).sum()
+}
+
+/// The Shannon entropy of the filtered bytes, in thousandths of a bit
+/// per byte so that rows compare as integers.
+fn entropy(row: &[u8]) -> u64 {
+    let mut counts = [0usize; 256];
+    for &v in row {
+        counts[v as usize] += 1;
+    }
+
+    let total = row.len() as f64;
+    let bits: f64 = counts.iter().filter(|&&n| n > 0).map(|&n| {
+        let p = n as f64 / total;
+        -p * p.log2()
+    }).sum();
+
+    (bits * 1000.0) as u64
+}
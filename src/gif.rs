@@ -0,0 +1,60 @@
+use helpers;
+
+pub type GifGraphicControlParseResult = Result<GifGraphicControl, String>;
+pub type GifApplicationParseResult = Result<GifApplication, String>;
+
+/// The GIF Graphic Control Extension of an image converted from
+/// GIF, from a gIFg chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GifGraphicControl {
+    pub disposal_method: u8,
+    pub user_input: bool,
+    /// The delay in hundredths of a second.
+    pub delay_time: u16
+}
+
+/// A GIF Application Extension of an image converted from GIF,
+/// from a gIFx chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GifApplication {
+    pub identifier: [u8; 8],
+    pub authentication_code: [u8; 3],
+    pub data: Vec<u8>
+}
+
+pub fn parse_gifg(data: &[u8]) -> GifGraphicControlParseResult {
+    if data.len() != 4 {
+        return Err("Invalid gIFg length".to_string());
+    }
+
+    if data[0] > 7 {
+        return Err(format!("Invalid gIFg disposal method: {}", data[0]));
+    }
+
+    if data[1] > 1 {
+        return Err(format!("Invalid gIFg user input flag: {}", data[1]));
+    }
+
+    Ok(GifGraphicControl {
+        disposal_method: data[0],
+        user_input: data[1] == 1,
+        delay_time: helpers::read_unsigned_short(&data[0x02..])
+    })
+}
+
+pub fn parse_gifx(data: &[u8]) -> GifApplicationParseResult {
+    if data.len() < 11 {
+        return Err("gIFx chunk is too short".to_string());
+    }
+
+    let mut identifier = [0; 8];
+    let mut authentication_code = [0; 3];
+    identifier.copy_from_slice(&data[0x00..0x08]);
+    authentication_code.copy_from_slice(&data[0x08..0x0B]);
+
+    Ok(GifApplication {
+        identifier: identifier,
+        authentication_code: authentication_code,
+        data: data[0x0B..].to_vec()
+    })
+}
@@ -0,0 +1,134 @@
+use chrm::Chromaticities;
+use helpers;
+
+pub type CodePointsParseResult = Result<CodingIndependentCodePoints, String>;
+pub type MasteringDisplayParseResult = Result<MasteringDisplayColorVolume, String>;
+pub type ContentLightLevelParseResult = Result<ContentLightLevel, String>;
+
+/// The ITU-T H.273 transfer characteristics for SMPTE ST 2084 (PQ).
+pub const TRANSFER_PQ: u8 = 16;
+
+/// The ITU-T H.273 transfer characteristics for ARIB STD-B67 (HLG).
+pub const TRANSFER_HLG: u8 = 18;
+
+/// Video signal type code points from a cICP chunk, as defined by ITU-T H.273.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodingIndependentCodePoints {
+    pub color_primaries: u8,
+    pub transfer_function: u8,
+    pub matrix_coefficients: u8,
+    pub full_range: bool
+}
+
+impl CodingIndependentCodePoints {
+    /// Whether the samples are encoded with the PQ transfer function.
+    pub fn is_pq(&self) -> bool {
+        self.transfer_function == TRANSFER_PQ
+    }
+
+    /// Whether the samples are encoded with the HLG transfer function.
+    pub fn is_hlg(&self) -> bool {
+        self.transfer_function == TRANSFER_HLG
+    }
+
+    /// The primaries named by the color primaries code point, for
+    /// the code points that this crate knows about.
+    pub fn chromaticities(&self) -> Option<Chromaticities> {
+        match self.color_primaries {
+            1 => Some(Chromaticities::srgb()),
+            9 => Some(Chromaticities::bt2020()),
+            12 => Some(Chromaticities::display_p3()),
+            _ => None
+        }
+    }
+}
+
+/// The color volume of the display an image was mastered on, from
+/// an mDCV chunk. Luminances are in candelas per square metre.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MasteringDisplayColorVolume {
+    pub chromaticities: Chromaticities,
+    pub max_luminance: f64,
+    pub min_luminance: f64
+}
+
+/// The light level of an image's content from a cLLI chunk, in
+/// candelas per square metre.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentLightLevel {
+    pub max_content_light_level: f64,
+    pub max_frame_average_light_level: f64
+}
+
+pub fn parse_cicp(data: &[u8]) -> CodePointsParseResult {
+    if data.len() != 4 {
+        return Err("Invalid cICP length".to_string());
+    }
+
+    // PNG only stores RGB samples, so the matrix coefficients
+    // must be the identity
+    if data[2] != 0 {
+        return Err("cICP matrix coefficients must be 0 for PNG images".to_string());
+    }
+
+    if data[3] > 1 {
+        return Err("Invalid cICP full range flag".to_string());
+    }
+
+    Ok(CodingIndependentCodePoints {
+        color_primaries: data[0],
+        transfer_function: data[1],
+        matrix_coefficients: data[2],
+        full_range: data[3] == 1
+    })
+}
+
+pub fn parse_mdcv(data: &[u8]) -> MasteringDisplayParseResult {
+    if data.len() != 24 {
+        return Err("Invalid mDCV length".to_string());
+    }
+
+    // Chromaticities are stored in units of 0.00002
+    let point = |offset: usize| -> (f64, f64) {
+        (
+            helpers::read_unsigned_short(&data[offset..]) as f64 * 0.00002,
+            helpers::read_unsigned_short(&data[offset + 0x02..]) as f64 * 0.00002
+        )
+    };
+
+    let volume = MasteringDisplayColorVolume {
+        chromaticities: Chromaticities {
+            red: point(0x00),
+            green: point(0x04),
+            blue: point(0x08),
+            white: point(0x0C)
+        },
+        max_luminance: helpers::read_unsigned_int(&data[0x10..]) as f64 * 0.0001,
+        min_luminance: helpers::read_unsigned_int(&data[0x14..]) as f64 * 0.0001
+    };
+
+    if volume.max_luminance != 0.0 && volume.min_luminance >= volume.max_luminance {
+        return Err("mDCV minimum luminance must be below its maximum".to_string());
+    }
+
+    Ok(volume)
+}
+
+pub fn parse_clli(data: &[u8]) -> ContentLightLevelParseResult {
+    if data.len() != 8 {
+        return Err("Invalid cLLI length".to_string());
+    }
+
+    let level = ContentLightLevel {
+        max_content_light_level: helpers::read_unsigned_int(&data[0x00..]) as f64 * 0.0001,
+        max_frame_average_light_level: helpers::read_unsigned_int(&data[0x04..]) as f64 * 0.0001
+    };
+
+    // Zero marks either level as unknown
+    if level.max_content_light_level != 0.0 &&
+       level.max_frame_average_light_level > level.max_content_light_level {
+        return Err("cLLI frame average light level exceeds the content light level".to_string());
+    }
+
+    Ok(level)
+}
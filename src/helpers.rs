@@ -19,3 +19,91 @@ pub fn read_unsigned_int(buf: &[u8]) -> u32 {
     (buf[1] as u32) << 16 |
     (buf[0] as u32) << 24
 }
+
+pub fn write_unsigned_int(buf: &mut Vec<u8>, value: u32) {
+    buf.push((value >> 24) as u8);
+    buf.push((value >> 16) as u8);
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+pub fn read_unsigned_short(buf: &[u8]) -> u16 {
+    buf[1] as u16 |
+    (buf[0] as u16) << 8
+}
+
+/// Rescales a sample from one bit depth to another, rounding
+/// to the nearest representable value.
+pub fn rescale(sample: u16, from_bits: u8, to_bits: u8) -> u16 {
+    if from_bits == to_bits {
+        return sample;
+    }
+
+    let from_max = (1u32 << from_bits) - 1;
+    let to_max = (1u32 << to_bits) - 1;
+
+    ((sample as u32 * to_max + from_max / 2) / from_max) as u16
+}
+
+/// Converts a 16-bit sample to 8 bits, rounding to the nearest value.
+#[inline(always)]
+pub fn sixteen_to_eight(sample: u16) -> u8 {
+    ((sample as u32 * 255 + 32895) >> 16) as u8
+}
+
+/// Parses an ASCII floating-point number as used by the pCAL and
+/// sCAL chunks, which do not allow infinities or NaN.
+pub fn parse_ascii_float(data: &[u8]) -> Result<f64, String> {
+    let valid = !data.is_empty() && data.iter().all(|&b| {
+        (b >= b'0' && b <= b'9') || b == b'.' || b == b'+' || b == b'-' || b == b'e' || b == b'E'
+    });
+
+    let text = String::from_utf8_lossy(data);
+    match text.parse::<f64>() {
+        Ok(value) if valid => Ok(value),
+        _ => Err(format!("Invalid floating-point value: {}", text))
+    }
+}
+
+/// Builds a PNG file from the fields of its IHDR chunk and the chunks
+/// that follow it, for tests that need files the encoder won't write.
+/// Each chunk is a type and its data, and IEND is added at the end.
+#[cfg(test)]
+pub fn png_file(w: u32, h: u32, bit_depth: u8, color_type: u8, chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    use encoder;
+    use png;
+
+    let mut ihdr = Vec::new();
+    write_unsigned_int(&mut ihdr, w);
+    write_unsigned_int(&mut ihdr, h);
+    ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+    let mut data = png::PNG_HEADER.to_vec();
+    encoder::write_chunk(&mut data, b"IHDR", &ihdr);
+    for &(chunk_type, ref chunk_data) in chunks {
+        encoder::write_chunk(&mut data, chunk_type, chunk_data);
+    }
+    encoder::write_chunk(&mut data, b"IEND", &[]);
+    data
+}
+
+/// Compresses unfiltered scanlines, each starting with its filter type
+/// byte, into the data of an IDAT chunk.
+#[cfg(test)]
+pub fn image_data(raw_rows: &[u8]) -> Vec<u8> {
+    use deflate;
+
+    deflate::encode(raw_rows).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sixteen_to_eight_rounds_to_nearest() {
+        for v in 0..65536u32 {
+            assert_eq!(sixteen_to_eight(v as u16) as u32, (v * 255 + 32767) / 65535);
+        }
+    }
+}
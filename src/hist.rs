@@ -0,0 +1,33 @@
+use helpers;
+
+pub type HistogramParseResult = Result<Vec<u16>, String>;
+
+/// Parses the approximate usage frequency of each palette entry
+/// from a hIST chunk.
+pub fn parse(data: &[u8], palette_len: usize) -> HistogramParseResult {
+    if palette_len == 0 {
+        return Err("hIST chunk found before PLTE chunk".to_string());
+    }
+
+    if data.len() != palette_len * 2 {
+        return Err("hIST chunk must have one entry per palette entry".to_string());
+    }
+
+    Ok(data.chunks(2).map(|entry| helpers::read_unsigned_short(entry)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn needs_one_entry_per_palette_entry() {
+        let data = [0, 1, 0x12, 0x34, 0xff, 0xff];
+        assert_eq!(parse(&data, 3).unwrap(), vec![1, 0x1234, 0xffff]);
+
+        assert!(parse(&data, 0).is_err());
+        assert!(parse(&data, 2).is_err());
+        assert!(parse(&data, 4).is_err());
+        assert!(parse(&data[..5], 3).is_err());
+    }
+}
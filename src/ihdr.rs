@@ -29,6 +29,20 @@ impl IHDR {
             crc: 0
         }
     }
+
+    /// Serializes the header as IHDR chunk data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(13);
+        helpers::write_unsigned_int(&mut result, self.width as u32);
+        helpers::write_unsigned_int(&mut result, self.height as u32);
+        result.push(self.bit_depth);
+        result.push(self.color_type.to_byte());
+        result.push(self.compression_method);
+        result.push(self.filter_method);
+        result.push(self.interlace_method);
+
+        result
+    }
 }
 
 pub fn parse(data: &[u8]) -> IHDRParseResult {
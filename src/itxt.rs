@@ -0,0 +1,140 @@
+use deflate;
+
+pub type InternationalTextParseResult = Result<InternationalText, String>;
+
+/// UTF-8 text from an iTXt chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InternationalText {
+    pub keyword: String,
+    pub compressed: bool,
+    pub language_tag: String,
+    pub translated_keyword: String,
+    pub text: String
+}
+
+impl InternationalText {
+    /// Uncompressed text with no language tag or translated keyword.
+    pub fn new(keyword: &str, text: &str) -> Self {
+        InternationalText {
+            keyword: keyword.to_string(),
+            compressed: false,
+            language_tag: String::new(),
+            translated_keyword: String::new(),
+            text: text.to_string()
+        }
+    }
+
+    /// Serializes the text as iTXt chunk data.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        // Keywords are 1-79 printable Latin-1 characters
+        if self.keyword.chars().any(|c| !((c >= ' ' && c <= '~') || (c >= '\u{a1}' && c <= '\u{ff}'))) {
+            return Err("iTXt keyword must only hold printable Latin-1 characters".to_string());
+        }
+
+        let keyword: Vec<u8> = self.keyword.chars().map(|c| c as u8).collect();
+        if keyword.is_empty() || keyword.len() > 79 {
+            return Err("iTXt keyword must be 1-79 bytes long".to_string());
+        }
+
+        // Spaces may only separate words
+        if self.keyword.starts_with(' ') || self.keyword.ends_with(' ') || self.keyword.contains("  ") {
+            return Err("iTXt keyword must not have leading, trailing or consecutive spaces".to_string());
+        }
+
+        let mut result = Vec::new();
+        result.extend_from_slice(&keyword);
+        result.push(0);
+        result.push(iif!(self.compressed, 1, 0));
+        result.push(0);
+        result.extend_from_slice(self.language_tag.as_bytes());
+        result.push(0);
+        result.extend_from_slice(self.translated_keyword.as_bytes());
+        result.push(0);
+
+        if self.compressed {
+            result.extend(try!(deflate::encode(self.text.as_bytes())));
+        } else {
+            result.extend_from_slice(self.text.as_bytes());
+        }
+
+        Ok(result)
+    }
+}
+
+/// Splits a null terminated field off the front of the data.
+fn field(data: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    match data.iter().position(|&b| b == 0) {
+        Some(n) => Ok((&data[..n], &data[n + 1..])),
+        None => Err("iTXt field is not null terminated".to_string())
+    }
+}
+
+fn utf8(data: &[u8]) -> Result<String, String> {
+    String::from_utf8(data.to_vec()).map_err(|_| "iTXt text is not valid UTF-8".to_string())
+}
+
+pub fn parse(data: &[u8]) -> InternationalTextParseResult {
+    let (keyword, rest) = try!(field(data));
+    if keyword.is_empty() || keyword.len() > 79 {
+        return Err("iTXt keyword must be 1-79 bytes long".to_string());
+    }
+
+    if rest.len() < 2 {
+        return Err("iTXt chunk is missing its compression fields".to_string());
+    }
+
+    let compressed = match rest[0] {
+        0 => false,
+        1 => true,
+        n => return Err(format!("Invalid iTXt compression flag: {}", n))
+    };
+
+    if rest[1] != 0 {
+        return Err("Invalid iTXt compression method".to_string());
+    }
+
+    let (language_tag, rest) = try!(field(&rest[2..]));
+    let (translated_keyword, text) = try!(field(rest));
+
+    let text = if compressed {
+        try!(deflate::decode(text, || text.len() * 4))
+    } else {
+        text.to_vec()
+    };
+
+    Ok(InternationalText {
+        // Keywords are Latin-1
+        keyword: keyword.iter().map(|&b| b as char).collect(),
+        compressed: compressed,
+        language_tag: try!(utf8(language_tag)),
+        translated_keyword: try!(utf8(translated_keyword)),
+        text: try!(utf8(&text))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_keywords() {
+        assert!(InternationalText::new("Caf\u{e9}", "text").to_bytes().unwrap().starts_with(b"Caf\xe9\0"));
+        assert!(InternationalText::new(&"k".repeat(79), "text").to_bytes().is_ok());
+        assert!(InternationalText::new("Two words", "text").to_bytes().is_ok());
+
+        for keyword in &["", "a\0b", "\u{65e5}\u{672c}", "tab\there", " lead", "trail ", "two  spaces"] {
+            assert!(InternationalText::new(keyword, "text").to_bytes().is_err());
+        }
+        assert!(InternationalText::new(&"k".repeat(80), "text").to_bytes().is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut text = InternationalText::new("Comment", "caf\u{e9} \u{65e5}\u{672c}");
+        text.language_tag = "ja".to_string();
+        assert_eq!(parse(&text.to_bytes().unwrap()).unwrap(), text);
+
+        text.compressed = true;
+        assert_eq!(parse(&text.to_bytes().unwrap()).unwrap(), text);
+    }
+}
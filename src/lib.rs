@@ -1,20 +1,315 @@
 #[macro_use]
 mod helpers;
 
+mod apng;
+mod bkgd;
+mod chrm;
+mod chunk_type;
 mod color;
 mod color_type;
 mod decoders;
 mod deflate;
+mod dither;
+mod encoder;
 mod error;
+mod exif;
 mod filters;
+mod gif;
+mod hdr;
+mod hist;
 mod ihdr;
+mod itxt;
+mod linear;
+mod offs;
+mod optimize;
+mod options;
+mod pcal;
+mod phys;
 mod png;
+mod quantize;
+mod reduce;
+mod scal;
+mod splt;
+mod srgb;
+mod ster;
+mod timestamp;
+mod trns;
+mod xmp;
+mod zopfli;
 
+pub use self::apng::{AnimationControl, AnimationFrame, FrameControl, DisposeOp, BlendOp, Frame, FrameRef, Frames};
+pub use self::bkgd::Background;
+pub use self::chrm::Chromaticities;
+pub use self::color::{Color, Color16};
+pub use self::encoder::{encode_animation, encode_linear};
+pub use self::exif::{Exif, Orientation};
+pub use self::gif::{GifGraphicControl, GifApplication};
+pub use self::hdr::{CodingIndependentCodePoints, MasteringDisplayColorVolume, ContentLightLevel};
+pub use self::itxt::InternationalText;
+pub use self::linear::{linear_to_srgb8, linear_to_srgb16};
+pub use self::offs::{ImageOffset, OffsetUnit};
+pub use self::optimize::optimize;
+pub use self::options::{AnimationOptions, Compression, DecodeOptions, DepthReduction, EncodeOptions, FilterStrategy, OptimizeOptions, QuantizeOptions};
+pub use self::pcal::{Calibration, Equation};
+pub use self::phys::{PhysicalDimensions, PhysicalUnit};
 pub use self::png::PngFile;
+pub use self::quantize::Quantized;
+pub use self::reduce::{smallest_format, PixelFormat};
+pub use self::scal::{PhysicalScale, ScaleUnit};
+pub use self::splt::{SuggestedPalette, SuggestedPaletteEntry};
+pub use self::srgb::RenderingIntent;
+pub use self::ster::StereoLayout;
+pub use self::timestamp::Timestamp;
+pub use self::trns::Transparency;
+pub use self::xmp::Xmp;
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn encode_round_trip() {
+        let mut png = PngFile::new(2, 2);
+        png.pixels = vec![
+            Color::new(255, 0, 0, 255),
+            Color::new(0, 255, 0, 128),
+            Color::new(0, 0, 255, 0),
+            Color::new(10, 20, 30, 40)
+        ];
+        png.set_physical_dimensions(Some(PhysicalDimensions::from_dpi(300.0, 300.0)));
+
+        let decoded = PngFile::from_data(&png.to_data().unwrap()).unwrap();
+        assert_eq!((decoded.w, decoded.h), (2, 2));
+        assert_eq!(decoded.physical_dimensions(), png.physical_dimensions());
+        for (a, b) in decoded.pixels.iter().zip(png.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+        }
+
+        let empty = PngFile::new(0, 0);
+        assert!(empty.to_data().is_err());
+        assert!(encode_animation(&[AnimationFrame::new(empty, 1, 10)], &AnimationOptions::new()).is_err());
+    }
+
+    #[test]
+    fn encode_picks_smallest_format() {
+        let grey: Vec<Color> = (0..16).map(|i| Color::new(i * 17, i * 17, i * 17, 255)).collect();
+        assert_eq!(smallest_format(&grey), PixelFormat::Greyscale(4));
+
+        let opaque: Vec<Color> = (0..300).map(|i| Color::new(i as u8, (i / 2) as u8, 0, 255)).collect();
+        assert_eq!(smallest_format(&opaque), PixelFormat::TrueColor(8));
+
+        let translucent = vec![Color::new(255, 0, 0, 255), Color::new(0, 0, 255, 128)];
+        assert_eq!(smallest_format(&translucent), PixelFormat::Indexed(1, vec![translucent[1].clone(), translucent[0].clone()]));
+
+        let mut png = PngFile::new(4, 4);
+        png.pixels = grey;
+        let decoded = PngFile::from_data(&png.to_data().unwrap()).unwrap();
+        assert_eq!(decoded.bit_depth(), 4);
+        assert!(decoded.pixels == png.pixels);
+
+        let mut options = EncodeOptions::new();
+        options.pixel_format = Some(PixelFormat::Greyscale(1));
+        assert!(png.to_data_with_options(&options).is_err());
+
+        for format in vec![PixelFormat::Greyscale(16), PixelFormat::GreyscaleWithAlpha(16),
+                           PixelFormat::TrueColor(16), PixelFormat::TrueColorWithAlpha(16)] {
+            options.pixel_format = Some(format);
+            let decoded = PngFile::from_data(&png.to_data_with_options(&options).unwrap()).unwrap();
+            assert_eq!(decoded.bit_depth(), 16);
+            assert!(decoded.pixels == png.pixels);
+        }
+
+        let black = Color::new(0, 0, 0, 255);
+        for format in vec![PixelFormat::Greyscale(0), PixelFormat::Greyscale(3), PixelFormat::TrueColor(4),
+                           PixelFormat::GreyscaleWithAlpha(1), PixelFormat::TrueColorWithAlpha(12),
+                           PixelFormat::Indexed(0, vec![black.clone()]), PixelFormat::Indexed(3, vec![black.clone()]),
+                           PixelFormat::Indexed(16, vec![black.clone()]),
+                           PixelFormat::Indexed(1, vec![]), PixelFormat::Indexed(1, vec![black.clone(); 3])] {
+            options.pixel_format = Some(format);
+            assert!(png.to_data_with_options(&options).is_err());
+        }
+    }
+
+    /// Linear light pixels covering the range, with grey and colored,
+    /// opaque and translucent pixels.
+    fn linear_pixels() -> Vec<[f32; 4]> {
+        (0..64).map(|i| {
+            let v = i as f32 / 63.0;
+            [v, v * v, 1.0 - v, iif!(i % 4 == 0, 1.0, v)]
+        }).collect()
+    }
+
+    /// Encodes linear light pixels at the given depth and decodes them
+    /// back, checking they come back within the tolerance.
+    fn assert_linear_round_trip(pixels: &[[f32; 4]], w: usize, h: usize, depth: u8, tolerance: f32) {
+        let data = encode_linear(pixels, w, h, depth, &EncodeOptions::new()).unwrap();
+
+        let mut options = DecodeOptions::new();
+        options.linear = true;
+        let decoded = PngFile::from_data_with_options(&data, &options).unwrap();
+        assert_eq!(decoded.bit_depth(), depth as usize);
+        assert_eq!(decoded.linear.len(), pixels.len());
+        for (a, b) in decoded.linear.iter().zip(pixels.iter()) {
+            for c in 0..4 {
+                assert!((a[c] - b[c]).abs() <= tolerance, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn linear_round_trip_eight_bit() {
+        assert_linear_round_trip(&linear_pixels(), 8, 8, 8, 0.005);
+    }
+
+    #[test]
+    fn linear_round_trip_sixteen_bit() {
+        let pixels = linear_pixels();
+        assert_linear_round_trip(&pixels, 8, 8, 16, 1e-4);
+
+        // Grey pixels are stored in the greyscale formats
+        let grey: Vec<[f32; 4]> = pixels.iter().map(|p| [p[0], p[0], p[0], p[3]]).collect();
+        assert_linear_round_trip(&grey, 8, 8, 16, 1e-4);
+        let opaque: Vec<[f32; 4]> = grey.iter().map(|p| [p[0], p[0], p[0], 1.0]).collect();
+        assert_linear_round_trip(&opaque, 8, 8, 16, 1e-4);
+
+        assert!(encode_linear(&pixels, 8, 8, 12, &EncodeOptions::new()).is_err());
+        assert!(encode_linear(&pixels, 8, 4, 16, &EncodeOptions::new()).is_err());
+    }
+
+    #[test]
+    fn quantize_to_palette() {
+        let mut png = PngFile::new(32, 32);
+        for i in 0..1024 {
+            let a = iif!(i % 32 < 4, 0, 255);
+            png.pixels.push(Color::new((i % 32 * 8) as u8, (i / 32 * 8) as u8, 128, a));
+        }
+
+        let mut options = QuantizeOptions::new();
+        options.colors = 16;
+        for &dither in &[false, true] {
+            options.dither = dither;
+            let quantized = png.quantize(&options).unwrap();
+            assert!(quantized.palette.len() <= 16);
+
+            let decoded = PngFile::from_data(&png.to_quantized_data(&options).unwrap()).unwrap();
+            assert_eq!(decoded.bit_depth(), 4);
+            assert!(decoded.pixels == quantized.pixels);
+            for (a, b) in png.pixels.iter().zip(decoded.pixels.iter()) {
+                assert_eq!(a.a, b.a);
+            }
+        }
+
+        // Images with few enough colors are kept exactly
+        png.pixels = (0..1024).map(|i| Color::new(iif!(i % 3 == 0, 255, 0), 0, 0, iif!(i % 5 == 0, 128, 255))).collect();
+        assert!(png.quantize(&options).unwrap().pixels == png.pixels);
+    }
+
+    #[test]
+    fn extreme_compression_beats_level_nine() {
+        let icon = PngFile::from_data(include_bytes!("../tests/images/sdl-icon.png")).unwrap();
+        let mut options = EncodeOptions::new();
+        options.compression.level = 9;
+        let level_nine = icon.to_data_with_options(&options).unwrap();
+
+        options.compression.extreme = true;
+        let extreme = icon.to_data_with_options(&options).unwrap();
+        assert!(PngFile::from_data(&extreme).unwrap().pixels == icon.pixels);
+
+        // The icon is 5% smaller
+        assert!(extreme.len() * 100 <= level_nine.len() * 95);
+    }
+
+    /// Four 3x3 frames that fill with a color one more pixel at a time.
+    fn sample_animation() -> Vec<AnimationFrame> {
+        let colors = [(0, 0, 0, 255), (255, 0, 0, 255), (255, 0, 0, 255), (0, 0, 0, 0)];
+        (0..4).map(|i| {
+            let mut png = PngFile::new(3, 3);
+            for j in 0..9 {
+                let (r, g, b, a) = iif!(j <= i, colors[i], (0, 0, 255, 255));
+                png.pixels.push(Color::new(r, g, b, a));
+            }
+            AnimationFrame::new(png, i as u16 + 1, 10)
+        }).collect()
+    }
+
+    #[test]
+    fn animation_round_trip() {
+        let frames = sample_animation();
+        let data = encode_animation(&frames, &AnimationOptions::new()).unwrap();
+        let mut options = DecodeOptions::new();
+        options.animation = true;
+
+        let decoded = PngFile::from_data_with_options(&data, &options).unwrap();
+        assert_eq!(decoded.loop_count(), Some(0));
+        assert_eq!(decoded.frames.len(), frames.len());
+        for (a, b) in decoded.frames.iter().zip(frames.iter()) {
+            assert!(a.pixels == b.image.pixels);
+        }
+    }
+
+    #[test]
+    fn frames_match_decoded_frames() {
+        let data = encode_animation(&sample_animation(), &AnimationOptions::new()).unwrap();
+        let mut options = DecodeOptions::new();
+        options.animation = true;
+        let decoded = PngFile::from_data_with_options(&data, &options).unwrap();
+
+        let mut frames = PngFile::frames(&data).unwrap();
+        assert!(frames.png().pixels.is_empty());
+        for expected in &decoded.frames {
+            let frame = frames.next().unwrap().unwrap();
+            assert!(frame.pixels == &expected.pixels[..]);
+            assert_eq!(frame.delay(), expected.delay());
+        }
+        assert!(frames.next().is_none());
+
+        // A still image has no frames to walk
+        let mut still = PngFile::new(1, 1);
+        still.pixels.push(Color::new(0, 0, 0, 255));
+        assert!(PngFile::frames(&still.to_data().unwrap()).is_err());
+    }
+
+    #[test]
+    fn opaque_animation_blends_over() {
+        // The second frame changes two opposite corners, so its region is
+        // the whole canvas and is smallest when the middle is left clear
+        let frames: Vec<AnimationFrame> = (0..2).map(|i| {
+            let mut png = PngFile::new(16, 16);
+            for j in 0..256 {
+                let corner = i == 1 && (j == 0 || j == 255);
+                png.pixels.push(iif!(corner, Color::new(255, 0, 0, 255), Color::new((j * 7 % 256) as u8, (j / 16 * 9) as u8, 40, 255)));
+            }
+            AnimationFrame::new(png, 1, 10)
+        }).collect();
+
+        let data = encode_animation(&frames, &AnimationOptions::new()).unwrap();
+        let mut decoded = PngFile::frames(&data).unwrap();
+        let mut blend_ops = Vec::new();
+        for frame in &frames {
+            let decoded = decoded.next().unwrap().unwrap();
+            assert!(decoded.pixels == &frame.image.pixels[..]);
+            blend_ops.push(decoded.control.blend_op.clone());
+        }
+        assert_eq!(blend_ops, vec![BlendOp::Source, BlendOp::Over]);
+    }
+
+    #[test]
+    fn filter_strategies_round_trip() {
+        let mut png = PngFile::new(8, 8);
+        for i in 0..64 {
+            png.pixels.push(Color::new(i * 4, 255 - i, i % 7 * 30, 200));
+        }
+
+        for (strategy, _) in png.compressed_sizes().unwrap() {
+            let mut options = EncodeOptions::new();
+            options.filter_strategy = strategy;
+
+            let decoded = PngFile::from_data(&png.to_data_with_options(&options).unwrap()).unwrap();
+            assert!(decoded.pixels == png.pixels);
+        }
+    }
 }
@@ -0,0 +1,138 @@
+use color::{Color, Color16};
+
+/// The transfer function used to encode the samples of an image.
+pub enum Transfer {
+    /// The sRGB transfer function.
+    Srgb,
+    /// A power law, given as the file's encoding gamma from gAMA.
+    Gamma(f64),
+    /// The SMPTE ST 2084 perceptual quantizer.
+    Pq,
+    /// The ARIB STD-B67 hybrid log-gamma function.
+    Hlg
+}
+
+/// Converts an sRGB encoded value in the range [0, 1] to linear light.
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear light value in the range [0, 1] to sRGB.
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts a PQ encoded value in the range [0, 1] to linear light,
+/// where 1.0 is the PQ peak of 10000 candelas per square metre.
+pub fn pq_to_linear(v: f32) -> f32 {
+    let m1 = 2610.0 / 16384.0;
+    let m2 = 2523.0 / 4096.0 * 128.0;
+    let c1 = 3424.0 / 4096.0;
+    let c2 = 2413.0 / 4096.0 * 32.0;
+    let c3 = 2392.0 / 4096.0 * 32.0;
+
+    let p = v.powf(1.0 / m2);
+    let numerator = iif!(p - c1 > 0.0, p - c1, 0.0);
+    (numerator / (c2 - c3 * p)).powf(1.0 / m1)
+}
+
+/// Converts an HLG encoded value in the range [0, 1] to scene
+/// linear light in the range [0, 1].
+pub fn hlg_to_linear(v: f32) -> f32 {
+    let a = 0.17883277;
+    let b = 1.0 - 4.0 * a;
+    let c = 0.5 - a * (4.0 * a as f32).ln();
+
+    if v <= 0.5 {
+        v * v / 3.0
+    } else {
+        (((v - c) / a).exp() + b) / 12.0
+    }
+}
+
+/// Converts samples at the given depth to linear light, leaving
+/// the alpha channel as straight coverage.
+pub fn decode(samples: &[Color16], depth: u8, transfer: &Transfer) -> Vec<[f32; 4]> {
+    let max = ((1u32 << depth) - 1) as f32;
+
+    // Every possible sample value is linearised up front, which is
+    // at most 65536 entries for 16-bit images
+    let lookup: Vec<f32> = (0..(1u32 << depth)).map(|v| {
+        let v = v as f32 / max;
+        match *transfer {
+            Transfer::Srgb => srgb_to_linear(v),
+            Transfer::Gamma(gamma) => v.powf(1.0 / gamma as f32),
+            Transfer::Pq => pq_to_linear(v),
+            Transfer::Hlg => hlg_to_linear(v)
+        }
+    }).collect();
+
+    samples.iter().map(|s| {
+        [
+            lookup[s.r as usize],
+            lookup[s.g as usize],
+            lookup[s.b as usize],
+            s.a as f32 / max
+        ]
+    }).collect()
+}
+
+/// Clamps a value into [0, 1] and scales it to the given maximum.
+fn quantize(v: f32, max: f32) -> u16 {
+    let v = iif!(v < 0.0, 0.0, iif!(v > 1.0, 1.0, v));
+    (v * max + 0.5) as u16
+}
+
+/// Converts linear light pixels to 8-bit sRGB samples.
+pub fn linear_to_srgb8(pixels: &[[f32; 4]]) -> Vec<Color> {
+    pixels.iter().map(|p| {
+        Color::new(
+            quantize(linear_to_srgb(p[0]), 255.0) as u8,
+            quantize(linear_to_srgb(p[1]), 255.0) as u8,
+            quantize(linear_to_srgb(p[2]), 255.0) as u8,
+            quantize(p[3], 255.0) as u8
+        )
+    }).collect()
+}
+
+/// Converts linear light pixels to 16-bit sRGB samples.
+pub fn linear_to_srgb16(pixels: &[[f32; 4]]) -> Vec<Color16> {
+    pixels.iter().map(|p| {
+        Color16::new(
+            quantize(linear_to_srgb(p[0]), 65535.0),
+            quantize(linear_to_srgb(p[1]), 65535.0),
+            quantize(linear_to_srgb(p[2]), 65535.0),
+            quantize(p[3], 65535.0)
+        )
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transfer_functions_hit_known_values() {
+        // PQ peaks at 10000 nits and reaches 100 nits at about 0.5081
+        assert!((pq_to_linear(1.0) - 1.0).abs() < 1e-5);
+        assert_eq!(pq_to_linear(0.0), 0.0);
+        assert!((pq_to_linear(0.5081) - 0.01).abs() < 1e-4);
+
+        // HLG meets its square root segment at a twelfth of the peak
+        assert!((hlg_to_linear(1.0) - 1.0).abs() < 1e-5);
+        assert!((hlg_to_linear(0.5) - 1.0 / 12.0).abs() < 1e-6);
+
+        for i in 0..256 {
+            let v = i as f32 / 255.0;
+            assert!((linear_to_srgb(srgb_to_linear(v)) - v).abs() < 1e-5);
+        }
+    }
+}
@@ -8,13 +8,30 @@ extern crate sdl2;
 extern crate rpng;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 2 {
+        view(&args[1]);
+    } else {
+        eprintln!("Usage: rpng <path>");
+        std::process::exit(1);
+    }
+}
 
-    match rpng::PngFile::from_path(std::env::args().nth(1).unwrap()) {
-        Err(error) => println!("Error loading PNG: {:?}", error),
+/// Shows a PNG in a window.
+fn view(path: &str) {
+    match rpng::PngFile::from_path(path) {
+        Err(error) => eprintln!("Error loading PNG: {:?}", error),
         Ok(png) =>  {
+            // Stretch non-square pixels along their longer side so that
+            // the image is shown at its intended aspect ratio
+            let aspect = png.pixel_aspect_ratio();
+            let (scale_x, scale_y) = if aspect >= 1.0 { (aspect, 1.0) } else { (1.0, 1.0 / aspect) };
+            let display_width = (png.w as f64 * scale_x).round() as u32;
+            let display_height = (png.h as f64 * scale_y).round() as u32;
+
             // A minimum width and height for the Window
-            let window_width = std::cmp::max(400, png.w as u32);
-            let window_height = std::cmp::max(400, png.h as u32);
+            let window_width = std::cmp::max(400, display_width);
+            let window_height = std::cmp::max(400, display_height);
 
             // Setup SDL Window 
             let context = sdl2::init().unwrap();
@@ -25,7 +42,7 @@ fn main() {
                 .build().unwrap();
 
             // Instantiate a renderer
-            let mut renderer = window.renderer()
+            let mut renderer = window.into_canvas()
             .accelerated()
             .build().unwrap();
             
@@ -39,10 +56,15 @@ fn main() {
             // Iterate over every pixel in the PNG and plot it within
             // the renderer
             for y in 0..png.h {
+                let top = (y as f64 * scale_y).round() as i32;
+                let bottom = ((y + 1) as f64 * scale_y).round() as i32;
                 for x in 0..png.w {
+                    let left = (x as f64 * scale_x).round() as i32;
+                    let right = ((x + 1) as f64 * scale_x).round() as i32;
+
                     let p = &png.pixels[png.w * y + x];
                     renderer.set_draw_color(sdl2::pixels::Color::RGBA(p.r, p.g, p.b, p.a));
-                    renderer.draw_point(sdl2::rect::Point::new(x as i32, y as i32)).unwrap();
+                    renderer.fill_rect(sdl2::rect::Rect::new(left, top, (right - left) as u32, (bottom - top) as u32)).unwrap();
                 }
             }
 
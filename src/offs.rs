@@ -0,0 +1,34 @@
+use helpers;
+
+pub type OffsetParseResult = Result<ImageOffset, String>;
+
+/// The unit of an oFFs chunk's offsets.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OffsetUnit {
+    Pixel,
+    Micrometre
+}
+
+/// The position of the image on a larger page, from an oFFs chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageOffset {
+    pub x: i32,
+    pub y: i32,
+    pub unit: OffsetUnit
+}
+
+pub fn parse(data: &[u8]) -> OffsetParseResult {
+    if data.len() != 9 {
+        return Err("Invalid oFFs length".to_string());
+    }
+
+    Ok(ImageOffset {
+        x: helpers::read_unsigned_int(&data[0x00..]) as i32,
+        y: helpers::read_unsigned_int(&data[0x04..]) as i32,
+        unit: match data[0x08] {
+            0 => OffsetUnit::Pixel,
+            1 => OffsetUnit::Micrometre,
+            n => return Err(format!("Invalid oFFs unit specifier: {}", n))
+        }
+    })
+}
@@ -0,0 +1,549 @@
+use color::Color;
+use deflate;
+use encoder;
+use error::PngError;
+use filters;
+use helpers;
+use options::{Compression, EncodeOptions, FilterStrategy, OptimizeOptions};
+use png::{PngFile, PNG_HEADER};
+use reduce::{self, PixelFormat};
+
+pub type OptimizeResult = Result<Vec<u8>, PngError>;
+
+/// Chunks that change how colors are displayed, which are kept even
+/// when stripping metadata.
+const COLOR_CHUNKS: [&'static [u8]; 7] = [b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP", b"mDCV", b"cLLI"];
+
+/// Unsafe-to-copy chunks that rpng understands and that stay valid when
+/// the image data is re-encoded, as they don't depend on how the pixels
+/// are stored.
+const REENCODE_SAFE_CHUNKS: [&'static [u8]; 11] = [
+    b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP", b"mDCV", b"cLLI", b"tIME", b"sPLT", b"sCAL", b"sTER"
+];
+
+/// A chunk copied from the original file, and whether it came before
+/// or after the image data.
+struct Chunk<'a> {
+    chunk_type: &'a [u8],
+    data: &'a [u8],
+    after_image_data: bool
+}
+
+/// Losslessly shrinks a PNG. The image is decoded and re-encoded with the
+/// smallest exact pixel format, trying several filter strategies and
+/// compression levels, and the smallest result is kept if the decoded
+/// pixels are identical. Metadata chunks are copied across, except for
+/// sBIT and hIST which may no longer apply, and unknown chunks that are
+/// unsafe to copy. A pCAL chunk limits the formats to those with the
+/// same sample depth. 16-bit images are reduced to 8 bits when no sample
+/// needs the extra precision, and otherwise keep their format and only
+/// have their image data recompressed.
+pub fn optimize(data: &[u8], options: OptimizeOptions) -> OptimizeResult {
+    for strategy in &options.filter_strategies {
+        try!(encoder::check_strategy(strategy));
+    }
+
+    let png = try!(PngFile::from_data(data));
+    if png.animation().is_some() {
+        return Err(PngError::InvalidFormat("Animated PNGs can't be optimised".to_string()));
+    }
+
+    let chunks = try!(read_chunks(&data[0x08..]));
+
+    // Re-encoding rewrites IHDR, PLTE and IDAT, after which only the
+    // unsafe-to-copy chunks known not to depend on them may be copied.
+    // pCAL is kept as long as the sample depth stays the same.
+    let kept: Vec<&Chunk> = chunks.iter()
+        .filter(|c| keep_chunk(c.chunk_type, &options) && (safe_to_reencode(c.chunk_type) || c.chunk_type == b"pCAL"))
+        .collect();
+
+    let formats = try!(candidate_formats(&png, &chunks, &kept));
+    let result = if formats.is_empty() {
+        // Every smaller format would change the sample depth that pCAL
+        // refers to
+        let (rows, bits_per_pixel) = try!(unfilter_image_data(&chunks, png.w, png.h));
+        try!(recompress(&chunks, &rows, bits_per_pixel, &options))
+    } else if png.bit_depth() == 16 {
+        // 16-bit samples that are all 8-bit values times 257 lose nothing
+        // when they are reduced, so the image is re-encoded at 8 bits
+        let (rows, bits_per_pixel) = try!(unfilter_image_data(&chunks, png.w, png.h));
+        if fits_eight_bits(&rows) {
+            try!(reencode(&png, &formats, &kept, &options))
+        } else {
+            try!(recompress(&chunks, &rows, bits_per_pixel, &options))
+        }
+    } else {
+        try!(reencode(&png, &formats, &kept, &options))
+    };
+
+    // Make sure nothing was lost along the way
+    let decoded = try!(PngFile::from_data(&result));
+    if decoded.w != png.w || decoded.h != png.h || decoded.pixels != png.pixels {
+        return Err(PngError::InvalidFormat("The optimised image doesn't match the original".to_string()));
+    }
+
+    Ok(iif!(result.len() < data.len(), result, data.to_vec()))
+}
+
+fn read_chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut chunks = Vec::new();
+    let mut seen_image_data = false;
+    let mut idx = 0;
+
+    while idx + 0x08 <= data.len() {
+        let length = helpers::read_unsigned_int(&data[idx..]) as usize;
+        if idx + length + 0x0C > data.len() {
+            return Err("Truncated chunk".to_string());
+        }
+
+        let chunk_type = &data[idx + 0x04..idx + 0x08];
+        match chunk_type {
+            b"IDAT" => seen_image_data = true,
+            b"IEND" => break,
+            b"IHDR" | b"PLTE" | b"CgBI" => (),
+            _ => {
+                // Critical chunks have an uppercase first letter
+                if chunk_type[0] & 0x20 == 0 {
+                    return Err(format!("Unknown critical chunk: {}", String::from_utf8_lossy(chunk_type)));
+                }
+            }
+        }
+
+        chunks.push(Chunk {
+            chunk_type: chunk_type,
+            data: &data[idx + 0x08..idx + 0x08 + length],
+            after_image_data: seen_image_data
+        });
+
+        idx += length + 0x0C;
+    }
+
+    Ok(chunks)
+}
+
+/// Whether an ancillary chunk is copied into the optimised file.
+fn keep_chunk(chunk_type: &[u8], options: &OptimizeOptions) -> bool {
+    match chunk_type {
+        b"IHDR" | b"PLTE" | b"IDAT" | b"CgBI" | b"tRNS" | b"bKGD" | b"sBIT" | b"hIST" => false,
+        _ => !options.strip || COLOR_CHUNKS.iter().any(|&c| c == chunk_type)
+    }
+}
+
+/// Whether a chunk may be copied into a file whose critical chunks
+/// have changed.
+fn safe_to_reencode(chunk_type: &[u8]) -> bool {
+    // Safe-to-copy chunks have a lowercase fourth letter
+    chunk_type[3] & 0x20 != 0 || REENCODE_SAFE_CHUNKS.iter().any(|&c| c == chunk_type)
+}
+
+/// Writes the kept chunks that belong on one side of the image data.
+fn write_kept(out: &mut Vec<u8>, kept: &[&Chunk], after_image_data: bool) {
+    for chunk in kept.iter().filter(|c| c.after_image_data == after_image_data) {
+        encoder::write_chunk(out, chunk.chunk_type, chunk.data);
+    }
+}
+
+/// The compression settings to try, in order.
+fn compressions(options: &OptimizeOptions) -> Vec<Compression> {
+    let mut result: Vec<Compression> = options.levels.iter().map(|&level| {
+        let mut compression = Compression::new();
+        compression.level = level;
+        compression
+    }).collect();
+
+    if options.extreme {
+        let mut compression = Compression::new();
+        compression.extreme = true;
+        result.push(compression);
+    }
+
+    result
+}
+
+/// Keeps whichever candidate is smaller.
+fn smallest(best: Option<Vec<u8>>, candidate: Vec<u8>) -> Option<Vec<u8>> {
+    match best {
+        Some(ref best) if best.len() <= candidate.len() => Some(best.clone()),
+        _ => Some(candidate)
+    }
+}
+
+/// The pixel formats that store the image exactly and that the kept
+/// chunks still apply to.
+fn candidate_formats(png: &PngFile, chunks: &[Chunk], kept: &[&Chunk]) -> Result<Vec<PixelFormat>, String> {
+    let pixels = &png.pixels[..];
+    let (bit_depth, color_type) = try!(header(chunks));
+
+    // An ICC profile is either for greyscale or for color images, so
+    // an image with one keeps to the same kind of format
+    let profile = kept.iter().any(|c| c.chunk_type == b"iCCP");
+    let greyscale = color_type & 2 == 0;
+    let (allow_greyscale, allow_color) = iif!(profile, (greyscale, !greyscale), (true, true));
+
+    let mut formats = vec![reduce::analyze_within(pixels.iter(), allow_greyscale, allow_color)];
+    let opaque = pixels.iter().all(|p| p.a == 255);
+    let fallback = match (allow_color, opaque) {
+        (true, true) => PixelFormat::TrueColor(8),
+        (true, false) => PixelFormat::TrueColorWithAlpha(8),
+        (false, true) => PixelFormat::Greyscale(8),
+        (false, false) => PixelFormat::GreyscaleWithAlpha(8)
+    };
+    if formats[0] != fallback {
+        formats.push(fallback);
+    }
+
+    // pCAL maps sample values at the depth they were stored at
+    if kept.iter().any(|c| c.chunk_type == b"pCAL") {
+        let sample_depth = iif!(color_type == 3, 8, bit_depth);
+        formats.retain(|f| f.sample_depth() == sample_depth);
+    }
+
+    Ok(formats)
+}
+
+/// Re-encodes the pixels with each candidate format and filter strategy
+/// at a quick compression level, then compresses the best of them with
+/// every compression setting.
+fn reencode(png: &PngFile, formats: &[PixelFormat], kept: &[&Chunk], options: &OptimizeOptions) -> OptimizeResult {
+    let pixels = &png.pixels[..];
+
+    let mut quick = EncodeOptions::new();
+    quick.compression.level = 1;
+
+    let mut best: Option<(usize, PixelFormat, FilterStrategy)> = None;
+    for format in formats {
+        for strategy in &options.filter_strategies {
+            quick.filter_strategy = strategy.clone();
+            let size = try!(encoder::compress(pixels, png.w, format, &quick)).len();
+
+            let better = match best {
+                Some((best_size, _, _)) => size < best_size,
+                None => true
+            };
+
+            if better {
+                best = Some((size, format.clone(), strategy.clone()));
+            }
+        }
+    }
+
+    let (format, strategy) = match best {
+        Some((_, format, strategy)) => (format, strategy),
+        None => return Err(PngError::InvalidFormat("No filter strategies to try".to_string()))
+    };
+
+    let mut image_data: Option<Vec<u8>> = None;
+    for compression in compressions(options) {
+        let mut encode_options = EncodeOptions::new();
+        encode_options.filter_strategy = strategy.clone();
+        encode_options.compression = compression;
+        image_data = smallest(image_data, try!(encoder::compress(pixels, png.w, &format, &encode_options)));
+    }
+
+    let image_data = match image_data {
+        Some(image_data) => image_data,
+        None => return Err(PngError::InvalidFormat("No compression levels to try".to_string()))
+    };
+
+    let mut out = PNG_HEADER.to_vec();
+    encoder::write_ihdr(&mut out, png.w, png.h, &format);
+    write_kept(&mut out, kept, false);
+    encoder::write_palette(&mut out, &format);
+    if let Some(background) = png.background_color() {
+        if let Some(data) = background_data(&background, &format) {
+            encoder::write_chunk(&mut out, b"bKGD", &data);
+        }
+    }
+    encoder::write_image_data(&mut out, &image_data);
+    write_kept(&mut out, kept, true);
+    encoder::write_chunk(&mut out, b"IEND", &[]);
+
+    Ok(out)
+}
+
+/// Stores the background color in the bKGD format of the pixel format,
+/// if it can be stored exactly.
+fn background_data(background: &Color, format: &PixelFormat) -> Option<Vec<u8>> {
+    let grey = background.r == background.g && background.g == background.b;
+    let wide = |v: u8| iif!(format.bit_depth() == 16, [v, v], [0, v]);
+    match *format {
+        PixelFormat::Greyscale(depth) if depth < 8 => {
+            let scale = 255 / ((1u16 << depth) - 1);
+            iif!(grey && background.r as u16 % scale == 0, Some(vec![0, (background.r as u16 / scale) as u8]), None)
+        },
+        PixelFormat::Greyscale(_) | PixelFormat::GreyscaleWithAlpha(_) => iif!(grey, Some(wide(background.r).to_vec()), None),
+        PixelFormat::TrueColor(_) | PixelFormat::TrueColorWithAlpha(_) => {
+            Some([wide(background.r), wide(background.g), wide(background.b)].concat())
+        },
+        PixelFormat::Indexed(_, ref palette) => {
+            palette.iter()
+                .position(|c| c.r == background.r && c.g == background.g && c.b == background.b)
+                .map(|i| vec![i as u8])
+        }
+    }
+}
+
+/// The bit depth and color type from the IHDR chunk.
+fn header(chunks: &[Chunk]) -> Result<(u8, u8), String> {
+    match chunks.iter().find(|c| c.chunk_type == b"IHDR") {
+        Some(ihdr) if ihdr.data.len() > 9 => Ok((ihdr.data[8], ihdr.data[9])),
+        _ => Err("IHDR chunk missing".to_string())
+    }
+}
+
+/// Joins, inflates and unfilters the IDAT chunks into scanlines of
+/// samples without their filter type bytes, returning them with the
+/// number of bits per pixel.
+fn unfilter_image_data(chunks: &[Chunk], w: usize, h: usize) -> Result<(Vec<Vec<u8>>, usize), String> {
+    // CgBI image data is raw deflate holding BGR samples with
+    // premultiplied alpha, which can only be re-encoded
+    if chunks.iter().any(|c| c.chunk_type == b"CgBI") {
+        return Err("CgBI image data can't be recompressed".to_string());
+    }
+
+    let (bit_depth, color_type) = try!(header(chunks));
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(format!("Invalid color type: {}", color_type))
+    };
+
+    let bits_per_pixel = channels * bit_depth as usize;
+    let bytes_per_pixel = ::std::cmp::max(1, bits_per_pixel / 8);
+    let row_size = (w * bits_per_pixel + 7) / 8 + 1;
+
+    let mut compressed_data = Vec::new();
+    for chunk in chunks.iter().filter(|c| c.chunk_type == b"IDAT") {
+        compressed_data.extend_from_slice(chunk.data);
+    }
+
+    let raw = try!(deflate::decode(&compressed_data, || row_size * h));
+    if raw.len() < row_size * h {
+        return Err("Truncated image data".to_string());
+    }
+
+    let mut rows: Vec<Vec<u8>> = Vec::with_capacity(h);
+    for line in raw[..row_size * h].chunks(row_size) {
+        let mut row = line[1..].to_vec();
+        try!(filters::unfilter_row(line[0], &mut row, rows.last().map(|p| &p[..]), bytes_per_pixel));
+        rows.push(row);
+    }
+
+    Ok((rows, bits_per_pixel))
+}
+
+/// Whether every sample of a 16-bit image is an 8-bit value times 257,
+/// which is when its high and low bytes are equal.
+fn fits_eight_bits(rows: &[Vec<u8>]) -> bool {
+    rows.iter().all(|row| row.chunks(2).all(|sample| sample[0] == sample[1]))
+}
+
+/// Re-filters the scanlines with each filter strategy at a quick
+/// compression level, then compresses the best of them with every
+/// compression setting. The samples are left as they are, along with
+/// every chunk that depends on the format.
+fn recompress(chunks: &[Chunk], rows: &[Vec<u8>], bits_per_pixel: usize, options: &OptimizeOptions) -> OptimizeResult {
+    let mut quick = EncodeOptions::new();
+    quick.compression.level = 1;
+
+    let mut best: Option<(usize, FilterStrategy)> = None;
+    for strategy in &options.filter_strategies {
+        quick.filter_strategy = strategy.clone();
+        let size = try!(encoder::compress_rows(rows, bits_per_pixel, &quick)).len();
+
+        let better = match best {
+            Some((best_size, _)) => size < best_size,
+            None => true
+        };
+
+        if better {
+            best = Some((size, strategy.clone()));
+        }
+    }
+
+    let strategy = match best {
+        Some((_, strategy)) => strategy,
+        None => return Err(PngError::InvalidFormat("No filter strategies to try".to_string()))
+    };
+
+    let mut image_data: Option<Vec<u8>> = None;
+    for compression in compressions(options) {
+        let mut encode_options = EncodeOptions::new();
+        encode_options.filter_strategy = strategy.clone();
+        encode_options.compression = compression;
+        image_data = smallest(image_data, try!(encoder::compress_rows(rows, bits_per_pixel, &encode_options)));
+    }
+
+    let image_data = match image_data {
+        Some(image_data) => image_data,
+        None => return Err(PngError::InvalidFormat("No compression levels to try".to_string()))
+    };
+
+    let mut out = PNG_HEADER.to_vec();
+    let mut wrote_image_data = false;
+    for chunk in chunks {
+        match chunk.chunk_type {
+            b"IHDR" | b"PLTE" | b"tRNS" | b"bKGD" | b"sBIT" | b"hIST" => encoder::write_chunk(&mut out, chunk.chunk_type, chunk.data),
+            b"IDAT" => {
+                if !wrote_image_data {
+                    encoder::write_image_data(&mut out, &image_data);
+                    wrote_image_data = true;
+                }
+            },
+            _ => {
+                if keep_chunk(chunk.chunk_type, options) {
+                    encoder::write_chunk(&mut out, chunk.chunk_type, chunk.data);
+                }
+            }
+        }
+    }
+    encoder::write_chunk(&mut out, b"IEND", &[]);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use options::DecodeOptions;
+
+    /// Builds a 16-bit truecolor PNG from big-endian samples.
+    fn sixteen_bit_png(w: usize, h: usize, samples: &[u16]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for row in samples.chunks(w * 3) {
+            raw.push(0);
+            for &sample in row {
+                raw.extend_from_slice(&[(sample >> 8) as u8, sample as u8]);
+            }
+        }
+
+        helpers::png_file(w as u32, h as u32, 16, 2, &[(b"IDAT", helpers::image_data(&raw))])
+    }
+
+    #[test]
+    fn reduces_sixteen_bit_samples() {
+        // Noise, so that halving the samples halves the compressed size
+        let mut seed = 1u32;
+        let mut samples: Vec<u16> = (0..3072).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16 & 0xFF) as u16 * 257
+        }).collect();
+        let original = sixteen_bit_png(32, 32, &samples);
+        let optimized = optimize(&original, OptimizeOptions::new()).unwrap();
+
+        let decoded = PngFile::from_data(&optimized).unwrap();
+        assert!(decoded.bit_depth() <= 8);
+        assert!(decoded.pixels == PngFile::from_data(&original).unwrap().pixels);
+
+        // A single sample that needs 16 bits keeps the whole image at 16
+        samples[100] += 1;
+        let original = sixteen_bit_png(32, 32, &samples);
+        let optimized = optimize(&original, OptimizeOptions::new()).unwrap();
+        assert_eq!(PngFile::from_data(&optimized).unwrap().bit_depth(), 16);
+    }
+
+    #[test]
+    fn reduces_and_round_trips() {
+        let mut png = PngFile::new(16, 16);
+        for i in 0..256 {
+            let v = iif!(i % 3 == 0, 255, 0);
+            png.pixels.push(Color::new(v, v, v, 255));
+        }
+
+        let mut options = EncodeOptions::new();
+        options.pixel_format = Some(PixelFormat::TrueColorWithAlpha(8));
+        let original = png.to_data_with_options(&options).unwrap();
+        let optimized = optimize(&original, OptimizeOptions::new()).unwrap();
+        assert!(optimized.len() < original.len());
+
+        let decoded = PngFile::from_data(&optimized).unwrap();
+        assert_eq!(decoded.bit_depth(), 1);
+        assert!(decoded.pixels == png.pixels);
+
+        let mut options = OptimizeOptions::new();
+        options.filter_strategies.push(FilterStrategy::Fixed(7));
+        assert!(optimize(&original, options).is_err());
+
+        // Unknown chunks are dropped on re-encoding unless they are safe
+        // to copy
+        let mut with_chunks = original[..33].to_vec();
+        encoder::write_chunk(&mut with_chunks, b"saFe", b"kept");
+        encoder::write_chunk(&mut with_chunks, b"unSF", b"dropped");
+        with_chunks.extend_from_slice(&original[33..]);
+
+        let optimized = optimize(&with_chunks, OptimizeOptions::new()).unwrap();
+        assert!(optimized.windows(4).any(|w| w == b"saFe"));
+        assert!(!optimized.windows(4).any(|w| w == b"unSF"));
+
+        // An RGB image with an ICC profile isn't reduced to greyscale
+        let mut with_profile = original[..33].to_vec();
+        encoder::write_chunk(&mut with_profile, b"iCCP", b"RGB\0\0\x78\x9c\x03\x00\x00\x00\x00\x01");
+        with_profile.extend_from_slice(&original[33..]);
+
+        let optimized = optimize(&with_profile, OptimizeOptions::new()).unwrap();
+        assert!(optimized[25] & 2 != 0);
+        assert!(PngFile::from_data(&optimized).unwrap().pixels == png.pixels);
+    }
+
+    /// Inserts a chunk straight after the IHDR chunk of a PNG.
+    fn with_chunk(data: &[u8], chunk_type: &[u8], chunk_data: &[u8]) -> Vec<u8> {
+        let mut result = data[..33].to_vec();
+        encoder::write_chunk(&mut result, chunk_type, chunk_data);
+        result.extend_from_slice(&data[33..]);
+        result
+    }
+
+    #[test]
+    fn keeps_calibration() {
+        let mut png = PngFile::new(16, 16);
+        for i in 0..256 {
+            let v = iif!(i % 3 == 0, 255, 0);
+            png.pixels.push(Color::new(v, v, v, 255));
+        }
+
+        let mut options = EncodeOptions::new();
+        options.pixel_format = Some(PixelFormat::TrueColorWithAlpha(8));
+        let pcal = b"Temperature\0\0\0\0\0\0\0\0\xff\0\x02K\0-10\00.5";
+        let original = with_chunk(&png.to_data_with_options(&options).unwrap(), b"pCAL", pcal);
+
+        // 1-bit greyscale would change the sample depth, but a palette
+        // keeps 8-bit samples
+        let optimized = optimize(&original, OptimizeOptions::new()).unwrap();
+        assert!(optimized.len() < original.len());
+        assert!(optimized.windows(4).any(|w| w == b"pCAL"));
+
+        let mut decode_options = DecodeOptions::new();
+        decode_options.calibrate = true;
+        let decoded = PngFile::from_data_with_options(&optimized, &decode_options).unwrap();
+        assert!(decoded.calibrated == PngFile::from_data_with_options(&original, &decode_options).unwrap().calibrated);
+        assert_eq!(decoded.calibration().map(|c| c.parameters.clone()), Some(vec![-10.0, 0.5]));
+
+        // 16-bit samples that would fit 8 bits stay at 16
+        let samples: Vec<u16> = (0..48).map(|i| (i % 5) * 257 * 60).collect();
+        let original = with_chunk(&sixteen_bit_png(4, 4, &samples), b"pCAL", pcal);
+        let optimized = optimize(&original, OptimizeOptions::new()).unwrap();
+        assert!(optimized.windows(4).any(|w| w == b"pCAL"));
+        assert_eq!(PngFile::from_data(&optimized).unwrap().bit_depth(), 16);
+    }
+
+    #[test]
+    fn refilters_sixteen_bit_images() {
+        // A smooth gradient that needs 16 bits, stored unfiltered
+        let samples: Vec<u16> = (0..3 * 32 * 32).map(|i| (i / 3 % 32 * 1000 + i / 96 * 700 + i % 3) as u16).collect();
+        let original = sixteen_bit_png(32, 32, &samples);
+        let optimized = optimize(&original, OptimizeOptions::new()).unwrap();
+        assert_eq!(PngFile::from_data(&optimized).unwrap().bit_depth(), 16);
+
+        let mut options = OptimizeOptions::new();
+        options.filter_strategies = vec![FilterStrategy::Fixed(0)];
+        let unfiltered = optimize(&original, options).unwrap();
+        assert!(optimized.len() < unfiltered.len());
+
+        // The samples themselves are untouched
+        let (rows, _) = unfilter_image_data(&read_chunks(&optimized[8..]).unwrap(), 32, 32).unwrap();
+        let stored: Vec<u16> = rows.iter().flat_map(|row| row.chunks(2).map(|s| (s[0] as u16) << 8 | s[1] as u16).collect::<Vec<u16>>()).collect();
+        assert!(stored == samples);
+    }
+}
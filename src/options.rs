@@ -0,0 +1,251 @@
+use chrm::Chromaticities;
+use reduce::PixelFormat;
+
+/// How 16-bit samples are reduced to 8 bits.
+#[derive(PartialEq)]
+pub enum DepthReduction {
+    /// Round each sample to the nearest 8-bit value.
+    Round,
+    /// Dither with a 4x4 Bayer matrix.
+    Ordered,
+    /// Dither by diffusing the rounding error over neighbouring pixels.
+    FloydSteinberg
+}
+
+/// How the encoder picks the filter for each scanline.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterStrategy {
+    /// Use the given filter type, from 0 (None) to 4 (Paeth), for
+    /// every row.
+    Fixed(u8),
+    /// Pick the filter whose output has the smallest sum of absolute
+    /// signed differences.
+    MinSumAbs,
+    /// Pick the filter whose output has the lowest Shannon entropy.
+    Entropy,
+    /// Compress the row with all five filters and keep the smallest.
+    BruteForce
+}
+
+/// Options that control how a PNG's image data is decoded.
+pub struct DecodeOptions {
+    /// Keep indexed-color images as raw palette indices in
+    /// `PngFile::indices` instead of expanding them through the
+    /// palette into `PngFile::pixels`. Decoding an indexed-color image
+    /// fails if the options also ask for its colors to be converted.
+    pub keep_indices: bool,
+
+    /// Honour the sBIT chunk by discarding insignificant low bits
+    /// from each sample and rescaling the significant bits to the
+    /// output depth.
+    pub significant_bits: bool,
+
+    /// How 16-bit images are reduced to 8 bits per sample.
+    pub depth_reduction: DepthReduction,
+
+    /// Decode into linear light floating point values in
+    /// `PngFile::linear` instead of `PngFile::pixels`, using the
+    /// file's gamma or assuming sRGB when it has none.
+    pub linear: bool,
+
+    /// Premultiply the color of each pixel in `PngFile::pixels`, or of
+    /// each value in `PngFile::linear`, by its alpha.
+    pub premultiply: bool,
+
+    /// Convert colors from the image's primaries, given by its sRGB or
+    /// cHRM chunk, to these primaries. Pixels are re-encoded with the
+    /// sRGB transfer function, while linear output stays linear.
+    pub target_gamut: Option<Chromaticities>,
+
+    /// Decode samples with the PQ or HLG transfer function when the
+    /// cICP chunk signals one, so that linear output holds absolute
+    /// (PQ) or scene-referred (HLG) light levels.
+    pub hdr_transfer: bool,
+
+    /// Rotate and flip the decoded image so that it is upright
+    /// according to its EXIF orientation.
+    pub apply_orientation: bool,
+
+    /// Convert every color sample into its physical value using the
+    /// pCAL chunk, storing them in `PngFile::calibrated`.
+    pub calibrate: bool,
+
+    /// Decode every frame of an animated PNG into `PngFile::frames`,
+    /// composited onto the full canvas.
+    pub animation: bool
+}
+
+impl DecodeOptions {
+    pub fn new() -> Self {
+        DecodeOptions {
+            keep_indices: false,
+            significant_bits: false,
+            depth_reduction: DepthReduction::Round,
+            linear: false,
+            premultiply: false,
+            target_gamut: None,
+            hdr_transfer: false,
+            apply_orientation: false,
+            calibrate: false,
+            animation: false
+        }
+    }
+}
+
+/// Options that control how an animated PNG is encoded.
+pub struct AnimationOptions {
+    /// The number of times to loop the animation, where 0 loops forever.
+    pub num_plays: u32,
+
+    /// Make the first frame the default image that is shown by
+    /// decoders without APNG support. When false, the first frame is
+    /// only written as that default image and the animation is made
+    /// of the remaining frames.
+    pub include_default_image: bool,
+
+    /// How the image data of each frame is filtered and compressed.
+    pub encoding: EncodeOptions
+}
+
+impl AnimationOptions {
+    pub fn new() -> Self {
+        AnimationOptions {
+            num_plays: 0,
+            include_default_image: true,
+            encoding: EncodeOptions::new()
+        }
+    }
+}
+
+/// How image data is compressed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compression {
+    /// From 0, which stores the data uncompressed, to 9, which searches
+    /// hardest for matches.
+    pub level: u8,
+
+    /// The base 2 logarithm of the window that matches are searched
+    /// for in, from 8 to 15.
+    pub window_bits: u8,
+
+    /// Ignore the level and search for the smallest possible encoding,
+    /// which is many times slower.
+    pub extreme: bool,
+
+    /// How many times the extreme mode refines its parse of each block.
+    pub iterations: usize
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Compression {
+            level: 6,
+            window_bits: 15,
+            extreme: false,
+            iterations: 15
+        }
+    }
+}
+
+/// Options that control how a PNG is encoded.
+pub struct EncodeOptions {
+    /// How the filter for each scanline is picked.
+    pub filter_strategy: FilterStrategy,
+
+    /// How the filtered image data is compressed.
+    pub compression: Compression,
+
+    /// How many threads compress the image data. With more than one,
+    /// the scanlines are split into bands that are compressed
+    /// independently.
+    pub threads: usize,
+
+    /// How many scanlines go in each independently compressed band.
+    /// The output is the same for any number of threads when this is
+    /// set. When it is None and there are several threads, the image
+    /// is split into one band per thread.
+    pub band_rows: Option<usize>,
+
+    /// The color type and bit depth the pixels are stored in. When it
+    /// is None, the smallest format that holds every pixel exactly is
+    /// picked. Encoding fails if the pixels don't fit the given format.
+    pub pixel_format: Option<PixelFormat>
+}
+
+impl EncodeOptions {
+    pub fn new() -> Self {
+        EncodeOptions {
+            filter_strategy: FilterStrategy::MinSumAbs,
+            compression: Compression::new(),
+            threads: 1,
+            band_rows: None,
+            pixel_format: None
+        }
+    }
+}
+
+/// Options that control how an image is reduced to a palette.
+pub struct QuantizeOptions {
+    /// The most colors the palette may have, from 1 to 256.
+    pub colors: usize,
+
+    /// The quality to aim for, from 0 to 100. The palette stops growing
+    /// once the average error is small enough for this quality, so lower
+    /// values give fewer colors. At 100, the palette only stops growing
+    /// once it has `colors` entries or every color is exact.
+    pub quality: u8,
+
+    /// Dither with Floyd-Steinberg error diffusion, which trades banding
+    /// for noise.
+    pub dither: bool,
+
+    /// How the quantized image is filtered and compressed. Its pixel
+    /// format is ignored, as the image is always stored as indexed color.
+    pub encoding: EncodeOptions
+}
+
+impl QuantizeOptions {
+    pub fn new() -> Self {
+        QuantizeOptions {
+            colors: 256,
+            quality: 100,
+            dither: true,
+            encoding: EncodeOptions::new()
+        }
+    }
+}
+
+/// Options that control how hard `optimize` works to shrink a PNG.
+pub struct OptimizeOptions {
+    /// The filter strategies that are tried with each pixel format.
+    pub filter_strategies: Vec<FilterStrategy>,
+
+    /// The compression levels that are tried with the best pixel format
+    /// and filter strategy.
+    pub levels: Vec<u8>,
+
+    /// Also try the extreme compression mode, which is much slower.
+    pub extreme: bool,
+
+    /// Remove every ancillary chunk except those that affect how colors
+    /// are displayed.
+    pub strip: bool
+}
+
+impl OptimizeOptions {
+    pub fn new() -> Self {
+        OptimizeOptions {
+            filter_strategies: vec![
+                FilterStrategy::Fixed(0),
+                FilterStrategy::Fixed(1),
+                FilterStrategy::Fixed(2),
+                FilterStrategy::Fixed(4),
+                FilterStrategy::MinSumAbs,
+                FilterStrategy::Entropy
+            ],
+            levels: vec![6, 9],
+            extreme: false,
+            strip: false
+        }
+    }
+}
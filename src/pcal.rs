@@ -0,0 +1,176 @@
+use helpers;
+
+pub type CalibrationParseResult = Result<Calibration, String>;
+
+/// The equation mapping sample values to physical values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Equation {
+    /// `p0 + p1 * x`
+    Linear,
+    /// `p0 + p1 * e^(p2 * x)`
+    Exponential,
+    /// `p0 + p1 * p2^x`
+    ArbitraryBaseExponential,
+    /// `p0 + p1 * sinh(p2 * (x - p3))`
+    Hyperbolic
+}
+
+impl Equation {
+    /// The number of parameters the equation takes.
+    pub fn parameter_count(&self) -> usize {
+        match *self {
+            Equation::Linear => 2,
+            Equation::Exponential | Equation::ArbitraryBaseExponential => 3,
+            Equation::Hyperbolic => 4
+        }
+    }
+}
+
+/// The mapping of sample values to physical values, from a pCAL chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calibration {
+    pub name: String,
+    pub x0: i32,
+    pub x1: i32,
+    pub equation: Equation,
+    pub unit: String,
+    pub parameters: Vec<f64>
+}
+
+impl Calibration {
+    /// Converts a stored sample at the given bit depth into its
+    /// physical value, in `unit`.
+    pub fn physical_value(&self, sample: u16, bit_depth: u8) -> f64 {
+        let max = (1i64 << bit_depth) - 1;
+        let range = self.x1 as i64 - self.x0 as i64;
+
+        // The sample is first mapped onto the original range [x0, x1]
+        // as the specification does, with integer arithmetic, then
+        // divided by its width to give x in the equations
+        let original = (sample as i64 * range + max / 2) / max + self.x0 as i64;
+        let range = range as f64;
+        let x = original as f64 / range;
+
+        let p = &self.parameters;
+        match self.equation {
+            Equation::Linear => p[0] + p[1] * x,
+            Equation::Exponential => p[0] + p[1] * (p[2] * x).exp(),
+            Equation::ArbitraryBaseExponential => p[0] + p[1] * p[2].powf(x),
+            Equation::Hyperbolic => p[0] + p[1] * (p[2] * (x - p[3] / range)).sinh()
+        }
+    }
+}
+
+/// Splits a null terminated field off the front of the data.
+fn field(data: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    match data.iter().position(|&b| b == 0) {
+        Some(n) => Ok((&data[..n], &data[n + 1..])),
+        None => Err("pCAL field is not null terminated".to_string())
+    }
+}
+
+pub fn parse(data: &[u8]) -> CalibrationParseResult {
+    let (name, rest) = try!(field(data));
+    if name.is_empty() || name.len() > 79 {
+        return Err("pCAL calibration name must be 1-79 bytes long".to_string());
+    }
+
+    if rest.len() < 10 {
+        return Err("pCAL chunk is too short".to_string());
+    }
+
+    let x0 = helpers::read_unsigned_int(&rest[0x00..]) as i32;
+    let x1 = helpers::read_unsigned_int(&rest[0x04..]) as i32;
+    if x0 == x1 {
+        return Err("pCAL original sample range must not be empty".to_string());
+    }
+
+    let equation = match rest[0x08] {
+        0 => Equation::Linear,
+        1 => Equation::Exponential,
+        2 => Equation::ArbitraryBaseExponential,
+        3 => Equation::Hyperbolic,
+        n => return Err(format!("Invalid pCAL equation type: {}", n))
+    };
+
+    let count = rest[0x09] as usize;
+    if count != equation.parameter_count() {
+        return Err("pCAL parameter count does not match the equation type".to_string());
+    }
+
+    let (unit, rest) = try!(field(&rest[0x0A..]));
+
+    // Parameters are separated, not terminated, by nulls
+    let mut parameters = Vec::with_capacity(count);
+    for parameter in rest.split(|&b| b == 0) {
+        parameters.push(try!(helpers::parse_ascii_float(parameter)));
+    }
+
+    if parameters.len() != count {
+        return Err("pCAL parameter count does not match the equation type".to_string());
+    }
+
+    Ok(Calibration {
+        // Names and units are Latin-1
+        name: name.iter().map(|&b| b as char).collect(),
+        x0: x0,
+        x1: x1,
+        equation: equation,
+        unit: unit.iter().map(|&b| b as char).collect(),
+        parameters: parameters
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// pCAL chunk data for the given original range, equation type
+    /// and parameters.
+    fn chunk(x0: i32, x1: i32, equation: u8, parameters: &[&str]) -> Vec<u8> {
+        let mut data = b"calibration\0".to_vec();
+        data.extend_from_slice(&[(x0 >> 24) as u8, (x0 >> 16) as u8, (x0 >> 8) as u8, x0 as u8]);
+        data.extend_from_slice(&[(x1 >> 24) as u8, (x1 >> 16) as u8, (x1 >> 8) as u8, x1 as u8]);
+        data.push(equation);
+        data.push(parameters.len() as u8);
+        data.extend_from_slice(b"unit\0");
+        data.extend_from_slice(parameters.join("\0").as_bytes());
+        data
+    }
+
+    fn assert_values(calibration: &Calibration, bit_depth: u8, expected: &[(u16, f64)]) {
+        for &(sample, value) in expected {
+            assert!((calibration.physical_value(sample, bit_depth) - value).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn evaluates_each_equation() {
+        // 128 of 255 maps to 502 of 1000, so x is 0.502
+        let linear = parse(&chunk(0, 1000, 0, &["-10", "50"])).unwrap();
+        assert_eq!(linear.equation, Equation::Linear);
+        assert_values(&linear, 8, &[(0, -10.0), (128, 15.1), (255, 40.0)]);
+        assert_values(&linear, 16, &[(32768, 15.0), (65535, 40.0)]);
+
+        let exponential = parse(&chunk(0, 1000, 1, &["0", "2", "1"])).unwrap();
+        assert_values(&exponential, 8, &[(0, 2.0), (255, 5.43656365691809)]);
+
+        let arbitrary_base = parse(&chunk(0, 1000, 2, &["1", "3", "10"])).unwrap();
+        assert_values(&arbitrary_base, 8, &[(0, 4.0), (128, 10.530622211949312), (255, 31.0)]);
+
+        // p3 is in the original units, so 250 of the range [-500, 500]
+        // shifts x by a quarter
+        let hyperbolic = parse(&chunk(-500, 500, 3, &["5", "2", "2", "250"])).unwrap();
+        assert_eq!(hyperbolic.x0, -500);
+        assert_values(&hyperbolic, 8, &[(0, 0.7414410898103654), (255, 6.042190610987495)]);
+    }
+
+    #[test]
+    fn rejects_bad_parameters() {
+        assert!(parse(&chunk(0, 1000, 0, &["1"])).is_err());
+        assert!(parse(&chunk(0, 1000, 1, &["1", "2"])).is_err());
+        assert!(parse(&chunk(0, 1000, 4, &["1", "2"])).is_err());
+        assert!(parse(&chunk(0, 0, 0, &["1", "2"])).is_err());
+        assert!(parse(&chunk(0, 1000, 0, &["1", "two"])).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use helpers;
+
+pub type PhysicalDimensionsParseResult = Result<PhysicalDimensions, String>;
+
+const INCHES_PER_METRE: f64 = 39.3700787;
+
+/// The unit of a pHYs chunk's pixels per unit values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PhysicalUnit {
+    /// Only the aspect ratio of the pixels is known.
+    Unknown,
+    Metre
+}
+
+/// The intended pixel size or aspect ratio from a pHYs chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysicalDimensions {
+    pub pixels_per_unit_x: u32,
+    pub pixels_per_unit_y: u32,
+    pub unit: PhysicalUnit
+}
+
+impl PhysicalDimensions {
+    /// Physical dimensions for the given horizontal and vertical
+    /// resolution in dots per inch.
+    pub fn from_dpi(x: f64, y: f64) -> Self {
+        PhysicalDimensions {
+            pixels_per_unit_x: (x * INCHES_PER_METRE).round() as u32,
+            pixels_per_unit_y: (y * INCHES_PER_METRE).round() as u32,
+            unit: PhysicalUnit::Metre
+        }
+    }
+
+    /// The horizontal and vertical resolution in dots per inch, when
+    /// the unit is known.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            PhysicalUnit::Metre => Some((
+                self.pixels_per_unit_x as f64 / INCHES_PER_METRE,
+                self.pixels_per_unit_y as f64 / INCHES_PER_METRE
+            )),
+            PhysicalUnit::Unknown => None
+        }
+    }
+
+    /// The width of a pixel divided by its height.
+    pub fn aspect_ratio(&self) -> f64 {
+        self.pixels_per_unit_y as f64 / self.pixels_per_unit_x as f64
+    }
+
+    /// Serializes the dimensions as pHYs chunk data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(9);
+        helpers::write_unsigned_int(&mut result, self.pixels_per_unit_x);
+        helpers::write_unsigned_int(&mut result, self.pixels_per_unit_y);
+        result.push(iif!(self.unit == PhysicalUnit::Metre, 1, 0));
+
+        result
+    }
+}
+
+pub fn parse(data: &[u8]) -> PhysicalDimensionsParseResult {
+    if data.len() != 9 {
+        return Err("Invalid pHYs length".to_string());
+    }
+
+    let dimensions = PhysicalDimensions {
+        pixels_per_unit_x: helpers::read_unsigned_int(&data[0x00..]),
+        pixels_per_unit_y: helpers::read_unsigned_int(&data[0x04..]),
+        unit: match data[0x08] {
+            0 => PhysicalUnit::Unknown,
+            1 => PhysicalUnit::Metre,
+            n => return Err(format!("Invalid pHYs unit specifier: {}", n))
+        }
+    };
+
+    if dimensions.pixels_per_unit_x == 0 || dimensions.pixels_per_unit_y == 0 {
+        return Err("pHYs pixels per unit must not be zero".to_string());
+    }
+
+    Ok(dimensions)
+}
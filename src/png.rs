@@ -1,12 +1,17 @@
 
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::Path;
 
+use apng::{self, AnimationControl, FrameData, Frame, Frames, DisposeOp};
+use bkgd::{self, Background};
+use chrm::{self, Chromaticities};
+use chunk_type::PngChunkType;
 use color::{Color, Color16};
 use color_type::ColorType;
 use decoders::{
     PixelDecoder,
+    IndexDecoder,
 
     // Paletted/Indexed Color Decoders
     OneBitIndexedColorDecoder,
@@ -21,6 +26,7 @@ use decoders::{
     EightBitGreyscaleDecoder,
     SixteenBitGreyscaleDecoder,
     EightBitGreyscaleWithAlphaDecoder,
+    SixteenBitGreyscaleWithAlphaDecoder,
 
     // TrueColor Decoders
     EightBitTrueColorDecoder,
@@ -30,12 +36,32 @@ use decoders::{
 };
 
 use deflate;
+use dither;
+use encoder;
+use exif::{self, Exif};
+use gif::{self, GifGraphicControl, GifApplication};
 use error::PngError;
-use filters::{Filter, NoFilter, Sub, Up, Average, Paeth};
+use filters;
+use hdr::{self, CodingIndependentCodePoints, MasteringDisplayColorVolume, ContentLightLevel};
 use helpers;
+use hist;
 use ihdr;
+use itxt::{self, InternationalText};
+use linear;
+use offs::{self, ImageOffset};
+use options::{DecodeOptions, DepthReduction, EncodeOptions, FilterStrategy, QuantizeOptions};
+use pcal::{self, Calibration};
+use phys::{self, PhysicalDimensions};
+use quantize::{self, Quantized};
+use scal::{self, PhysicalScale};
+use splt::{self, SuggestedPalette};
+use srgb::{self, RenderingIntent};
+use ster::{self, StereoLayout};
+use timestamp::{self, Timestamp};
+use trns::{self, Transparency};
+use xmp::{Xmp, XMP_KEYWORD};
 
-const PNG_HEADER: [u8; 8] = [
+pub const PNG_HEADER: [u8; 8] = [
     0x89,
     'P' as u8,
     'N' as u8,
@@ -67,9 +93,69 @@ pub struct PngFile {
     pub pixels: Vec<Color>,
 
     pub palette: Vec<Color>,
+    pub indices: Vec<u8>,
+    pub linear: Vec<[f32; 4]>,
+    pub calibrated: Vec<f64>,
+    pub frames: Vec<Frame>,
+
+    // acTL, fcTL and fdAT
+    animation: Option<AnimationControl>,
+    frame_data: Vec<FrameData>,
+    default_image_is_frame: bool,
+    next_sequence_number: u32,
+    keep_frame_data: bool,
+
+    // gAMA
+    gamma: Option<u32>,
+
+    // tRNS
+    transparency: Option<Transparency>,
+
+    // bKGD
+    background: Option<Background>,
+
+    // cHRM
+    chromaticities: Option<Chromaticities>,
+
+    // sRGB
+    rendering_intent: Option<RenderingIntent>,
+
+    // cICP, mDCV and cLLI
+    code_points: Option<CodingIndependentCodePoints>,
+    mastering_display: Option<MasteringDisplayColorVolume>,
+    content_light_level: Option<ContentLightLevel>,
+
+    // pHYs
+    physical_dimensions: Option<PhysicalDimensions>,
+
+    // tIME
+    last_modified: Option<Timestamp>,
+
+    // hIST
+    histogram: Vec<u16>,
+
+    // sPLT
+    suggested_palettes: Vec<SuggestedPalette>,
+
+    // eXIf
+    exif: Option<Exif>,
+
+    // iTXt
+    international_text: Vec<InternationalText>,
+
+    // CgBI
+    cgbi: bool,
+
+    // oFFs, pCAL, sCAL, sTER, gIFg and gIFx
+    offset: Option<ImageOffset>,
+    calibration: Option<Calibration>,
+    physical_scale: Option<PhysicalScale>,
+    stereo_layout: Option<StereoLayout>,
+    gif_graphic_controls: Vec<GifGraphicControl>,
+    gif_applications: Vec<GifApplication>,
 
     // sBIT
-    significant_bits: [u8; 4],
+    significant_bits: Vec<u8>,
 
     idx: usize
 }
@@ -94,8 +180,53 @@ impl PngFile {
             pixels: Vec::new(),
 
             palette: Vec::new(),
+            indices: Vec::new(),
+            linear: Vec::new(),
+            calibrated: Vec::new(),
+            frames: Vec::new(),
+
+            animation: None,
+            frame_data: Vec::new(),
+            default_image_is_frame: false,
+            next_sequence_number: 0,
+            keep_frame_data: true,
+
+            gamma: None,
+
+            transparency: None,
+
+            background: None,
+
+            chromaticities: None,
+
+            rendering_intent: None,
+
+            code_points: None,
+            mastering_display: None,
+            content_light_level: None,
+
+            physical_dimensions: None,
+
+            last_modified: None,
+
+            histogram: Vec::new(),
+
+            suggested_palettes: Vec::new(),
+
+            exif: None,
+
+            international_text: Vec::new(),
 
-            significant_bits: [0; 4],
+            cgbi: false,
+
+            offset: None,
+            calibration: None,
+            physical_scale: None,
+            stereo_layout: None,
+            gif_graphic_controls: Vec::new(),
+            gif_applications: Vec::new(),
+
+            significant_bits: Vec::new(),
 
             idx: 0
         }
@@ -107,39 +238,133 @@ impl PngFile {
 
     /// Loads a PNG from given path.
     pub fn from_path<P: AsRef<Path>>(path: P) -> PngLoadResult {
+        Self::from_path_with_options(path, &DecodeOptions::new())
+    }
+
+    /// Loads a PNG from given path, decoding it according
+    /// to the given options.
+    pub fn from_path_with_options<P: AsRef<Path>>(path: P, options: &DecodeOptions) -> PngLoadResult {
         let mut data: Vec<u8> = Vec::new();
         match File::open(path) {
             Ok(mut file) => try!(file.read_to_end(&mut data)),
             Err(err) => return Err(PngError::Io(err))
         };
-        Self::from_data(&data)
+        Self::from_data_with_options(&data, options)
     }
 
     /// Parses a byte slice as a PNG file.
     pub fn from_data(file_data: &[u8]) -> PngLoadResult {
+        Self::from_data_with_options(file_data, &DecodeOptions::new())
+    }
+
+    /// Parses a byte slice as a PNG file, decoding it according
+    /// to the given options.
+    pub fn from_data_with_options(file_data: &[u8], options: &DecodeOptions) -> PngLoadResult {
         let mut png = Self::default();
 
         // Check that we have what looks like a
         // PNG file.
-        let header = &file_data[0..8];
-        if header != PNG_HEADER {
+        if file_data.len() < 8 || &file_data[0..8] != PNG_HEADER {
             Err(PngError::InvalidHeader)
         } else {
             try!(png.read_chunks(&file_data[0x08..]));
-            try!(png.decode_pixel_data());
+            if options.animation && png.animation.is_some() {
+                try!(png.decode_frames());
+            }
+            try!(png.decode_pixel_data(options));
+
+            if options.apply_orientation {
+                png.apply_orientation();
+            }
 
             Ok(png)
         }
     }
 
+    /// Reads the chunks of an animated PNG without decoding any image
+    /// data, returning an iterator that decodes its frames one at
+    /// a time.
+    pub fn frames<'a>(file_data: &'a [u8]) -> Result<Frames<'a>, PngError> {
+        let mut png = Self::default();
+        png.keep_frame_data = false;
+
+        if file_data.len() < 8 || &file_data[0..8] != PNG_HEADER {
+            return Err(PngError::InvalidHeader);
+        }
+
+        try!(png.read_chunks(&file_data[0x08..]));
+        if png.animation.is_none() {
+            return Err(PngError::InvalidFormat("acTL chunk missing".to_string()));
+        }
+
+        Ok(Frames::new(png, &file_data[0x08..]))
+    }
+
+    /// Encodes the PNG in the smallest color type and bit depth that
+    /// stores its pixels exactly, along with its metadata.
+    pub fn to_data(&self) -> Result<Vec<u8>, PngError> {
+        self.to_data_with_options(&EncodeOptions::new())
+    }
+
+    /// Encodes the PNG according to the given options.
+    pub fn to_data_with_options(&self, options: &EncodeOptions) -> Result<Vec<u8>, PngError> {
+        Ok(try!(encoder::encode(self, options)))
+    }
+
+    /// Reduces the PNG's pixels to a palette, which can be encoded by
+    /// setting the pixels and the pixel format of the encode options
+    /// from the result.
+    pub fn quantize(&self, options: &QuantizeOptions) -> Result<Quantized, PngError> {
+        Ok(try!(quantize::quantize(&self.pixels, self.w, options)))
+    }
+
+    /// Reduces the PNG's pixels to a palette and encodes it as an
+    /// indexed color image.
+    pub fn to_quantized_data(&self, options: &QuantizeOptions) -> Result<Vec<u8>, PngError> {
+        Ok(try!(encoder::encode_quantized(self, options)))
+    }
+
+    /// Encodes the PNG and writes it to the given path.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PngError> {
+        self.save_with_options(path, &EncodeOptions::new())
+    }
+
+    /// Encodes the PNG according to the given options and writes it
+    /// to the given path.
+    pub fn save_with_options<P: AsRef<Path>>(&self, path: P, options: &EncodeOptions) -> Result<(), PngError> {
+        let data = try!(self.to_data_with_options(options));
+        let mut file = try!(File::create(path));
+        try!(file.write_all(&data));
+
+        Ok(())
+    }
+
+    /// Compresses the image data with every filter strategy and reports
+    /// the compressed size in bytes for each.
+    pub fn compressed_sizes(&self) -> Result<Vec<(FilterStrategy, usize)>, PngError> {
+        Ok(try!(encoder::compressed_sizes(self)))
+    }
+
     #[inline(always)]
     fn advance(&mut self, distance: usize) {
         self.idx += distance;
     }
 
     pub fn read_chunks(&mut self, data: &[u8]) -> PngParseResult {
+        // PNGs optimised by Apple's tools for iOS have a CgBI chunk
+        // before the IHDR chunk
+        if data.len() >= 8 && &data[0x04..0x08] == b"CgBI" {
+            self.cgbi = true;
+            self.advance(helpers::read_unsigned_int(data) as usize + 0x0C);
+        }
+
         self.advance(4); // Jump over the IHDR u32 length bytes
 
+        // The IHDR chunk type, data and CRC must all be present
+        if data.len() < self.idx + 0x15 {
+            return Err("IHDR chunk missing".to_string());
+        }
+
         // The ImageHeader (IHDR) chunk should be first
         let ihdr = &data[self.idx..self.idx+4];
         if ihdr == b"IHDR" {
@@ -151,26 +376,71 @@ impl PngFile {
             // work with it
             loop {
                 // Read the chunk length, type and its data
+                if data.len() < self.idx + 0x0C {
+                    return Err("Unexpected end of file".to_string());
+                }
                 let chunk_length = helpers::read_unsigned_int(&data[self.idx..]) as usize;
+                if data.len() - self.idx - 0x0C < chunk_length {
+                    return Err("Chunk data runs past the end of the file".to_string());
+                }
                 let chunk_type = &data[self.idx + 0x04..self.idx + 0x08];
                 let chunk_data = &data[self.idx + 0x08..self.idx + chunk_length + 0x08];
 
-                match chunk_type {
-                    b"IDAT" => self.image_data_chunks.push(chunk_data.iter().cloned().collect()),
-                    b"PLTE" => {
+                match PngChunkType::from(chunk_type) {
+                    PngChunkType::ImageData => {
+                        // An fcTL before the first IDAT makes the default image
+                        // the first frame of the animation
+                        if self.image_data_chunks.is_empty() && self.frame_data.len() == 1 {
+                            self.default_image_is_frame = true;
+                        }
+                        if self.default_image_is_frame && self.keep_frame_data {
+                            self.frame_data[0].chunks.push(chunk_data.iter().cloned().collect());
+                        }
+                        self.image_data_chunks.push(chunk_data.iter().cloned().collect())
+                    },
+                    PngChunkType::AnimationControl => try!(self.parse_actl(&chunk_data)),
+                    PngChunkType::FrameControl => try!(self.parse_fctl(&chunk_data)),
+                    PngChunkType::FrameData => try!(self.parse_fdat(&chunk_data)),
+                    PngChunkType::Palette => {
                         if chunk_length % 3 == 0 {
                             self.build_palette(&chunk_data);
                         } else {
                             return Err("Invalid palette length".to_string());
                         }
                     },
-                    b"sBIT" => self.parse_sbit(&chunk_data),
-                    b"IEND" => { break; },
-                    n => /*println!("Found chunk: {}", String::from_utf8(n.iter().cloned().collect()).unwrap())*/()
+                    PngChunkType::SignificantBits => try!(self.parse_sbit(&chunk_data)),
+                    PngChunkType::Gamma => try!(self.parse_gama(&chunk_data)),
+                    PngChunkType::Transparency => try!(self.parse_trns(&chunk_data)),
+                    PngChunkType::BackgroundColor => try!(self.parse_bkgd(&chunk_data)),
+                    PngChunkType::Chromaticity => try!(self.parse_chrm(&chunk_data)),
+                    PngChunkType::RGBColorSpace => try!(self.parse_srgb(&chunk_data)),
+                    PngChunkType::CodingIndependentCodePoints => self.code_points = Some(try!(hdr::parse_cicp(&chunk_data))),
+                    PngChunkType::MasteringDisplayColorVolume => self.mastering_display = Some(try!(hdr::parse_mdcv(&chunk_data))),
+                    PngChunkType::ContentLightLevel => self.content_light_level = Some(try!(hdr::parse_clli(&chunk_data))),
+                    PngChunkType::PhysicalPixelDimensions => self.physical_dimensions = Some(try!(phys::parse(&chunk_data))),
+                    PngChunkType::LastModifiedTime => self.last_modified = Some(try!(timestamp::parse(&chunk_data))),
+                    PngChunkType::Histogram => self.histogram = try!(hist::parse(&chunk_data, self.palette.len())),
+                    PngChunkType::SuggestedPalette => try!(self.parse_splt(&chunk_data)),
+                    PngChunkType::Exif => self.exif = Some(try!(exif::parse(&chunk_data))),
+                    PngChunkType::InternationalTextualData => self.international_text.push(try!(itxt::parse(&chunk_data))),
+                    PngChunkType::ImageOffset => self.offset = Some(try!(offs::parse(&chunk_data))),
+                    PngChunkType::PixelCalibration => self.calibration = Some(try!(pcal::parse(&chunk_data))),
+                    PngChunkType::PhysicalScale => self.physical_scale = Some(try!(scal::parse(&chunk_data))),
+                    PngChunkType::StereoLayout => self.stereo_layout = Some(try!(ster::parse(&chunk_data))),
+                    PngChunkType::GifGraphicControl => self.gif_graphic_controls.push(try!(gif::parse_gifg(&chunk_data))),
+                    PngChunkType::GifApplication => self.gif_applications.push(try!(gif::parse_gifx(&chunk_data))),
+                    PngChunkType::End => { break; },
+                    _ => /*println!("Found chunk: {}", String::from_utf8(chunk_type.iter().cloned().collect()).unwrap())*/()
                 };
 
                 self.advance(chunk_data.len() + 0x0C); // The chunk length, type, data and CRC
             }
+
+            if let Some(ref animation) = self.animation {
+                if self.frame_data.len() != animation.num_frames as usize {
+                    return Err("acTL frame count does not match the number of frames".to_string());
+                }
+            }
         } else {
             return Err("IHDR chunk missing".to_string())
         }
@@ -221,7 +491,11 @@ impl PngFile {
                 self.bits_per_pixel = self.bit_depth;
             },
             ColorType::GreyscaleWithAlpha => {
-                self.bits_per_pixel = 16;
+                if self.bit_depth == 16 {
+                    self.bits_per_pixel = 32;
+                } else {
+                    self.bits_per_pixel = 16;
+                }
             },
             ColorType::TrueColorWithAlpha => {
                 if self.bit_depth == 16 {
@@ -236,82 +510,216 @@ impl PngFile {
     }
 
     /// Decodes concatenated IDAT chunks and converts the raw
-    /// data into a Vector of Color objects
-    fn decode_pixel_data(&mut self) -> PngParseResult {
+    /// data into a Vector of Color objects, or into palette indices
+    /// or linear light values when asked to.
+    fn decode_pixel_data(&mut self, options: &DecodeOptions) -> PngParseResult {
         let mut pixels = try!(self.get_pixel_data());
-        let row_size = 1 + (self.bits_per_pixel * self.w + 7) / 8;
+        let (w, h) = (self.w, self.h);
+        let row_size = self.row_size(w);
         self.pitch = row_size - 1;
 
-        self.apply_filters(&mut pixels, row_size);
-        self.pixels = self.build_pixels(&mut pixels, row_size);
+        try!(self.apply_filters(&mut pixels, w, h));
+
+        if self.cgbi {
+            try!(self.convert_cgbi(&mut pixels, w, h));
+        }
+
+        if options.keep_indices && self.color_type == ColorType::IndexedColor {
+            if options.linear || options.target_gamut.is_some() || options.premultiply || options.significant_bits {
+                return Err("Palette indices can't be kept while also converting colors".to_string());
+            }
+
+            self.indices = self.build_indices(&pixels, row_size);
+            if options.calibrate && self.calibration.is_some() {
+                let samples = self.build_samples(&pixels, w, h);
+                self.calibrated = self.build_calibrated(&samples);
+            }
+            return Ok(());
+        }
+
+        let calibrate = options.calibrate && self.calibration.is_some();
+        let significant = options.significant_bits && !self.significant_bits.is_empty();
+        let convert = options.linear || options.target_gamut.is_some();
+        let sixteen = self.bit_depth == 16;
+        let needs_samples = self.has_color_key() || calibrate || significant || convert ||
+                            (sixteen && (options.premultiply || options.depth_reduction != DepthReduction::Round));
+        if !needs_samples {
+            self.pixels = self.build_pixels(&pixels, w, h);
+            if options.premultiply {
+                self.premultiply();
+            }
+            return Ok(());
+        }
+
+        let mut samples = self.build_samples(&pixels, w, h);
+        let mut depth = self.sample_depth();
+
+        // Calibration maps the samples as they are stored
+        if calibrate {
+            self.calibrated = self.build_calibrated(&samples);
+        }
+
+        // Only the significant bits are kept, rescaled to 16 bits when
+        // the samples are reduced or converted later on so that they
+        // lose no precision
+        if significant {
+            depth = iif!(sixteen || convert, 16, 8);
+            samples = self.significant_samples(&samples, depth);
+        }
+
+        if convert {
+            self.linear = linear::decode(&samples, depth, &self.transfer(options));
+            if let Some(ref target) = options.target_gamut {
+                self.convert_gamut(target);
+            }
+
+            if options.linear {
+                if options.premultiply {
+                    for pixel in &mut self.linear {
+                        let alpha = pixel[3];
+                        for c in &mut pixel[..3] {
+                            *c *= alpha;
+                        }
+                    }
+                }
+                return Ok(());
+            }
+
+            // Gamut conversion happens in linear light, after which
+            // the pixels are encoded as sRGB again, at 16 bits when
+            // they are reduced to 8 afterwards
+            if sixteen {
+                samples = linear::linear_to_srgb16(&self.linear);
+                depth = 16;
+            } else {
+                samples = linear::linear_to_srgb8(&self.linear).iter().map(|c| {
+                    Color16::new(c.r as u16, c.g as u16, c.b as u16, c.a as u16)
+                }).collect();
+                depth = 8;
+            }
+            self.linear = Vec::new();
+        }
+
+        // 16-bit samples are premultiplied before they are reduced
+        // to 8 bits so that no precision is lost
+        if depth == 16 && options.premultiply {
+            samples = samples.iter().map(|s| s.premultiply()).collect();
+        }
+        self.pixels = self.scale_samples(&samples, depth, w, &options.depth_reduction);
+        if depth != 16 && options.premultiply {
+            self.premultiply();
+        }
 
         Ok(())
     }
 
+    /// Scales samples at the given depth to 8 bits, reducing 16-bit
+    /// samples with the given method.
+    fn scale_samples(&self, samples: &[Color16], depth: u8, w: usize, method: &DepthReduction) -> Vec<Color> {
+        if depth != 16 {
+            return samples.iter().map(|s| {
+                Color::new(
+                    helpers::rescale(s.r, depth, 8) as u8,
+                    helpers::rescale(s.g, depth, 8) as u8,
+                    helpers::rescale(s.b, depth, 8) as u8,
+                    helpers::rescale(s.a, depth, 8) as u8
+                )
+            }).collect();
+        }
+
+        match *method {
+            DepthReduction::Round => samples.iter().map(|s| {
+                Color::new(
+                    helpers::sixteen_to_eight(s.r),
+                    helpers::sixteen_to_eight(s.g),
+                    helpers::sixteen_to_eight(s.b),
+                    helpers::sixteen_to_eight(s.a)
+                )
+            }).collect(),
+            DepthReduction::Ordered => dither::ordered(samples, w),
+            DepthReduction::FloydSteinberg => dither::floyd_steinberg(samples, w)
+        }
+    }
+
+    /// Multiplies the color of every pixel by its alpha.
+    pub fn premultiply(&mut self) {
+        for pixel in &mut self.pixels {
+            *pixel = pixel.premultiply();
+        }
+    }
+
+    /// Divides the color of every premultiplied pixel by its alpha,
+    /// reversing `premultiply`.
+    pub fn unpremultiply(&mut self) {
+        for pixel in &mut self.pixels {
+            *pixel = pixel.unpremultiply();
+        }
+    }
+
+    /// The size in bytes of a scanline of the given width, including
+    /// its filter type byte.
+    fn row_size(&self, w: usize) -> usize {
+        1 + (self.bits_per_pixel * w + 7) / 8
+    }
+
     /// Applies scanline filtering depending on the filter type
     /// that is specified at the start of each row.
-    fn apply_filters(&self, pixels: &mut [u8], row_size: usize) {
-        for y in 0..self.h {
-            let mut i = 0;
+    fn apply_filters(&self, pixels: &mut [u8], w: usize, h: usize) -> PngParseResult {
+        let row_size = self.row_size(w);
+        if pixels.len() < row_size * h {
+            return Err("Not enough image data".to_string());
+        }
+
+        for y in 0..h {
             let row_start = y * row_size;
-            let filter_type = pixels[row_start];
-            let pixel_start = row_start + 1;
-            let filter: Box<Filter> = 
-                match filter_type {
-                    0 => Box::new(NoFilter),
-                    1 => Box::new(Sub),
-                    2 => Box::new(Up),
-                    3 => Box::new(Average),
-                    4 => Box::new(Paeth),
-                    _ => unreachable!()
-                };
+            let (above, rest) = pixels.split_at_mut(row_start);
+            let filter_type = rest[0];
+            let previous = iif!(y > 0, Some(&above[row_start - row_size + 1..]), None);
 
-            let mut i = 0;
-            while i < self.pitch {
-                let x = pixel_start + i;
-                let p = pixels[x] as u16;
-                let x_okay = x - pixel_start > self.bytes_per_pixel - 1;
-                let y_okay = y > 0;
-                let a = iif!(x_okay, pixels[x - self.bytes_per_pixel] as u16, 0); 
-                let b = iif!(y_okay, pixels[x - row_size] as u16, 0);
-                let c = iif!(x_okay && y_okay, pixels[x - row_size - self.bytes_per_pixel] as u16, 0);
+            try!(filters::unfilter_row(filter_type, &mut rest[1..row_size], previous, self.bytes_per_pixel));
+        }
 
-                pixels[x] = filter.apply(p, a, b, c);
+        Ok(())
+    }
 
-                i += 1;
-            }
+    /// Picks the pixel decoder for this image's color type
+    /// and bit depth.
+    fn pixel_decoder(&self) -> Box<PixelDecoder> {
+        match (&self.color_type, self.bit_depth) {
+            (&ColorType::IndexedColor, 1) => Box::new(OneBitIndexedColorDecoder),
+            (&ColorType::IndexedColor, 2) => Box::new(TwoBitIndexedColorDecoder),
+            (&ColorType::IndexedColor, 4) => Box::new(FourBitIndexedColorDecoder),
+            (&ColorType::IndexedColor, 8) => Box::new(EightBitIndexedColorDecoder),
+            (&ColorType::Greyscale, 1) => Box::new(OneBitGreyscaleDecoder),
+            (&ColorType::Greyscale, 2) => Box::new(TwoBitGreyscaleDecoder),
+            (&ColorType::Greyscale, 4) => Box::new(FourBitGreyscaleDecoder),
+            (&ColorType::Greyscale, 8) => Box::new(EightBitGreyscaleDecoder),
+            (&ColorType::Greyscale, 16) => Box::new(SixteenBitGreyscaleDecoder),
+            (&ColorType::GreyscaleWithAlpha, 8) => Box::new(EightBitGreyscaleWithAlphaDecoder),
+            (&ColorType::GreyscaleWithAlpha, 16) => Box::new(SixteenBitGreyscaleWithAlphaDecoder),
+            (&ColorType::TrueColor, 8) => Box::new(EightBitTrueColorDecoder),
+            (&ColorType::TrueColor, 16) => Box::new(SixteenBitTrueColorDecoder),
+            (&ColorType::TrueColorWithAlpha, 8) => Box::new(EightBitTrueColorWithAlphaDecoder),
+            (&ColorType::TrueColorWithAlpha, 16) => Box::new(SixteenBitTrueColorWithAlphaDecoder),
+            _ => unreachable!()
         }
     }
 
-    fn build_pixels(&self, pixels: &mut [u8], row_size: usize) -> Vec<Color> {
+    fn build_pixels(&self, pixels: &[u8], w: usize, h: usize) -> Vec<Color> {
         let mut result = Vec::new();
-        let decoder: Box<PixelDecoder> = 
-            match (&self.color_type, self.bit_depth) {
-                (&ColorType::IndexedColor, 1) => Box::new(OneBitIndexedColorDecoder),
-                (&ColorType::IndexedColor, 2) => Box::new(TwoBitIndexedColorDecoder),
-                (&ColorType::IndexedColor, 4) => Box::new(FourBitIndexedColorDecoder),
-                (&ColorType::IndexedColor, 8) => Box::new(EightBitIndexedColorDecoder),
-                (&ColorType::Greyscale, 1) => Box::new(OneBitGreyscaleDecoder),
-                (&ColorType::Greyscale, 2) => Box::new(TwoBitGreyscaleDecoder),
-                (&ColorType::Greyscale, 4) => Box::new(FourBitGreyscaleDecoder),
-                (&ColorType::Greyscale, 8) => Box::new(EightBitGreyscaleDecoder),
-                (&ColorType::Greyscale, 16) => Box::new(SixteenBitGreyscaleDecoder),
-                (&ColorType::GreyscaleWithAlpha, 8) => Box::new(EightBitGreyscaleWithAlphaDecoder),
-                (&ColorType::TrueColor, 8) => Box::new(EightBitTrueColorDecoder),
-                (&ColorType::TrueColor, 16) => Box::new(SixteenBitTrueColorDecoder),
-                (&ColorType::TrueColorWithAlpha, 8) => Box::new(EightBitTrueColorWithAlphaDecoder),
-                (&ColorType::TrueColorWithAlpha, 16) => Box::new(SixteenBitTrueColorWithAlphaDecoder),
-                _ => unreachable!()
-            };
+        let decoder = self.pixel_decoder();
+        let row_size = self.row_size(w);
+        let pitch = row_size - 1;
 
-        for y in 0..self.h {
+        for y in 0..h {
             let mut i = 0;
             let row_start = y * row_size;
             let pixel_start = row_start + 1;
-            while i < self.pitch {
+            let mut row = Vec::with_capacity(w);
+            while i < pitch {
                 let x = pixel_start + i;
                 let mut val = pixels[x] as u8;
-                result.extend(
+                row.extend(
                     decoder.decode(
                         &pixels[..],
                         x,
@@ -322,11 +730,158 @@ impl PngFile {
 
                 i += decoder.step();
             }
+
+            // Sub-byte bit depths pad the end of each row, so drop
+            // anything decoded from the padding bits
+            row.truncate(w);
+            result.extend(row);
         }
         
         result
     }
 
+    /// Decodes every pixel as its raw samples, at the sample depth
+    /// of the image. Pixels matching the tRNS color key are made
+    /// fully transparent.
+    fn build_samples(&self, pixels: &[u8], w: usize, h: usize) -> Vec<Color16> {
+        let mut result = Vec::with_capacity(w * h);
+        let decoder = self.pixel_decoder();
+        let row_size = self.row_size(w);
+        let pitch = row_size - 1;
+
+        for y in 0..h {
+            let mut i = 0;
+            let pixel_start = y * row_size + 1;
+            let mut row = Vec::with_capacity(w);
+            while i < pitch {
+                let x = pixel_start + i;
+                row.extend(decoder.samples(pixels, x, pixels[x], &self));
+
+                i += decoder.step();
+            }
+
+            row.truncate(w);
+            result.extend(row);
+        }
+
+        match self.transparency {
+            Some(Transparency::Greyscale(grey)) => {
+                for s in result.iter_mut().filter(|s| s.r == grey) {
+                    s.a = 0;
+                }
+            },
+            Some(Transparency::TrueColor(r, g, b)) => {
+                for s in result.iter_mut().filter(|s| s.r == r && s.g == g && s.b == b) {
+                    s.a = 0;
+                }
+            },
+            _ => ()
+        }
+
+        result
+    }
+
+    /// Converts every color sample into its physical value using the
+    /// pCAL calibration. Alpha samples are not calibrated.
+    fn build_calibrated(&self, samples: &[Color16]) -> Vec<f64> {
+        let calibration = self.calibration.as_ref().unwrap();
+        let depth = self.sample_depth();
+        let greyscale = self.color_type == ColorType::Greyscale ||
+                        self.color_type == ColorType::GreyscaleWithAlpha;
+
+        let mut result = Vec::new();
+        for s in samples {
+            if greyscale {
+                result.push(calibration.physical_value(s.r, depth));
+            } else {
+                result.push(calibration.physical_value(s.r, depth));
+                result.push(calibration.physical_value(s.g, depth));
+                result.push(calibration.physical_value(s.b, depth));
+            }
+        }
+
+        result
+    }
+
+    /// Whether the image has a tRNS color key marking one
+    /// greyscale or truecolor value as transparent.
+    fn has_color_key(&self) -> bool {
+        match self.transparency {
+            Some(Transparency::Greyscale(_)) | Some(Transparency::TrueColor(..)) => true,
+            _ => false
+        }
+    }
+
+    /// The depth of the samples produced by `build_samples`. Indexed-color
+    /// images have 8-bit samples from their palette regardless of the
+    /// bit depth of their indices.
+    fn sample_depth(&self) -> u8 {
+        if self.color_type == ColorType::IndexedColor {
+            8
+        } else {
+            self.bit_depth as u8
+        }
+    }
+
+    /// Discards the bits of each sample that the sBIT chunk marks as
+    /// insignificant, then scales the remaining bits to the given depth.
+    fn significant_samples(&self, samples: &[Color16], to: u8) -> Vec<Color16> {
+        let depth = self.sample_depth();
+        let sbit = &self.significant_bits;
+
+        // The significant bits of the red, green, blue and alpha
+        // samples. Zero marks a channel without an sBIT entry.
+        let bits = match self.color_type {
+            ColorType::Greyscale => [sbit[0], sbit[0], sbit[0], 0],
+            ColorType::GreyscaleWithAlpha => [sbit[0], sbit[0], sbit[0], sbit[1]],
+            ColorType::TrueColor | ColorType::IndexedColor => [sbit[0], sbit[1], sbit[2], 0],
+            _ => [sbit[0], sbit[1], sbit[2], sbit[3]]
+        };
+
+        let scale = |sample: u16, bits: u8| -> u16 {
+            if bits == 0 {
+                helpers::rescale(sample, depth, to)
+            } else {
+                helpers::rescale(sample >> (depth - bits), bits, to)
+            }
+        };
+
+        samples.iter().map(|s| {
+            Color16::new(
+                scale(s.r, bits[0]),
+                scale(s.g, bits[1]),
+                scale(s.b, bits[2]),
+                scale(s.a, bits[3])
+            )
+        }).collect()
+    }
+
+    /// image without expanding them through the palette.
+    fn build_indices(&self, pixels: &[u8], row_size: usize) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.w * self.h);
+        let decoder: Box<IndexDecoder> =
+            match self.bit_depth {
+                1 => Box::new(OneBitIndexedColorDecoder),
+                2 => Box::new(TwoBitIndexedColorDecoder),
+                4 => Box::new(FourBitIndexedColorDecoder),
+                8 => Box::new(EightBitIndexedColorDecoder),
+                _ => unreachable!()
+            };
+
+        for y in 0..self.h {
+            let pixel_start = y * row_size + 1;
+            let mut row = Vec::with_capacity(self.w);
+            for i in 0..self.pitch {
+                row.extend(decoder.indices(pixels[pixel_start + i]));
+            }
+
+            row.truncate(self.w);
+            result.extend(row);
+        }
+
+        result
+    }
+
     fn get_pixel_data(&mut self) -> Result<Vec<u8>, String> {
         let mut compressed_data = Vec::new();
 
@@ -334,8 +889,57 @@ impl PngFile {
             compressed_data.append(chunk);
         }
 
-        let prediction = (((self.w / 8) * self.bits_per_pixel) + ((self.w & 7) * self.bits_per_pixel + 7) / 8) * self.h;
-        deflate::decode(&compressed_data[..], || prediction)
+        let (w, h) = (self.w, self.h);
+        self.inflate(&compressed_data[..], w, h)
+    }
+
+    /// Decompresses the image data of an image with the given dimensions.
+    fn inflate(&self, compressed_data: &[u8], w: usize, h: usize) -> Result<Vec<u8>, String> {
+        let prediction = self.row_size(w) * h;
+        if self.cgbi {
+            deflate::decode_raw(compressed_data, || prediction)
+        } else {
+            deflate::decode(compressed_data, || prediction)
+        }
+    }
+
+    /// Converts unfiltered CgBI image data, which stores BGR samples
+    /// with premultiplied alpha, into ordinary RGB samples with
+    /// straight alpha. Only 8-bit truecolor CgBI images are supported.
+    fn convert_cgbi(&self, pixels: &mut [u8], w: usize, h: usize) -> PngParseResult {
+        let row_size = self.row_size(w);
+        let has_alpha = match (&self.color_type, self.bit_depth) {
+            (&ColorType::TrueColor, 8) => false,
+            (&ColorType::TrueColorWithAlpha, 8) => true,
+            _ => return Err("Only 8-bit truecolor CgBI images are supported".to_string())
+        };
+
+        for y in 0..h {
+            let pixel_start = y * row_size + 1;
+            for pixel in pixels[pixel_start..pixel_start + row_size - 1].chunks_mut(self.bytes_per_pixel) {
+                pixel.swap(0, 2);
+
+                if has_alpha {
+                    let c = Color::new(pixel[0], pixel[1], pixel[2], pixel[3]).unpremultiply();
+                    pixel[0] = c.r;
+                    pixel[1] = c.g;
+                    pixel[2] = c.b;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The number of bits per sample, or per palette index, of the
+    /// image data.
+    pub fn bit_depth(&self) -> usize {
+        self.bit_depth
+    }
+
+    /// Whether the file is an Apple CgBI PNG.
+    pub fn is_cgbi(&self) -> bool {
+        self.cgbi
     }
 
     fn build_palette(&mut self, data: &[u8]) {
@@ -348,21 +952,684 @@ impl PngFile {
        }
     }
 
-    fn parse_sbit(&mut self, data: &[u8]) {
-        if self.color_type == ColorType::Greyscale {
-            self.significant_bits[0] = data[0];
-        } else if self.color_type == ColorType::TrueColor || self.color_type == ColorType::IndexedColor {
-            self.significant_bits[0] = data[0];
-            self.significant_bits[1] = data[1];
-            self.significant_bits[2] = data[2];
-        } else if self.color_type == ColorType::GreyscaleWithAlpha {
-            self.significant_bits[0] = data[0];
-            self.significant_bits[1] = data[1];
+    /// The significant bits of each channel recorded by the sBIT
+    /// chunk, in the order the channels are stored, if there is one.
+    pub fn significant_bits(&self) -> Option<&[u8]> {
+        if self.significant_bits.is_empty() {
+            None
+        } else {
+            Some(&self.significant_bits[..])
+        }
+    }
+
+    fn parse_sbit(&mut self, data: &[u8]) -> PngParseResult {
+        let channels = match self.color_type {
+            ColorType::Greyscale => 1,
+            ColorType::GreyscaleWithAlpha => 2,
+            ColorType::TrueColor | ColorType::IndexedColor => 3,
+            _ => 4
+        };
+
+        if data.len() != channels {
+            return Err("Invalid sBIT length".to_string());
+        }
+
+        let depth = self.sample_depth();
+        for &bits in data {
+            if bits == 0 || bits > depth {
+                return Err(format!("Invalid sBIT value {} for a sample depth of {}", bits, depth));
+            }
+        }
+
+        self.significant_bits = data.to_vec();
+
+        Ok(())
+    }
+
+    /// The encoding gamma recorded by the gAMA chunk, if there is one.
+    pub fn gamma(&self) -> Option<f64> {
+        self.gamma.map(|g| g as f64 / 100000.0)
+    }
+
+    /// The transfer function the samples are encoded with. PQ and HLG
+    /// signalled by cICP are only used when asked for. Otherwise an
+    /// sRGB chunk takes precedence over gAMA, and images without
+    /// either are assumed to be sRGB.
+    fn transfer(&self, options: &DecodeOptions) -> linear::Transfer {
+        if let Some(ref code_points) = self.code_points {
+            if options.hdr_transfer && code_points.is_pq() {
+                return linear::Transfer::Pq;
+            }
+
+            if options.hdr_transfer && code_points.is_hlg() {
+                return linear::Transfer::Hlg;
+            }
+        }
+
+        if self.rendering_intent.is_some() {
+            return linear::Transfer::Srgb;
+        }
+
+        match self.gamma() {
+            Some(gamma) => linear::Transfer::Gamma(gamma),
+            None => linear::Transfer::Srgb
+        }
+    }
+
+    fn parse_gama(&mut self, data: &[u8]) -> PngParseResult {
+        if data.len() != 4 {
+            return Err("Invalid gAMA length".to_string());
+        }
+
+        let gamma = helpers::read_unsigned_int(data);
+        if gamma == 0 {
+            return Err("Invalid gAMA value of zero".to_string());
+        }
+
+        self.gamma = Some(gamma);
+
+        Ok(())
+    }
+
+    /// The transparency information from the tRNS chunk, if there is one.
+    pub fn transparency(&self) -> Option<&Transparency> {
+        self.transparency.as_ref()
+    }
+
+    /// The default background from the bKGD chunk, if there is one.
+    pub fn background(&self) -> Option<&Background> {
+        self.background.as_ref()
+    }
+
+    /// The default background from the bKGD chunk as an opaque
+    /// 8-bit color, if there is one.
+    pub fn background_color(&self) -> Option<Color> {
+        let depth = self.bit_depth as u8;
+        self.background.as_ref().map(|background| {
+            match *background {
+                Background::PaletteIndex(i) => {
+                    let c = &self.palette[i as usize];
+                    Color::new(c.r, c.g, c.b, 255)
+                },
+                Background::Greyscale(grey) => {
+                    let v = helpers::rescale(grey, depth, 8) as u8;
+                    Color::new(v, v, v, 255)
+                },
+                Background::TrueColor(r, g, b) => {
+                    Color::new(
+                        helpers::rescale(r, depth, 8) as u8,
+                        helpers::rescale(g, depth, 8) as u8,
+                        helpers::rescale(b, depth, 8) as u8,
+                        255
+                    )
+                }
+            }
+        })
+    }
+
+    /// Composites every pixel onto the background from the bKGD chunk,
+    /// leaving the image fully opaque. Images without a bKGD chunk are
+    /// composited onto white.
+    pub fn flatten(&mut self) {
+        let background = self.background_color().unwrap_or(Color::new(255, 255, 255, 255));
+        self.flatten_onto(&background);
+    }
+
+    /// Composites every pixel onto the given color, leaving the image
+    /// fully opaque.
+    pub fn flatten_onto(&mut self, background: &Color) {
+        for pixel in &mut self.pixels {
+            let a = pixel.a as u16;
+            let blend = |fg: u8, bg: u8| ((fg as u16 * a + bg as u16 * (255 - a) + 127) / 255) as u8;
+
+            *pixel = Color::new(
+                blend(pixel.r, background.r),
+                blend(pixel.g, background.g),
+                blend(pixel.b, background.b),
+                255
+            );
+        }
+    }
+
+    fn parse_trns(&mut self, data: &[u8]) -> PngParseResult {
+        let transparency = try!(trns::parse(data, &self.color_type, self.bit_depth as u8, self.palette.len()));
+
+        if let Transparency::PaletteAlpha(ref alpha) = transparency {
+            for (color, &a) in self.palette.iter_mut().zip(alpha.iter()) {
+                color.a = a;
+            }
+        }
+
+        self.transparency = Some(transparency);
+
+        Ok(())
+    }
+
+    fn parse_bkgd(&mut self, data: &[u8]) -> PngParseResult {
+        let background = try!(bkgd::parse(data, &self.color_type, self.bit_depth as u8, self.palette.len()));
+        self.background = Some(background);
+
+        Ok(())
+    }
+
+    /// The white point and primaries from the cHRM chunk, if there is one.
+    pub fn chromaticities(&self) -> Option<&Chromaticities> {
+        self.chromaticities.as_ref()
+    }
+
+    /// The rendering intent from the sRGB chunk, if there is one.
+    pub fn rendering_intent(&self) -> Option<&RenderingIntent> {
+        self.rendering_intent.as_ref()
+    }
+
+    /// The video signal code points from the cICP chunk, if there is one.
+    pub fn code_points(&self) -> Option<&CodingIndependentCodePoints> {
+        self.code_points.as_ref()
+    }
+
+    /// The mastering display color volume from the mDCV chunk, if there is one.
+    pub fn mastering_display(&self) -> Option<&MasteringDisplayColorVolume> {
+        self.mastering_display.as_ref()
+    }
+
+    /// The content light level from the cLLI chunk, if there is one.
+    pub fn content_light_level(&self) -> Option<&ContentLightLevel> {
+        self.content_light_level.as_ref()
+    }
+
+    /// The primaries the image's colors are defined against. Known
+    /// cICP primaries take precedence, then an sRGB chunk, then cHRM.
+    /// Images without any of these are assumed to be sRGB.
+    pub fn source_chromaticities(&self) -> Chromaticities {
+        if let Some(chromaticities) = self.code_points.as_ref().and_then(|c| c.chromaticities()) {
+            return chromaticities;
+        }
+
+        match (&self.rendering_intent, &self.chromaticities) {
+            (&None, &Some(ref chromaticities)) => chromaticities.clone(),
+            _ => Chromaticities::srgb()
+        }
+    }
+
+    /// The matrix converting the image's linear RGB values into CIE XYZ.
+    pub fn rgb_to_xyz(&self) -> chrm::Matrix {
+        self.source_chromaticities().to_xyz()
+    }
+
+    /// Converts the linear light pixels from the image's primaries
+    /// to the target primaries.
+    fn convert_gamut(&mut self, target: &Chromaticities) {
+        let source = self.source_chromaticities();
+        if source == *target {
+            return;
+        }
+
+        let matrix = chrm::conversion_matrix(&source, target);
+        for pixel in &mut self.linear {
+            let rgb = chrm::transform(&matrix, [pixel[0] as f64, pixel[1] as f64, pixel[2] as f64]);
+            pixel[0] = rgb[0] as f32;
+            pixel[1] = rgb[1] as f32;
+            pixel[2] = rgb[2] as f32;
+        }
+    }
+
+    fn parse_chrm(&mut self, data: &[u8]) -> PngParseResult {
+        self.chromaticities = Some(try!(chrm::parse(data)));
+
+        Ok(())
+    }
+
+    fn parse_srgb(&mut self, data: &[u8]) -> PngParseResult {
+        self.rendering_intent = Some(try!(srgb::parse(data)));
+
+        Ok(())
+    }
+
+    /// The intended pixel size or aspect ratio from the pHYs chunk,
+    /// if there is one.
+    pub fn physical_dimensions(&self) -> Option<&PhysicalDimensions> {
+        self.physical_dimensions.as_ref()
+    }
+
+    /// Sets the pHYs physical dimensions written when encoding.
+    pub fn set_physical_dimensions(&mut self, dimensions: Option<PhysicalDimensions>) {
+        self.physical_dimensions = dimensions;
+    }
+
+    /// The horizontal and vertical resolution in dots per inch, if
+    /// the pHYs chunk gives them.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        self.physical_dimensions.as_ref().and_then(|d| d.dpi())
+    }
+
+    /// The width of a pixel divided by its height. Pixels are
+    /// square unless the pHYs chunk says otherwise.
+    pub fn pixel_aspect_ratio(&self) -> f64 {
+        self.physical_dimensions.as_ref().map_or(1.0, |d| d.aspect_ratio())
+    }
+
+    /// The time of the image's last modification from the tIME chunk,
+    /// if there is one.
+    pub fn last_modified(&self) -> Option<&Timestamp> {
+        self.last_modified.as_ref()
+    }
+
+    /// Sets the tIME timestamp written when encoding. Use
+    /// `Timestamp::now()` to record the time the image is regenerated.
+    pub fn set_last_modified(&mut self, timestamp: Option<Timestamp>) {
+        self.last_modified = timestamp;
+    }
+
+    /// The usage frequency of each palette entry from the hIST
+    /// chunk, if there is one.
+    pub fn histogram(&self) -> Option<&[u16]> {
+        if self.histogram.is_empty() {
+            None
         } else {
-            self.significant_bits[0] = data[0];
-            self.significant_bits[1] = data[1];
-            self.significant_bits[2] = data[2];
-            self.significant_bits[3] = data[3];
+            Some(&self.histogram[..])
+        }
+    }
+
+    /// The palettes suggested by the file's sPLT chunks.
+    pub fn suggested_palettes(&self) -> &[SuggestedPalette] {
+        &self.suggested_palettes[..]
+    }
+
+    fn parse_splt(&mut self, data: &[u8]) -> PngParseResult {
+        let palette = try!(splt::parse(data));
+        if self.suggested_palettes.iter().any(|p| p.name == palette.name) {
+            return Err(format!("Duplicate sPLT palette name: {}", palette.name));
+        }
+
+        self.suggested_palettes.push(palette);
+
+        Ok(())
+    }
+
+    /// The EXIF metadata from the eXIf chunk, if there is one.
+    pub fn exif(&self) -> Option<&Exif> {
+        self.exif.as_ref()
+    }
+
+    /// Transforms the decoded image so that it is upright according
+    /// to its EXIF orientation. The EXIF metadata itself is left as is.
+    fn apply_orientation(&mut self) {
+        let orientation = match self.exif.as_ref().and_then(|e| e.orientation()) {
+            Some(orientation) => orientation,
+            None => return
+        };
+
+        let (w, h) = (self.w, self.h);
+        if !self.pixels.is_empty() {
+            self.pixels = orientation.apply(&self.pixels, w, h).0;
+        }
+        if !self.indices.is_empty() {
+            self.indices = orientation.apply(&self.indices, w, h).0;
+        }
+        if !self.linear.is_empty() {
+            self.linear = orientation.apply(&self.linear, w, h).0;
+        }
+
+        if orientation.swaps_dimensions() {
+            self.w = h;
+            self.h = w;
+        }
+    }
+
+    /// The text from the file's iTXt chunks.
+    pub fn international_text(&self) -> &[InternationalText] {
+        &self.international_text[..]
+    }
+
+    /// Adds text that is written as an iTXt chunk when encoding.
+    pub fn add_international_text(&mut self, text: InternationalText) {
+        self.international_text.push(text);
+    }
+
+    /// The XMP packet from the iTXt chunk keyed by `XML:com.adobe.xmp`,
+    /// if there is one.
+    pub fn xmp(&self) -> Option<Xmp> {
+        self.international_text.iter()
+            .find(|t| t.keyword == XMP_KEYWORD)
+            .map(|t| Xmp::new(&t.text))
+    }
+
+    /// Replaces the XMP packet written when encoding, or removes it.
+    pub fn set_xmp(&mut self, xmp: Option<Xmp>) {
+        self.international_text.retain(|t| t.keyword != XMP_KEYWORD);
+        if let Some(xmp) = xmp {
+            // XMP is stored uncompressed so that tools scanning
+            // for the packet can find it
+            self.international_text.push(InternationalText::new(XMP_KEYWORD, &xmp.packet));
+        }
+    }
+
+    /// The position of the image on a larger page from the oFFs
+    /// chunk, if there is one.
+    pub fn offset(&self) -> Option<&ImageOffset> {
+        self.offset.as_ref()
+    }
+
+    /// The mapping of samples to physical values from the pCAL
+    /// chunk, if there is one.
+    pub fn calibration(&self) -> Option<&Calibration> {
+        self.calibration.as_ref()
+    }
+
+    /// The physical size of each pixel from the sCAL chunk, if
+    /// there is one.
+    pub fn physical_scale(&self) -> Option<&PhysicalScale> {
+        self.physical_scale.as_ref()
+    }
+
+    /// The stereo image layout from the sTER chunk, if there is one.
+    pub fn stereo_layout(&self) -> Option<&StereoLayout> {
+        self.stereo_layout.as_ref()
+    }
+
+    /// The GIF Graphic Control Extensions from the file's gIFg chunks.
+    pub fn gif_graphic_controls(&self) -> &[GifGraphicControl] {
+        &self.gif_graphic_controls[..]
+    }
+
+    /// The GIF Application Extensions from the file's gIFx chunks.
+    pub fn gif_applications(&self) -> &[GifApplication] {
+        &self.gif_applications[..]
+    }
+
+    /// The number of frames and plays of an animated PNG.
+    pub fn animation(&self) -> Option<&AnimationControl> {
+        self.animation.as_ref()
+    }
+
+    /// How many times an animated PNG should be played, where 0
+    /// means it loops forever.
+    pub fn loop_count(&self) -> Option<u32> {
+        self.animation.as_ref().map(|a| a.num_plays)
+    }
+
+    /// Checks that an fcTL or fdAT chunk is the next chunk in the
+    /// animation's sequence.
+    fn check_sequence_number(&mut self, sequence_number: u32) -> PngParseResult {
+        if sequence_number != self.next_sequence_number {
+            return Err(format!("Expected APNG sequence number {}, found {}", self.next_sequence_number, sequence_number));
+        }
+
+        self.next_sequence_number += 1;
+
+        Ok(())
+    }
+
+    fn parse_actl(&mut self, data: &[u8]) -> PngParseResult {
+        if !self.image_data_chunks.is_empty() {
+            return Err("acTL must come before the image data".to_string());
+        }
+
+        if self.animation.is_some() {
+            return Err("Multiple acTL chunks".to_string());
+        }
+
+        self.animation = Some(try!(apng::parse_actl(data)));
+
+        Ok(())
+    }
+
+    fn parse_fctl(&mut self, data: &[u8]) -> PngParseResult {
+        // Without an acTL the file is an ordinary PNG
+        if self.animation.is_none() {
+            return Ok(());
+        }
+
+        let control = try!(apng::parse_fctl(data, self.w, self.h));
+        try!(self.check_sequence_number(control.sequence_number));
+
+        if self.frame_data.is_empty() && self.image_data_chunks.is_empty() &&
+           (control.width != self.w || control.height != self.h || control.x_offset != 0 || control.y_offset != 0) {
+            return Err("The fcTL of the default image must cover the whole image".to_string());
+        }
+
+        self.frame_data.push(FrameData {
+            control: control,
+            chunks: Vec::new()
+        });
+
+        Ok(())
+    }
+
+    fn parse_fdat(&mut self, data: &[u8]) -> PngParseResult {
+        if self.animation.is_none() {
+            return Ok(());
+        }
+
+        if data.len() < 4 {
+            return Err("Invalid fdAT length".to_string());
+        }
+
+        try!(self.check_sequence_number(helpers::read_unsigned_int(data)));
+
+        if self.frame_data.is_empty() || (self.default_image_is_frame && self.frame_data.len() == 1) {
+            return Err("fdAT without a preceding fcTL".to_string());
+        }
+
+        if self.keep_frame_data {
+            let frame = self.frame_data.len() - 1;
+            self.frame_data[frame].chunks.push(data[4..].iter().cloned().collect());
+        }
+
+        Ok(())
+    }
+
+    /// Decodes and composites every frame of the animation onto a
+    /// canvas that starts out transparent black.
+    fn decode_frames(&mut self) -> PngParseResult {
+        let mut canvas = vec![Color::new(0, 0, 0, 0); self.w * self.h];
+        let mut previous = Vec::new();
+        let mut frames = Vec::with_capacity(self.frame_data.len());
+
+        for (i, frame) in self.frame_data.iter().enumerate() {
+            let control = &frame.control;
+            let compressed_data: Vec<u8> = frame.chunks.iter().flat_map(|c| c.iter().cloned()).collect();
+            let region = try!(self.decode_region(&compressed_data, control.width, control.height));
+
+            // A PREVIOUS dispose on the first frame is treated as BACKGROUND
+            let restore = control.dispose_op == DisposeOp::Previous && i > 0;
+            if restore {
+                apng::save_region(&canvas, self.w, control, &mut previous);
+            }
+
+            apng::blend(&mut canvas, self.w, &region, control);
+
+            frames.push(Frame {
+                pixels: canvas.clone(),
+                delay_num: control.delay_num,
+                delay_den: control.delay_den
+            });
+
+            apng::dispose(&mut canvas, self.w, control, iif!(restore, Some(&previous[..]), None));
+        }
+
+        self.frames = frames;
+
+        Ok(())
+    }
+
+    /// Decompresses, unfilters and decodes the image data of a frame
+    /// with the given dimensions.
+    pub(crate) fn decode_region(&self, compressed_data: &[u8], w: usize, h: usize) -> Result<Vec<Color>, String> {
+        let mut pixels = try!(self.inflate(compressed_data, w, h));
+        try!(self.apply_filters(&mut pixels, w, h));
+        if self.cgbi {
+            try!(self.convert_cgbi(&mut pixels, w, h));
+        }
+
+        if self.has_color_key() {
+            let samples = self.build_samples(&pixels, w, h);
+            Ok(self.scale_samples(&samples, self.sample_depth(), w, &DepthReduction::Round))
+        } else {
+            Ok(self.build_pixels(&pixels, w, h))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helpers;
+
+    /// A 16-bit RGBA image of `w` by `h` pixels that all have the given
+    /// samples, with an sBIT chunk.
+    fn sixteen_bit_flat(w: u32, h: u32, sample: [u16; 4], sbit: Vec<u8>) -> Vec<u8> {
+        let mut row = vec![0];
+        for _ in 0..w {
+            for &s in &sample {
+                row.extend_from_slice(&[(s >> 8) as u8, s as u8]);
+            }
+        }
+
+        helpers::png_file(w, h, 16, 6, &[(b"sBIT", sbit), (b"IDAT", helpers::image_data(&row.repeat(h as usize)))])
+    }
+
+    #[test]
+    fn significant_bits_rescale() {
+        // 183 is 10110111, whose top 5 bits are 22 out of 31
+        let raw = [0, 183, 255, 0];
+        let data = helpers::png_file(3, 1, 8, 0, &[(b"sBIT", vec![5]), (b"IDAT", helpers::image_data(&raw))]);
+
+        let grey = |png: &PngFile| png.pixels.iter().map(|p| p.r).collect::<Vec<u8>>();
+        assert_eq!(grey(&PngFile::from_data(&data).unwrap()), vec![183, 255, 0]);
+
+        let mut options = DecodeOptions::new();
+        options.significant_bits = true;
+        assert_eq!(grey(&PngFile::from_data_with_options(&data, &options).unwrap()), vec![181, 255, 0]);
+
+        // Linear output starts from the significant bits as well
+        options.linear = true;
+        let decoded = PngFile::from_data_with_options(&data, &options).unwrap();
+        assert!((decoded.linear[0][0] - linear::srgb_to_linear(22.0 / 31.0)).abs() < 1e-4);
+        assert_eq!(decoded.linear[1][0], 1.0);
+    }
+
+    #[test]
+    fn significant_bits_reduce_and_premultiply_sixteen_bit() {
+        // 12 significant bits of 2064 fall between two 8-bit values
+        let data = sixteen_bit_flat(8, 8, [2064 << 4, 2064 << 4, 2064 << 4, 2048 << 4], vec![12; 4]);
+        let mut options = DecodeOptions::new();
+        options.significant_bits = true;
+
+        let rounded = PngFile::from_data_with_options(&data, &options).unwrap();
+        assert!(rounded.pixels.iter().all(|p| p.r == 129 && p.a == 128));
+
+        options.depth_reduction = DepthReduction::Ordered;
+        let dithered = PngFile::from_data_with_options(&data, &options).unwrap();
+        assert!(dithered.pixels.iter().any(|p| p.r == 128) && dithered.pixels.iter().any(|p| p.r == 129));
+
+        // Premultiplying happens at 16 bits, before the reduction
+        options.depth_reduction = DepthReduction::Round;
+        options.premultiply = true;
+        let premultiplied = PngFile::from_data_with_options(&data, &options).unwrap();
+        let c = helpers::rescale(2064, 12, 16);
+        let expected = Color16::new(c, c, c, helpers::rescale(2048, 12, 16)).premultiply();
+        assert!(premultiplied.pixels.iter().all(|p| p.r == helpers::sixteen_to_eight(expected.r)));
+    }
+
+    #[test]
+    fn linear_output_is_premultiplied() {
+        let data = sixteen_bit_flat(1, 1, [0xffff, 0xffff, 0xffff, 0x8000], vec![16; 4]);
+        let mut options = DecodeOptions::new();
+        options.linear = true;
+        options.premultiply = true;
+
+        let pixel = PngFile::from_data_with_options(&data, &options).unwrap().linear[0];
+        assert!((pixel[0] - 0x8000 as f32 / 65535.0).abs() < 1e-6);
+        assert_eq!(pixel[0], pixel[3]);
+    }
+
+    #[test]
+    fn keeping_indices_rejects_color_conversion() {
+        let raw = [0, 0x10];
+        let data = helpers::png_file(2, 1, 4, 3, &[(b"PLTE", vec![0, 0, 0, 255, 255, 255]), (b"IDAT", helpers::image_data(&raw))]);
+
+        let mut options = DecodeOptions::new();
+        options.keep_indices = true;
+        assert_eq!(PngFile::from_data_with_options(&data, &options).unwrap().indices, vec![1, 0]);
+
+        options.premultiply = true;
+        assert!(PngFile::from_data_with_options(&data, &options).is_err());
+    }
+
+    #[test]
+    fn parse_sbit_checks_length_and_range() {
+        let mut png = PngFile::new(1, 1);
+        png.color_type = ColorType::TrueColor;
+        png.bit_depth = 8;
+        assert!(png.parse_sbit(&[8, 8]).is_err());
+        assert!(png.parse_sbit(&[8, 8, 8, 8]).is_err());
+        assert!(png.parse_sbit(&[0, 8, 8]).is_err());
+        assert!(png.parse_sbit(&[8, 9, 8]).is_err());
+        assert!(png.parse_sbit(&[1, 5, 8]).is_ok());
+        assert_eq!(png.significant_bits, vec![1, 5, 8]);
+
+        png.color_type = ColorType::GreyscaleWithAlpha;
+        png.bit_depth = 16;
+        assert!(png.parse_sbit(&[16, 17]).is_err());
+        assert!(png.parse_sbit(&[16, 16]).is_ok());
+
+        // Palette samples are 8 bits whatever the index depth
+        png.color_type = ColorType::IndexedColor;
+        png.bit_depth = 2;
+        assert!(png.parse_sbit(&[8, 8, 8]).is_ok());
+        png.color_type = ColorType::Greyscale;
+        assert!(png.parse_sbit(&[3]).is_err());
+        assert!(png.parse_sbit(&[2]).is_ok());
+    }
+
+    /// Builds an Apple CgBI file, with its CgBI chunk before IHDR and
+    /// its image data compressed as raw deflate without a zlib header.
+    fn cgbi_file(w: u32, h: u32, bit_depth: u8, color_type: u8, raw_rows: &[u8]) -> Vec<u8> {
+        let zlib = helpers::image_data(raw_rows);
+        let idat = zlib[2..zlib.len() - 4].to_vec();
+        let file = helpers::png_file(w, h, bit_depth, color_type, &[(b"IDAT", idat)]);
+
+        let mut data = file[..8].to_vec();
+        encoder::write_chunk(&mut data, b"CgBI", &[0x50, 0x00, 0x20, 0x06]);
+        data.extend_from_slice(&file[8..]);
+        data
+    }
+
+    #[test]
+    fn cgbi_swaps_channels_and_unpremultiplies() {
+        // BGRA samples with premultiplied alpha
+        let raw = [0, 25, 50, 100, 128, 30, 20, 10, 255];
+        let decoded = PngFile::from_data(&cgbi_file(2, 1, 8, 6, &raw)).unwrap();
+        assert!(decoded.is_cgbi());
+        assert!(decoded.pixels == vec![Color::new(100, 50, 25, 128).unpremultiply(), Color::new(10, 20, 30, 255)]);
+
+        let raw = [0, 30, 20, 10];
+        let decoded = PngFile::from_data(&cgbi_file(1, 1, 8, 2, &raw)).unwrap();
+        assert!(decoded.pixels == vec![Color::new(10, 20, 30, 255)]);
+    }
+
+    #[test]
+    fn cgbi_rejects_unsupported_formats() {
+        assert!(PngFile::from_data(&cgbi_file(2, 1, 8, 0, &[0, 1, 2])).is_err());
+        assert!(PngFile::from_data(&cgbi_file(1, 1, 16, 2, &[0, 0, 1, 0, 2, 0, 3])).is_err());
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        for data in &[&[][..], &PNG_HEADER[..1], &PNG_HEADER[..7]] {
+            match PngFile::from_data(data) {
+                Err(PngError::InvalidHeader) => (),
+                _ => panic!("a truncated header should be invalid")
+            }
+            assert!(PngFile::frames(data).is_err());
+        }
+
+        // A file that stops partway through its chunks
+        let raw = [0, 1, 2, 3];
+        let data = helpers::png_file(1, 1, 8, 6, &[(b"IDAT", helpers::image_data(&raw))]);
+        for length in 8..data.len() - 12 {
+            assert!(PngFile::from_data(&data[..length]).is_err());
         }
     }
 }
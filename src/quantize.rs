@@ -0,0 +1,318 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use color::Color;
+use dither;
+use linear;
+use options::QuantizeOptions;
+use reduce::{self, PixelFormat};
+
+/// The rounds of k-means that refine the palette found by median cut.
+const REFINEMENT_ROUNDS: usize = 3;
+
+/// The root mean square error, in Oklab units, that is allowed for each
+/// point of quality below 100.
+const ERROR_PER_QUALITY: f32 = 0.001;
+
+/// A color in Oklab premultiplied by its alpha, with the alpha as a
+/// fourth component. Distances between points follow how different
+/// colors look, and colors that are nearly transparent are close
+/// together whatever their hue.
+type Point = [f32; 4];
+
+/// An image reduced to a palette.
+pub struct Quantized {
+    /// The colors of the palette, translucent colors first.
+    pub palette: Vec<Color>,
+
+    /// The pixels of the image, each of which is in the palette.
+    pub pixels: Vec<Color>
+}
+
+impl Quantized {
+    /// The indexed format that stores the image at the smallest bit depth.
+    pub fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Indexed(reduce::depth_for(self.palette.len()), self.palette.clone())
+    }
+}
+
+/// A distinct color of the image and how many pixels have it.
+struct Entry {
+    color: Color,
+    point: Point,
+    count: usize
+}
+
+/// A range of entries that median cut treats as a single color, and
+/// the squared error of doing so.
+struct Bucket {
+    start: usize,
+    end: usize,
+    error: f32
+}
+
+/// Reduces an image to a palette of at most `options.colors` colors,
+/// with median cut refined by k-means in premultiplied Oklab.
+pub fn quantize(pixels: &[Color], w: usize, options: &QuantizeOptions) -> Result<Quantized, String> {
+    if options.colors < 1 || options.colors > 256 {
+        return Err("The palette must have between 1 and 256 colors".to_string());
+    }
+
+    if options.quality > 100 {
+        return Err("The quality must be between 0 and 100".to_string());
+    }
+
+    if w == 0 || pixels.len() % w != 0 {
+        return Err("Pixel count does not match the image width".to_string());
+    }
+
+    let mut counts: HashMap<(u8, u8, u8, u8), usize> = HashMap::new();
+    for p in pixels {
+        let p = clear(p);
+        *counts.entry((p.r, p.g, p.b, p.a)).or_insert(0) += 1;
+    }
+
+    // Fully transparent pixels get a palette entry of their own, so
+    // that they stay fully transparent
+    let transparent = counts.remove(&(0, 0, 0, 0)).is_some();
+    let budget = options.colors - iif!(transparent, 1, 0);
+
+    let mut entries: Vec<Entry> = counts.iter().map(|(&(r, g, b, a), &count)| {
+        let color = Color::new(r, g, b, a);
+        Entry { point: to_point(&color), color: color, count: count }
+    }).collect();
+
+    // Start from the same order every time, so the palette only depends
+    // on the image
+    entries.sort_by(|a, b| (a.color.r, a.color.g, a.color.b, a.color.a).cmp(&(b.color.r, b.color.g, b.color.b, b.color.a)));
+
+    let mut palette: Vec<Color> = Vec::with_capacity(options.colors);
+    if budget > 0 && !entries.is_empty() {
+        let allowed = ERROR_PER_QUALITY * (100 - options.quality) as f32;
+        let buckets = median_cut(&mut entries, budget, allowed * allowed);
+        palette.extend(buckets.iter().map(|b| average(entries[b.start..b.end].iter())));
+        refine(&entries, &mut palette);
+    }
+
+    if transparent {
+        palette.push(Color::new(0, 0, 0, 0));
+    }
+
+    // Remember the palette entry picked for each color
+    let points: Vec<Point> = palette.iter().map(to_point).collect();
+    let mut cache: HashMap<(u8, u8, u8, u8), usize> = HashMap::new();
+    let mut nearest = |c: &Color| -> Color {
+        let index = *cache.entry((c.r, c.g, c.b, c.a)).or_insert_with(|| closest(&to_point(c), &points));
+        palette[index].clone()
+    };
+
+    let mapped: Vec<Color> = if options.dither {
+        dither(pixels, w, &mut nearest)
+    } else {
+        pixels.iter().map(|p| nearest(&clear(p))).collect()
+    };
+
+    let mut used: HashMap<(u8, u8, u8, u8), usize> = HashMap::new();
+    for p in &mapped {
+        *used.entry((p.r, p.g, p.b, p.a)).or_insert(0) += 1;
+    }
+
+    Ok(Quantized {
+        palette: reduce::palette(&used),
+        pixels: mapped
+    })
+}
+
+/// Makes every fully transparent color transparent black.
+fn clear(c: &Color) -> Color {
+    iif!(c.a == 0, Color::new(0, 0, 0, 0), c.clone())
+}
+
+/// Converts a color to premultiplied Oklab.
+fn to_point(c: &Color) -> Point {
+    let r = linear::srgb_to_linear(c.r as f32 / 255.0);
+    let g = linear::srgb_to_linear(c.g as f32 / 255.0);
+    let b = linear::srgb_to_linear(c.b as f32 / 255.0);
+
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    let alpha = c.a as f32 / 255.0;
+    [
+        (0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s) * alpha,
+        (1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s) * alpha,
+        (0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s) * alpha,
+        alpha
+    ]
+}
+
+fn distance(a: &Point, b: &Point) -> f32 {
+    (0..4).map(|i| (a[i] - b[i]) * (a[i] - b[i])).sum()
+}
+
+/// The index of the point nearest to the given one.
+fn closest(point: &Point, points: &[Point]) -> usize {
+    let mut best = (0, ::std::f32::MAX);
+    for (i, p) in points.iter().enumerate() {
+        let d = distance(point, p);
+        if d < best.1 {
+            best = (i, d);
+        }
+    }
+
+    best.0
+}
+
+/// The mean point of some entries, weighted by their counts.
+fn mean(entries: &[Entry]) -> Point {
+    let mut sum = [0f32; 4];
+    let mut total = 0;
+    for e in entries {
+        for i in 0..4 {
+            sum[i] += e.point[i] * e.count as f32;
+        }
+        total += e.count;
+    }
+
+    for v in sum.iter_mut() {
+        *v /= total as f32;
+    }
+    sum
+}
+
+/// The total squared error of replacing some entries with their mean.
+fn error(entries: &[Entry]) -> f32 {
+    let center = mean(entries);
+    entries.iter().map(|e| distance(&e.point, &center) * e.count as f32).sum()
+}
+
+/// The average color of some entries. The color channels are weighted
+/// by alpha so that nearly transparent pixels barely affect them.
+fn average<'a, I: Iterator<Item = &'a Entry>>(entries: I) -> Color {
+    let (mut r, mut g, mut b, mut a, mut total) = (0f64, 0f64, 0f64, 0f64, 0f64);
+    for e in entries {
+        let weight = e.count as f64 * e.color.a as f64;
+        r += e.color.r as f64 * weight;
+        g += e.color.g as f64 * weight;
+        b += e.color.b as f64 * weight;
+        a += weight;
+        total += e.count as f64;
+    }
+
+    if a == 0.0 {
+        return Color::new(0, 0, 0, 0);
+    }
+
+    Color::new((r / a).round() as u8, (g / a).round() as u8, (b / a).round() as u8, (a / total).round() as u8)
+}
+
+/// Splits the entries into buckets until there are `colors` of them, or
+/// the mean squared error per pixel is no more than `allowed`. The
+/// bucket with the most error is split each time, at the median of the
+/// channel it varies most in.
+fn median_cut(entries: &mut [Entry], colors: usize, allowed: f32) -> Vec<Bucket> {
+    let pixels: usize = entries.iter().map(|e| e.count).sum();
+    let mut buckets = vec![Bucket { start: 0, end: entries.len(), error: error(entries) }];
+
+    while buckets.len() < colors {
+        let total: f32 = buckets.iter().map(|b| b.error).sum();
+        if total <= allowed * pixels as f32 {
+            break;
+        }
+
+        let index = (0..buckets.len())
+            .max_by(|&a, &b| buckets[a].error.partial_cmp(&buckets[b].error).unwrap_or(Ordering::Equal))
+            .unwrap();
+        if buckets[index].error <= 0.0 || buckets[index].end - buckets[index].start < 2 {
+            break;
+        }
+
+        let Bucket { start, end, .. } = buckets.swap_remove(index);
+        let middle = start + split(&mut entries[start..end]);
+        buckets.push(Bucket { start: start, end: middle, error: error(&entries[start..middle]) });
+        buckets.push(Bucket { start: middle, end: end, error: error(&entries[middle..end]) });
+    }
+
+    buckets
+}
+
+/// Sorts at least two entries along the channel they vary most in, and
+/// returns where to split them so that each half has about as many pixels.
+fn split(entries: &mut [Entry]) -> usize {
+    let center = mean(entries);
+    let mut variance = [0f32; 4];
+    for e in entries.iter() {
+        for i in 0..4 {
+            variance[i] += (e.point[i] - center[i]) * (e.point[i] - center[i]) * e.count as f32;
+        }
+    }
+
+    let channel = (0..4).max_by(|&a, &b| variance[a].partial_cmp(&variance[b]).unwrap_or(Ordering::Equal)).unwrap();
+    entries.sort_by(|a, b| a.point[channel].partial_cmp(&b.point[channel]).unwrap_or(Ordering::Equal));
+
+    let total: usize = entries.iter().map(|e| e.count).sum();
+    let mut seen = 0;
+    for (i, e) in entries.iter().enumerate() {
+        seen += e.count;
+        if seen * 2 >= total {
+            return iif!(i + 1 < entries.len(), i + 1, entries.len() - 1);
+        }
+    }
+
+    entries.len() - 1
+}
+
+/// Moves each palette color to the average of the entries nearest to it.
+fn refine(entries: &[Entry], palette: &mut Vec<Color>) {
+    for _ in 0..REFINEMENT_ROUNDS {
+        let points: Vec<Point> = palette.iter().map(to_point).collect();
+        let mut clusters: Vec<Vec<&Entry>> = vec![Vec::new(); palette.len()];
+        for e in entries {
+            clusters[closest(&e.point, &points)].push(e);
+        }
+
+        for (color, cluster) in palette.iter_mut().zip(clusters.iter()) {
+            // A color that no entry is nearest to keeps its place
+            if !cluster.is_empty() {
+                *color = average(cluster.iter().cloned());
+            }
+        }
+    }
+}
+
+/// Maps pixels to the palette with Floyd-Steinberg dithering, spreading
+/// the difference between each pixel and its palette color over its
+/// neighbours. Fully transparent pixels neither take nor give any error,
+/// and fully opaque pixels keep their alpha, so the edges of sprites
+/// stay clean.
+fn dither<F: FnMut(&Color) -> Color>(pixels: &[Color], width: usize, nearest: &mut F) -> Vec<Color> {
+    let mut result = Vec::with_capacity(pixels.len());
+
+    dither::diffuse(pixels.len(), width, |i, carried| {
+        let p = &pixels[i];
+        if p.a == 0 {
+            result.push(nearest(&clear(p)));
+            return [0.0; 4];
+        }
+
+        let mut wanted = [0f32; 4];
+        for (c, &v) in [p.r, p.g, p.b, p.a].iter().enumerate() {
+            let v = v as f32 + iif!(c == 3 && p.a == 255, 0.0, carried[c]);
+            wanted[c] = iif!(v < 0.0, 0.0, iif!(v > 255.0, 255.0, v));
+        }
+
+        let rounded = Color::new(wanted[0].round() as u8, wanted[1].round() as u8, wanted[2].round() as u8, wanted[3].round() as u8);
+        let chosen = nearest(&clear(&rounded));
+
+        let mut error = [0f32; 4];
+        for (c, &v) in [chosen.r, chosen.g, chosen.b, chosen.a].iter().enumerate() {
+            error[c] = wanted[c] - v as f32;
+        }
+
+        result.push(chosen);
+        error
+    });
+
+    result
+}
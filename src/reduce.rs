@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use color::{Color, Color16};
+use color_type::ColorType;
+
+/// How pixels are stored in an encoded PNG.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// Greyscale samples at a bit depth of 1, 2, 4, 8 or 16.
+    Greyscale(u8),
+    /// Greyscale and alpha samples at a bit depth of 8 or 16.
+    GreyscaleWithAlpha(u8),
+    /// Red, green and blue samples at a bit depth of 8 or 16.
+    TrueColor(u8),
+    /// Red, green, blue and alpha samples at a bit depth of 8 or 16.
+    TrueColorWithAlpha(u8),
+    /// Indices at a bit depth of 1, 2, 4 or 8 into the given palette,
+    /// whose alpha values are written as a tRNS chunk.
+    Indexed(u8, Vec<Color>)
+}
+
+impl PixelFormat {
+    pub fn color_type(&self) -> ColorType {
+        match *self {
+            PixelFormat::Greyscale(_) => ColorType::Greyscale,
+            PixelFormat::GreyscaleWithAlpha(_) => ColorType::GreyscaleWithAlpha,
+            PixelFormat::TrueColor(_) => ColorType::TrueColor,
+            PixelFormat::TrueColorWithAlpha(_) => ColorType::TrueColorWithAlpha,
+            PixelFormat::Indexed(_, _) => ColorType::IndexedColor
+        }
+    }
+
+    pub fn bit_depth(&self) -> u8 {
+        match *self {
+            PixelFormat::Greyscale(depth) | PixelFormat::GreyscaleWithAlpha(depth) |
+            PixelFormat::TrueColor(depth) | PixelFormat::TrueColorWithAlpha(depth) |
+            PixelFormat::Indexed(depth, _) => depth
+        }
+    }
+
+    /// The depth of the samples the format stores. Indexed formats have
+    /// 8-bit samples in their palette whatever the depth of their indices.
+    pub fn sample_depth(&self) -> u8 {
+        match *self {
+            PixelFormat::Indexed(_, _) => 8,
+            _ => self.bit_depth()
+        }
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Greyscale(depth) | PixelFormat::Indexed(depth, _) => depth as usize,
+            PixelFormat::GreyscaleWithAlpha(depth) => depth as usize * 2,
+            PixelFormat::TrueColor(depth) => depth as usize * 3,
+            PixelFormat::TrueColorWithAlpha(depth) => depth as usize * 4
+        }
+    }
+
+    /// The palette of an indexed format.
+    pub fn palette(&self) -> Option<&[Color]> {
+        match *self {
+            PixelFormat::Indexed(_, ref palette) => Some(&palette[..]),
+            _ => None
+        }
+    }
+}
+
+/// Whether an 8-bit greyscale value can be stored exactly at the
+/// given bit depth.
+fn fits_depth(value: u8, depth: u8) -> bool {
+    value as u32 % (255 / ((1u32 << depth) - 1)) == 0
+}
+
+/// The smallest bit depth of 1, 2, 4 or 8 that can hold `count` values.
+pub fn depth_for(count: usize) -> u8 {
+    if count <= 2 {
+        1
+    } else if count <= 4 {
+        2
+    } else if count <= 16 {
+        4
+    } else {
+        8
+    }
+}
+
+/// Works out the smallest format that stores every pixel exactly.
+pub fn smallest_format(pixels: &[Color]) -> PixelFormat {
+    analyze(pixels.iter())
+}
+
+/// Works out the smallest format that stores every pixel exactly, in a
+/// single pass over the pixels.
+pub fn analyze<'a, I: Iterator<Item = &'a Color>>(pixels: I) -> PixelFormat {
+    analyze_within(pixels, true, true)
+}
+
+/// Works out the smallest format that stores every pixel exactly, only
+/// picking greyscale formats when `greyscale` is set, and palette or
+/// truecolor formats when `color` is set. When only `greyscale` is set,
+/// every pixel must be grey.
+pub fn analyze_within<'a, I: Iterator<Item = &'a Color>>(pixels: I, greyscale: bool, color: bool) -> PixelFormat {
+    let (mut opaque, mut grey) = (true, true);
+
+    // Whether every grey value fits a bit depth of 1, 2 and 4
+    let mut fits = [true; 3];
+
+    // Count the distinct colors, giving up once there are too many
+    // for a palette
+    let mut counts: HashMap<(u8, u8, u8, u8), usize> = HashMap::new();
+    for p in pixels {
+        opaque = opaque && p.a == 255;
+        if p.r != p.g || p.g != p.b {
+            grey = false;
+        } else {
+            for (fit, &depth) in fits.iter_mut().zip([1, 2, 4].iter()) {
+                *fit = *fit && fits_depth(p.r, depth);
+            }
+        }
+
+        if counts.len() <= 256 {
+            *counts.entry((p.r, p.g, p.b, p.a)).or_insert(0) += 1;
+        }
+    }
+    let indexed_depth = iif!(color && counts.len() <= 256, Some(depth_for(counts.len())), None);
+    let grey = grey && greyscale;
+
+    if grey && opaque {
+        let grey_depth = [1, 2, 4].iter().zip(fits.iter())
+            .find(|&(_, &fit)| fit)
+            .map(|(&depth, _)| depth)
+            .unwrap_or(8);
+
+        // Greyscale needs no palette, so it wins a tie
+        match indexed_depth {
+            Some(depth) if depth < grey_depth => (),
+            _ => return PixelFormat::Greyscale(grey_depth)
+        }
+    }
+
+    if let Some(depth) = indexed_depth {
+        return PixelFormat::Indexed(depth, palette(&counts));
+    }
+
+    match (grey, opaque) {
+        (true, false) => PixelFormat::GreyscaleWithAlpha(8),
+        (_, true) => PixelFormat::TrueColor(8),
+        _ => PixelFormat::TrueColorWithAlpha(8)
+    }
+}
+
+/// Orders the colors for a palette. Translucent colors go first so that
+/// the tRNS chunk can stop at the last of them, and more frequent colors
+/// go before less frequent ones.
+pub fn palette(counts: &HashMap<(u8, u8, u8, u8), usize>) -> Vec<Color> {
+    let mut colors: Vec<(&(u8, u8, u8, u8), &usize)> = counts.iter().collect();
+    colors.sort_by(|a, b| {
+        ((a.0).3 == 255).cmp(&((b.0).3 == 255))
+            .then(b.1.cmp(a.1))
+            .then(a.0.cmp(b.0))
+    });
+
+    colors.iter().map(|&(&(r, g, b, a), _)| Color::new(r, g, b, a)).collect()
+}
+
+/// Packs pixels into scanlines of samples in the given format, without
+/// filter type bytes. Fails if a pixel can't be stored exactly. 8-bit
+/// samples are scaled up to fill 16-bit formats.
+pub fn pack(pixels: &[Color], w: usize, format: &PixelFormat) -> Result<Vec<Vec<u8>>, String> {
+    let mut indices: HashMap<(u8, u8, u8, u8), u8> = HashMap::new();
+    if let PixelFormat::Indexed(depth, ref palette) = *format {
+        if palette.len() > 1 << depth {
+            return Err("The palette has too many colors for the bit depth".to_string());
+        }
+
+        for (i, c) in palette.iter().enumerate().rev() {
+            indices.insert((c.r, c.g, c.b, c.a), i as u8);
+        }
+    }
+
+    if format.bit_depth() == 16 {
+        let samples: Vec<Color16> = pixels.iter().map(|p| {
+            Color16::new(p.r as u16 * 257, p.g as u16 * 257, p.b as u16 * 257, p.a as u16 * 257)
+        }).collect();
+        return pack16(&samples, w, format);
+    }
+
+    let mut rows = Vec::with_capacity(pixels.len() / w);
+    for row in pixels.chunks(w) {
+        let mut packed = Vec::with_capacity((w * format.bits_per_pixel() + 7) / 8);
+        match *format {
+            PixelFormat::Greyscale(depth) => {
+                let scale = 255 / ((1u32 << depth) - 1);
+                let mut samples = Vec::with_capacity(w);
+                for p in row {
+                    if p.a != 255 || p.r != p.g || p.g != p.b || !fits_depth(p.r, depth) {
+                        return Err("A pixel can't be stored as greyscale at this bit depth".to_string());
+                    }
+                    samples.push((p.r as u32 / scale) as u8);
+                }
+                pack_samples(&samples, depth, &mut packed);
+            },
+            PixelFormat::GreyscaleWithAlpha(_) => {
+                for p in row {
+                    if p.r != p.g || p.g != p.b {
+                        return Err("A pixel can't be stored as greyscale".to_string());
+                    }
+                    packed.extend_from_slice(&[p.r, p.a]);
+                }
+            },
+            PixelFormat::TrueColor(_) => {
+                for p in row {
+                    if p.a != 255 {
+                        return Err("A pixel can't be stored without alpha".to_string());
+                    }
+                    packed.extend_from_slice(&[p.r, p.g, p.b]);
+                }
+            },
+            PixelFormat::TrueColorWithAlpha(_) => {
+                for p in row {
+                    packed.extend_from_slice(&[p.r, p.g, p.b, p.a]);
+                }
+            },
+            PixelFormat::Indexed(depth, _) => {
+                let mut samples = Vec::with_capacity(w);
+                for p in row {
+                    match indices.get(&(p.r, p.g, p.b, p.a)) {
+                        Some(&i) => samples.push(i),
+                        None => return Err("A pixel's color isn't in the palette".to_string())
+                    }
+                }
+                pack_samples(&samples, depth, &mut packed);
+            }
+        }
+
+        rows.push(packed);
+    }
+
+    Ok(rows)
+}
+
+/// Packs 16-bit samples into scanlines in one of the 16-bit formats,
+/// without filter type bytes. Fails if a pixel can't be stored exactly.
+pub fn pack16(pixels: &[Color16], w: usize, format: &PixelFormat) -> Result<Vec<Vec<u8>>, String> {
+    if format.bit_depth() != 16 || format.palette().is_some() {
+        return Err("Only 16-bit formats can store 16-bit samples".to_string());
+    }
+
+    let mut rows = Vec::with_capacity(pixels.len() / w);
+    for row in pixels.chunks(w) {
+        let mut samples: Vec<u16> = Vec::with_capacity(w * format.bits_per_pixel() / 16);
+        for p in row {
+            let grey = p.r == p.g && p.g == p.b;
+            match *format {
+                PixelFormat::Greyscale(_) if grey && p.a == 0xffff => samples.push(p.r),
+                PixelFormat::GreyscaleWithAlpha(_) if grey => samples.extend_from_slice(&[p.r, p.a]),
+                PixelFormat::TrueColor(_) if p.a == 0xffff => samples.extend_from_slice(&[p.r, p.g, p.b]),
+                PixelFormat::TrueColorWithAlpha(_) => samples.extend_from_slice(&[p.r, p.g, p.b, p.a]),
+                _ => return Err("A pixel can't be stored exactly in this format".to_string())
+            }
+        }
+
+        let mut packed = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            packed.extend_from_slice(&[(sample >> 8) as u8, sample as u8]);
+        }
+        rows.push(packed);
+    }
+
+    Ok(rows)
+}
+
+/// Packs samples of less than 8 bits into bytes, most significant
+/// bits first.
+fn pack_samples(samples: &[u8], depth: u8, out: &mut Vec<u8>) {
+    let per_byte = 8 / depth as usize;
+    for chunk in samples.chunks(per_byte) {
+        let mut byte = 0u8;
+        for (i, &sample) in chunk.iter().enumerate() {
+            byte |= sample << (8 - depth as usize * (i + 1));
+        }
+        out.push(byte);
+    }
+}
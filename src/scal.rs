@@ -0,0 +1,49 @@
+use helpers;
+
+pub type PhysicalScaleParseResult = Result<PhysicalScale, String>;
+
+/// The unit of an sCAL chunk's pixel sizes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScaleUnit {
+    Metre,
+    Radian
+}
+
+/// The physical width and height covered by each pixel, from an
+/// sCAL chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysicalScale {
+    pub unit: ScaleUnit,
+    pub pixel_width: f64,
+    pub pixel_height: f64
+}
+
+pub fn parse(data: &[u8]) -> PhysicalScaleParseResult {
+    if data.len() < 4 {
+        return Err("sCAL chunk is too short".to_string());
+    }
+
+    let unit = match data[0] {
+        1 => ScaleUnit::Metre,
+        2 => ScaleUnit::Radian,
+        n => return Err(format!("Invalid sCAL unit specifier: {}", n))
+    };
+
+    let separator = match data[1..].iter().position(|&b| b == 0) {
+        Some(n) => n + 1,
+        None => return Err("sCAL pixel width is not null terminated".to_string())
+    };
+
+    let pixel_width = try!(helpers::parse_ascii_float(&data[1..separator]));
+    let pixel_height = try!(helpers::parse_ascii_float(&data[separator + 1..]));
+
+    if pixel_width <= 0.0 || pixel_height <= 0.0 {
+        return Err("sCAL pixel sizes must be positive".to_string());
+    }
+
+    Ok(PhysicalScale {
+        unit: unit,
+        pixel_width: pixel_width,
+        pixel_height: pixel_height
+    })
+}
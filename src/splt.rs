@@ -0,0 +1,106 @@
+use color::Color16;
+use helpers;
+
+pub type SuggestedPaletteParseResult = Result<SuggestedPalette, String>;
+
+/// A single color of a suggested palette.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuggestedPaletteEntry {
+    pub color: Color16,
+    pub frequency: u16
+}
+
+/// A named palette suggested by an sPLT chunk. Samples are stored
+/// at the palette's own sample depth of 8 or 16 bits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuggestedPalette {
+    pub name: String,
+    pub sample_depth: u8,
+    pub entries: Vec<SuggestedPaletteEntry>
+}
+
+pub fn parse(data: &[u8]) -> SuggestedPaletteParseResult {
+    let name_length = match data.iter().position(|&b| b == 0) {
+        Some(n) => n,
+        None => return Err("sPLT palette name is not null terminated".to_string())
+    };
+
+    if name_length == 0 || name_length > 79 {
+        return Err("sPLT palette name must be 1-79 bytes long".to_string());
+    }
+
+    // Palette names are Latin-1, which maps directly onto the
+    // first 256 Unicode code points
+    let name = data[..name_length].iter().map(|&b| b as char).collect();
+
+    if data.len() < name_length + 2 {
+        return Err("sPLT chunk is missing its sample depth".to_string());
+    }
+
+    let sample_depth = data[name_length + 1];
+    let entry_size = match sample_depth {
+        8 => 6,
+        16 => 10,
+        n => return Err(format!("Invalid sPLT sample depth: {}", n))
+    };
+
+    let entries = &data[name_length + 2..];
+    if entries.len() % entry_size != 0 {
+        return Err("sPLT entries do not match the sample depth".to_string());
+    }
+
+    Ok(SuggestedPalette {
+        name: name,
+        sample_depth: sample_depth,
+        entries: entries.chunks(entry_size).map(|entry| {
+            if sample_depth == 8 {
+                SuggestedPaletteEntry {
+                    color: Color16::new(entry[0] as u16, entry[1] as u16, entry[2] as u16, entry[3] as u16),
+                    frequency: helpers::read_unsigned_short(&entry[0x04..])
+                }
+            } else {
+                SuggestedPaletteEntry {
+                    color: Color16::new(
+                        helpers::read_unsigned_short(&entry[0x00..]),
+                        helpers::read_unsigned_short(&entry[0x02..]),
+                        helpers::read_unsigned_short(&entry[0x04..]),
+                        helpers::read_unsigned_short(&entry[0x06..])
+                    ),
+                    frequency: helpers::read_unsigned_short(&entry[0x08..])
+                }
+            }
+        }).collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_lengths() {
+        let mut data = b"Caf\xe9\0\x08".to_vec();
+        data.extend_from_slice(&[1, 2, 3, 4, 0x12, 0x34]);
+        let palette = parse(&data).unwrap();
+        assert_eq!(palette.name, "Caf\u{e9}");
+        assert_eq!(palette.entries, vec![SuggestedPaletteEntry { color: Color16::new(1, 2, 3, 4), frequency: 0x1234 }]);
+
+        // A single 8-bit entry is not a whole 16-bit entry
+        data[5] = 16;
+        assert!(parse(&data).is_err());
+        data.extend_from_slice(&[5, 6, 7, 8]);
+        let palette = parse(&data).unwrap();
+        assert_eq!(palette.entries, vec![SuggestedPaletteEntry { color: Color16::new(0x0102, 0x0304, 0x1234, 0x0506), frequency: 0x0708 }]);
+
+        // Entries must be whole, and the name and depth must be present
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        assert!(parse(b"Caf\xe9\0").is_err());
+        assert!(parse(b"Caf\xe9").is_err());
+        assert!(parse(b"\0\x08").is_err());
+        assert!(parse(b"name\0\x04").is_err());
+
+        let mut long = vec![b'k'; 80];
+        long.extend_from_slice(&[0, 8]);
+        assert!(parse(&long).is_err());
+    }
+}
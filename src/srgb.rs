@@ -0,0 +1,24 @@
+pub type RenderingIntentParseResult = Result<RenderingIntent, String>;
+
+/// The rendering intent from an sRGB chunk, as defined by the ICC.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric
+}
+
+pub fn parse(data: &[u8]) -> RenderingIntentParseResult {
+    if data.len() != 1 {
+        return Err("Invalid sRGB length".to_string());
+    }
+
+    match data[0] {
+        0 => Ok(RenderingIntent::Perceptual),
+        1 => Ok(RenderingIntent::RelativeColorimetric),
+        2 => Ok(RenderingIntent::Saturation),
+        3 => Ok(RenderingIntent::AbsoluteColorimetric),
+        n => Err(format!("Invalid sRGB rendering intent: {}", n))
+    }
+}
@@ -0,0 +1,23 @@
+pub type StereoLayoutParseResult = Result<StereoLayout, String>;
+
+/// How the left and right halves of a stereo image pair are laid
+/// out, from an sTER chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StereoLayout {
+    /// The right-eye image is on the left, for cross-eyed viewing.
+    CrossFuse,
+    /// The left-eye image is on the left, for wall-eyed viewing.
+    DivergingFuse
+}
+
+pub fn parse(data: &[u8]) -> StereoLayoutParseResult {
+    if data.len() != 1 {
+        return Err("Invalid sTER length".to_string());
+    }
+
+    match data[0] {
+        0 => Ok(StereoLayout::CrossFuse),
+        1 => Ok(StereoLayout::DivergingFuse),
+        n => Err(format!("Invalid sTER mode: {}", n))
+    }
+}
@@ -0,0 +1,131 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use helpers;
+
+pub type TimestampParseResult = Result<Timestamp, String>;
+
+/// The time of an image's last modification from a tIME chunk,
+/// in UTC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+impl Timestamp {
+    /// Creates a timestamp, checking that each field is in range.
+    /// Seconds may be 60 to allow for leap seconds.
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> TimestampParseResult {
+        if month < 1 || month > 12 {
+            return Err(format!("Invalid tIME month: {}", month));
+        }
+
+        if day < 1 || day > 31 {
+            return Err(format!("Invalid tIME day: {}", day));
+        }
+
+        if hour > 23 {
+            return Err(format!("Invalid tIME hour: {}", hour));
+        }
+
+        if minute > 59 {
+            return Err(format!("Invalid tIME minute: {}", minute));
+        }
+
+        if second > 60 {
+            return Err(format!("Invalid tIME second: {}", second));
+        }
+
+        Ok(Timestamp {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second
+        })
+    }
+
+    /// The current time in UTC.
+    pub fn now() -> Self {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Self::from_unix(seconds)
+    }
+
+    /// The time the given number of seconds after the Unix epoch, in UTC.
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86400) as i64;
+        let time = seconds % 86400;
+
+        // Converts days since the epoch to a civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z / 146097;
+        let day_of_era = z - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = iif!(mp < 10, mp + 3, mp - 9);
+        let year = year_of_era + era * 400 + iif!(month <= 2, 1, 0);
+
+        Timestamp {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8
+        }
+    }
+
+    /// Serializes the timestamp as tIME chunk data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            (self.year >> 8) as u8,
+            self.year as u8,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second
+        ]
+    }
+}
+
+pub fn parse(data: &[u8]) -> TimestampParseResult {
+    if data.len() != 7 {
+        return Err("Invalid tIME length".to_string());
+    }
+
+    Timestamp::new(
+        helpers::read_unsigned_short(data),
+        data[0x02],
+        data[0x03],
+        data[0x04],
+        data[0x05],
+        data[0x06]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_unix_times() {
+        assert_eq!(Timestamp::from_unix(0), Timestamp::new(1970, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(Timestamp::from_unix(951782400), Timestamp::new(2000, 2, 29, 0, 0, 0).unwrap());
+        assert_eq!(Timestamp::from_unix(1234567890), Timestamp::new(2009, 2, 13, 23, 31, 30).unwrap());
+
+        let time = Timestamp::from_unix(1234567890);
+        assert_eq!(parse(&time.to_bytes()).unwrap(), time);
+        assert!(parse(&time.to_bytes()[..6]).is_err());
+        assert!(Timestamp::new(2009, 13, 1, 0, 0, 0).is_err());
+        assert!(Timestamp::new(2009, 12, 31, 23, 59, 60).is_ok());
+    }
+}
@@ -0,0 +1,79 @@
+use color_type::ColorType;
+use helpers;
+
+pub type TransparencyParseResult = Result<Transparency, String>;
+
+/// Transparency information from a tRNS chunk.
+pub enum Transparency {
+    /// Alpha values for the leading palette entries. Entries
+    /// without a value are fully opaque.
+    PaletteAlpha(Vec<u8>),
+    /// A greyscale sample that is fully transparent.
+    Greyscale(u16),
+    /// A red, green and blue sample that is fully transparent.
+    TrueColor(u16, u16, u16)
+}
+
+pub fn parse(data: &[u8], color_type: &ColorType, bit_depth: u8, palette_len: usize) -> TransparencyParseResult {
+    let max = ((1u32 << bit_depth) - 1) as u16;
+
+    match *color_type {
+        ColorType::IndexedColor => {
+            if palette_len == 0 {
+                return Err("tRNS chunk found before PLTE chunk".to_string());
+            }
+
+            if data.len() > palette_len {
+                return Err("tRNS chunk has more entries than the palette".to_string());
+            }
+
+            Ok(Transparency::PaletteAlpha(data.to_vec()))
+        },
+        ColorType::Greyscale => {
+            if data.len() != 2 {
+                return Err("Invalid tRNS length".to_string());
+            }
+
+            let grey = helpers::read_unsigned_short(data);
+            if grey > max {
+                return Err("tRNS sample out of range for the bit depth".to_string());
+            }
+
+            Ok(Transparency::Greyscale(grey))
+        },
+        ColorType::TrueColor => {
+            if data.len() != 6 {
+                return Err("Invalid tRNS length".to_string());
+            }
+
+            let r = helpers::read_unsigned_short(&data[0x00..]);
+            let g = helpers::read_unsigned_short(&data[0x02..]);
+            let b = helpers::read_unsigned_short(&data[0x04..]);
+            if r > max || g > max || b > max {
+                return Err("tRNS sample out of range for the bit depth".to_string());
+            }
+
+            Ok(Transparency::TrueColor(r, g, b))
+        },
+        _ => Err(format!("tRNS chunk is not allowed for color type: {}", color_type))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_keys_out_of_range() {
+        assert!(parse(&[0, 3], &ColorType::Greyscale, 2, 0).is_ok());
+        assert!(parse(&[0, 4], &ColorType::Greyscale, 2, 0).is_err());
+        assert!(parse(&[0xff, 0xff], &ColorType::Greyscale, 16, 0).is_ok());
+
+        assert!(parse(&[0, 255, 0, 0, 0, 255], &ColorType::TrueColor, 8, 0).is_ok());
+        assert!(parse(&[0, 255, 1, 0, 0, 255], &ColorType::TrueColor, 8, 0).is_err());
+        assert!(parse(&[0, 255, 0, 0, 0], &ColorType::TrueColor, 8, 0).is_err());
+
+        assert!(parse(&[0, 128], &ColorType::IndexedColor, 1, 2).is_ok());
+        assert!(parse(&[0, 128, 255], &ColorType::IndexedColor, 1, 2).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+/// The iTXt keyword that XMP packets are stored under.
+pub const XMP_KEYWORD: &'static str = "XML:com.adobe.xmp";
+
+/// An XMP metadata packet, with a minimal reader for the common
+/// Dublin Core fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Xmp {
+    pub packet: String
+}
+
+impl Xmp {
+    pub fn new(packet: &str) -> Self {
+        Xmp {
+            packet: packet.to_string()
+        }
+    }
+
+    /// The dc:title, in its default language.
+    pub fn title(&self) -> Option<String> {
+        self.property("dc:title").and_then(|p| first_item(p))
+    }
+
+    /// The dc:creator entries, in order.
+    pub fn creator(&self) -> Vec<String> {
+        self.property("dc:creator").map_or(Vec::new(), |p| items(p))
+    }
+
+    /// The dc:rights, in its default language.
+    pub fn rights(&self) -> Option<String> {
+        self.property("dc:rights").and_then(|p| first_item(p))
+    }
+
+    /// The content of the first element with the given name.
+    fn property(&self, name: &str) -> Option<&str> {
+        element(&self.packet, name).map(|(content, _)| content)
+    }
+}
+
+/// Finds the content of the first element with the given name, along
+/// with the offset just past the end of that element. Self-closing
+/// elements have no content.
+fn element<'a>(xml: &'a str, name: &str) -> Option<(&'a str, usize)> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(&open) {
+        let start = from + found + open.len();
+        from = start;
+
+        // Skip elements whose names merely start with this one
+        match xml[start..].chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\r') | Some('\n') | Some('/') => (),
+            _ => continue
+        }
+
+        let tag_end = match xml[start..].find('>') {
+            Some(n) => start + n,
+            None => return None
+        };
+
+        if xml[..tag_end].ends_with('/') {
+            return Some(("", tag_end + 1));
+        }
+
+        let content = tag_end + 1;
+        return xml[content..].find(&close).map(|end| (&xml[content..content + end], content + end + close.len()));
+    }
+
+    None
+}
+
+/// The text of every rdf:li item within a property.
+fn items(property: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut rest = property;
+    while let Some((item, end)) = element(rest, "rdf:li") {
+        result.push(unescape(item.trim()));
+
+        // Move past the item that was just read
+        rest = &rest[end..];
+    }
+
+    result
+}
+
+/// The text of the first rdf:li item in a property, which is the
+/// default language for language alternatives. Properties that hold
+/// plain text rather than a list are returned as they are.
+fn first_item(property: &str) -> Option<String> {
+    if property.contains("<rdf:li") {
+        items(property).into_iter().next()
+    } else {
+        Some(unescape(property.trim()))
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_items() {
+        let xmp = Xmp::new("<dc:creator><rdf:Seq><rdf:li/><rdf:li>Alice</rdf:li><rdf:li xml:lang=\"en\">Bob &amp; Co</rdf:li></rdf:Seq></dc:creator>");
+        assert_eq!(xmp.creator(), vec!["".to_string(), "Alice".to_string(), "Bob & Co".to_string()]);
+
+        let xmp = Xmp::new("<dc:creator><rdf:Seq><rdf:li/></rdf:Seq></dc:creator><dc:title/>");
+        assert_eq!(xmp.creator(), vec!["".to_string()]);
+        assert_eq!(xmp.title(), Some("".to_string()));
+        assert_eq!(xmp.rights(), None);
+    }
+}
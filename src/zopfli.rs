@@ -0,0 +1,366 @@
+use deflate::{self, BitWriter, Matcher, Token, DISTANCE_EXTRA, LENGTH_EXTRA, MAX_MATCH, MIN_MATCH};
+use options::Compression;
+
+/// How many earlier positions are searched for matches.
+const CHAIN: usize = 8192;
+
+/// The fewest tokens in a block split off from another.
+const MIN_SPLIT: usize = 10;
+
+/// How many evenly spaced split points each pass of the search tries.
+const SPLIT_CANDIDATES: usize = 64;
+
+/// The most blocks that the data is split into.
+const MAX_BLOCKS: usize = 64;
+
+/// The `(token, byte)` offsets where a block starts and ends.
+type Bounds = ((usize, usize), (usize, usize));
+
+/// Compresses data as deflate blocks, spending far more time than the
+/// regular levels to find the smallest encoding. The data is split into
+/// blocks where the statistics change, and each block is parsed into
+/// the cheapest sequence of literals and matches under a cost model
+/// that is refined over `compression.iterations` squeeze passes. The
+/// squeezed parse is then split and squeezed again, and each block's
+/// Huffman codes are picked to be cheap to store as well as to use.
+///
+/// The SDL icon in the tests comes out 5% smaller than at level 9, and
+/// takes hundreds of times longer to find: a 256x256 icon can take
+/// several seconds.
+pub fn compress(writer: &mut BitWriter, data: &[u8], start: usize, compression: &Compression, last: bool) {
+    if start == data.len() {
+        deflate::write_block(writer, &[], &[], last, true);
+        return;
+    }
+
+    let matches = find_matches(data, start, compression.window_bits);
+
+    // Start from the lazy matching parse of level 9 to decide where to
+    // split blocks
+    let mut lazy = Compression::new();
+    lazy.level = 9;
+    lazy.window_bits = compression.window_bits;
+    let tokens = deflate::lz77(data, start, &lazy);
+    let (mut squeezed, mut bounds) = squeeze_blocks(data, &tokens, &split_blocks(&tokens, start), &matches, compression.iterations);
+
+    // The squeezed parse has different statistics, so splitting it
+    // again can find better blocks, which are squeezed in turn
+    let resplit = split_blocks(&squeezed, start);
+    if resplit != bounds {
+        let (refined, refined_bounds) = squeeze_blocks(data, &squeezed, &resplit, &matches, compression.iterations);
+        if blocks_size(&refined, &refined_bounds) < blocks_size(&squeezed, &bounds) {
+            squeezed = refined;
+            bounds = refined_bounds;
+        }
+    }
+
+    for (i, &(block_start, block_end)) in bounds.iter().enumerate() {
+        let block = &squeezed[block_start.0..block_end.0];
+        deflate::write_block(writer, &data[block_start.1..block_end.1], block, last && i == bounds.len() - 1, true);
+    }
+}
+
+/// Squeezes each block of tokens between the given bounds, returning
+/// the squeezed tokens of every block and the bounds of the blocks
+/// among them.
+fn squeeze_blocks(data: &[u8], tokens: &[Token], bounds: &[Bounds], matches: &Matches, iterations: usize) -> (Vec<Token>, Vec<Bounds>) {
+    let mut squeezed = Vec::with_capacity(tokens.len());
+    let mut squeezed_bounds = Vec::with_capacity(bounds.len());
+    for &(block_start, block_end) in bounds {
+        let initial = tokens[block_start.0..block_end.0].to_vec();
+        let first = squeezed.len();
+        squeezed.extend(squeeze(data, block_start.1, block_end.1, matches, initial, iterations));
+        squeezed_bounds.push(((first, block_start.1), (squeezed.len(), block_end.1)));
+    }
+
+    (squeezed, squeezed_bounds)
+}
+
+/// Every match at every position from `start`, stored as
+/// `(length, distance)` pairs from `offsets[i]` to `offsets[i + 1]`.
+struct Matches {
+    start: usize,
+    offsets: Vec<usize>,
+    pairs: Vec<(u16, u16)>
+}
+
+impl Matches {
+    fn at(&self, pos: usize) -> &[(u16, u16)] {
+        let i = pos - self.start;
+        &self.pairs[self.offsets[i]..self.offsets[i + 1]]
+    }
+}
+
+fn find_matches(data: &[u8], start: usize, window_bits: u8) -> Matches {
+    let mut matcher = Matcher::new(data, window_bits);
+    let mut offsets = Vec::with_capacity(data.len() - start + 1);
+    let mut pairs = Vec::new();
+
+    for pos in start..data.len() {
+        offsets.push(pairs.len());
+        matcher.find_all(pos, CHAIN, &mut pairs);
+    }
+    offsets.push(pairs.len());
+
+    Matches {
+        start: start,
+        offsets: offsets,
+        pairs: pairs
+    }
+}
+
+/// The length of the data that a token decodes to.
+fn token_length(token: &Token) -> usize {
+    match *token {
+        Token::Literal(_) => 1,
+        Token::Match(length, _) => length as usize
+    }
+}
+
+/// Splits tokens into blocks wherever two blocks would be smaller than
+/// one, returning the `(token, byte)` bounds of each block. The tokens
+/// start at byte `start`.
+fn split_blocks(tokens: &[Token], start: usize) -> Vec<Bounds> {
+    let positions = token_positions(tokens, start);
+
+    let mut splits = Vec::new();
+    split(tokens, &positions, 0, tokens.len(), &mut splits);
+
+    let mut points = vec![0];
+    points.extend(splits);
+    points.push(tokens.len());
+
+    points.windows(2).map(|w| ((w[0], positions[w[0]]), (w[1], positions[w[1]]))).collect()
+}
+
+/// The byte offset where each token starts, followed by the offset
+/// where the last one ends.
+fn token_positions(tokens: &[Token], start: usize) -> Vec<usize> {
+    let mut positions = Vec::with_capacity(tokens.len() + 1);
+    let mut pos = start;
+    for token in tokens {
+        positions.push(pos);
+        pos += token_length(token);
+    }
+    positions.push(pos);
+
+    positions
+}
+
+fn split(tokens: &[Token], positions: &[usize], start: usize, end: usize, splits: &mut Vec<usize>) {
+    if end - start < MIN_SPLIT * 2 || splits.len() + 1 >= MAX_BLOCKS {
+        return;
+    }
+
+    let whole = deflate::block_size(positions[end] - positions[start], &tokens[start..end], true);
+    if let Some((point, size)) = best_split(tokens, positions, start, end) {
+        if size < whole {
+            split(tokens, positions, start, point, splits);
+            splits.push(point);
+            split(tokens, positions, point, end, splits);
+        }
+    }
+}
+
+/// Finds the point that splits the tokens from `start` to `end` into
+/// the two smallest blocks, returning it with their combined size. Each
+/// pass tries evenly spaced points, then narrows the search to the
+/// neighbours of the best one, until the last pass tries every point.
+fn best_split(tokens: &[Token], positions: &[usize], start: usize, end: usize) -> Option<(usize, usize)> {
+    let (mut low, mut high) = (start + MIN_SPLIT, end - MIN_SPLIT);
+    let mut best: Option<(usize, usize)> = None;
+
+    loop {
+        let step = ::std::cmp::max(1, (high - low) / SPLIT_CANDIDATES);
+        let mut point = low;
+        let mut tried = SplitSizes::new(tokens, start, end, low);
+        while point <= high {
+            tried.advance(tokens, point);
+            let size = tried.size(positions, start, end);
+            let better = match best {
+                Some((_, best_size)) => size < best_size,
+                None => true
+            };
+
+            if better {
+                best = Some((point, size));
+            }
+            point += step;
+        }
+
+        if step == 1 {
+            return best;
+        }
+
+        // Narrow the search to the points around the best so far
+        let centre = best.map(|(point, _)| point).unwrap_or(low);
+        low = ::std::cmp::max(low, centre.saturating_sub(step));
+        high = ::std::cmp::min(high, centre + step);
+    }
+}
+
+/// The symbol frequencies on each side of a split point, updated as
+/// the point moves forward through the tokens.
+struct SplitSizes {
+    point: usize,
+    before: ([usize; 286], [usize; 30]),
+    after: ([usize; 286], [usize; 30])
+}
+
+impl SplitSizes {
+    fn new(tokens: &[Token], start: usize, end: usize, point: usize) -> Self {
+        SplitSizes {
+            point: point,
+            before: deflate::frequencies(&tokens[start..point]),
+            after: deflate::frequencies(&tokens[point..end])
+        }
+    }
+
+    /// Moves the split point forward to `point`.
+    fn advance(&mut self, tokens: &[Token], point: usize) {
+        for token in &tokens[self.point..point] {
+            let (literal, distance) = match *token {
+                Token::Literal(byte) => (byte as usize, None),
+                Token::Match(length, distance) => {
+                    (257 + deflate::length_code(length as usize), Some(deflate::distance_code(distance as usize)))
+                }
+            };
+
+            (self.before.0)[literal] += 1;
+            (self.after.0)[literal] -= 1;
+            if let Some(distance) = distance {
+                (self.before.1)[distance] += 1;
+                (self.after.1)[distance] -= 1;
+            }
+        }
+        self.point = point;
+    }
+
+    /// The combined size in bits of the blocks either side of the point.
+    fn size(&self, positions: &[usize], start: usize, end: usize) -> usize {
+        deflate::block_size_with(positions[self.point] - positions[start], &self.before.0, &self.before.1, true) +
+            deflate::block_size_with(positions[end] - positions[self.point], &self.after.0, &self.after.1, true)
+    }
+}
+
+/// The combined size in bits of the blocks between the given bounds.
+fn blocks_size(tokens: &[Token], bounds: &[Bounds]) -> usize {
+    bounds.iter().map(|&(from, to)| deflate::block_size(to.1 - from.1, &tokens[from.0..to.0], true)).sum()
+}
+
+/// The estimated cost in bits of each symbol, from the symbol
+/// frequencies of a parse.
+struct CostModel {
+    literals: Vec<f64>,
+    distances: Vec<f64>
+}
+
+impl CostModel {
+    fn new(tokens: &[Token]) -> Self {
+        let (literal_frequencies, distance_frequencies) = deflate::frequencies(tokens);
+        CostModel {
+            literals: entropy(&literal_frequencies),
+            distances: entropy(&distance_frequencies)
+        }
+    }
+
+    fn literal(&self, byte: u8) -> f64 {
+        self.literals[byte as usize]
+    }
+
+    fn length(&self, length: usize) -> f64 {
+        let code = deflate::length_code(length);
+        self.literals[257 + code] + LENGTH_EXTRA[code] as f64
+    }
+
+    fn distance(&self, distance: usize) -> f64 {
+        let code = deflate::distance_code(distance);
+        self.distances[code] + DISTANCE_EXTRA[code] as f64
+    }
+}
+
+/// The information content in bits of each symbol. Unused symbols cost
+/// as much as a symbol that was used once.
+fn entropy(frequencies: &[usize]) -> Vec<f64> {
+    let total: usize = frequencies.iter().sum();
+    let log_total = (::std::cmp::max(total, 1) as f64).log2();
+
+    frequencies.iter().map(|&f| {
+        iif!(f == 0, log_total, log_total - (f as f64).log2())
+    }).collect()
+}
+
+/// Repeatedly parses a block with a cost model built from the previous
+/// parse, keeping whichever parse encodes smallest.
+fn squeeze(data: &[u8], start: usize, end: usize, matches: &Matches, initial: Vec<Token>, iterations: usize) -> Vec<Token> {
+    let mut best_size = deflate::block_size(end - start, &initial, true);
+    let mut best = initial;
+    let mut model = CostModel::new(&best);
+
+    for _ in 0..iterations {
+        let tokens = optimal_parse(data, start, end, matches, &model);
+        let size = deflate::block_size(end - start, &tokens, true);
+        model = CostModel::new(&tokens);
+
+        if size < best_size {
+            best_size = size;
+            best = tokens;
+        }
+    }
+
+    best
+}
+
+/// Finds the cheapest sequence of literals and matches for the block
+/// under the cost model, by working out the cheapest way to reach
+/// every position.
+fn optimal_parse(data: &[u8], start: usize, end: usize, matches: &Matches, model: &CostModel) -> Vec<Token> {
+    let length = end - start;
+    let mut costs = vec![::std::f64::INFINITY; length + 1];
+    let mut steps: Vec<Token> = vec![Token::Literal(0); length + 1];
+    costs[0] = 0.0;
+
+    for i in 0..length {
+        let pos = start + i;
+        let cost = costs[i];
+
+        let literal = cost + model.literal(data[pos]);
+        if literal < costs[i + 1] {
+            costs[i + 1] = literal;
+            steps[i + 1] = Token::Literal(data[pos]);
+        }
+
+        // Every length up to each match's length can use its distance
+        let max_length = ::std::cmp::min(MAX_MATCH, end - pos);
+        let mut shorter = MIN_MATCH - 1;
+        for &(match_length, distance) in matches.at(pos) {
+            let match_length = ::std::cmp::min(match_length as usize, max_length);
+            if match_length <= shorter {
+                continue;
+            }
+
+            let distance_cost = cost + model.distance(distance as usize);
+            for l in shorter + 1..match_length + 1 {
+                let total = distance_cost + model.length(l);
+                if total < costs[i + l] {
+                    costs[i + l] = total;
+                    steps[i + l] = Token::Match(l as u16, distance);
+                }
+            }
+
+            shorter = match_length;
+        }
+    }
+
+    // Walk back from the end to recover the cheapest path
+    let mut tokens = Vec::new();
+    let mut i = length;
+    while i > 0 {
+        let token = steps[i];
+        tokens.push(token);
+        i -= token_length(&token);
+    }
+    tokens.reverse();
+
+    tokens
+}